@@ -0,0 +1,230 @@
+use std::collections::VecDeque;
+
+// Lines sent by clearing 0 to 4 rows at once
+const LINES_SENT: [usize; 5] = [0, 0, 1, 2, 4];
+// Lines sent by a T-spin clearing 0 to 3 rows
+const T_SPIN_LINES_SENT: [usize; 4] = [0, 2, 4, 6];
+// Extra lines sent by the 1st, 2nd, 3rd... clear following another one
+const COMBO_LINES_SENT: [usize; 12] = [0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 4, 5];
+// Extra line sent by a Tetris or T-spin clear following another one
+const BACK_TO_BACK_LINES_SENT: usize = 1;
+
+/// How long (in ms) received garbage waits before it can enter the board.
+pub const GARBAGE_DELAY_MS: u64 = 1000;
+
+/// Counts the garbage lines sent to the other player by clearing rows.
+pub struct Attack {
+    // How many clears in a row follow the first one, None when the last piece cleared nothing
+    combo: Option<usize>,
+    // Whether the last clear was a Tetris or a T-spin
+    back_to_back: bool,
+}
+
+impl Attack {
+    pub fn new() -> Self {
+        Attack {
+            combo: None,
+            back_to_back: false,
+        }
+    }
+
+    /// The lines sent by a piece that cleared `rows` rows. A piece clearing
+    /// nothing breaks the combo, but not the back-to-back chain.
+    pub fn lines_sent(&mut self, rows: usize, t_spin: bool) -> usize {
+        if rows == 0 {
+            self.combo = None;
+            return 0;
+        }
+
+        let combo = self.combo.map_or(0, |combo| combo + 1);
+        self.combo = Some(combo);
+
+        let difficult = rows >= 4 || t_spin;
+        let back_to_back = difficult && self.back_to_back;
+        self.back_to_back = difficult;
+
+        let lines = if t_spin {
+            T_SPIN_LINES_SENT[rows.min(T_SPIN_LINES_SENT.len() - 1)]
+        } else {
+            LINES_SENT[rows.min(LINES_SENT.len() - 1)]
+        };
+        let combo_lines = COMBO_LINES_SENT[combo.min(COMBO_LINES_SENT.len() - 1)];
+        let back_to_back_lines = if back_to_back {
+            BACK_TO_BACK_LINES_SENT
+        } else {
+            0
+        };
+
+        lines + combo_lines + back_to_back_lines
+    }
+}
+
+impl Default for Attack {
+    fn default() -> Self {
+        Attack::new()
+    }
+}
+
+/// Garbage sent by the other player that hasn't entered the board yet.
+/// Lines cleared in the meantime cancel it instead of being sent back.
+pub struct GarbageQueue {
+    // The lines of each attack, oldest first, and when (in ms) they can enter the board
+    attacks: VecDeque<(usize, u64)>,
+}
+
+impl GarbageQueue {
+    pub fn new() -> Self {
+        GarbageQueue {
+            attacks: VecDeque::new(),
+        }
+    }
+
+    pub fn receive(&mut self, lines: usize, time_ms: u64) {
+        if lines > 0 {
+            self.attacks.push_back((lines, time_ms + GARBAGE_DELAY_MS));
+        }
+    }
+
+    /// Cancels up to `lines` waiting lines, oldest first, and returns how many are left to send.
+    pub fn offset(&mut self, mut lines: usize) -> usize {
+        while let Some((waiting, _)) = self.attacks.front_mut() {
+            if lines == 0 {
+                break;
+            }
+
+            let cancelled = lines.min(*waiting);
+            *waiting -= cancelled;
+            lines -= cancelled;
+            if *waiting == 0 {
+                self.attacks.pop_front();
+            }
+        }
+
+        lines
+    }
+
+    /// Removes the attacks done waiting at `time_ms` and returns their lines, to add them to the board.
+    pub fn take_ready(&mut self, time_ms: u64) -> Vec<usize> {
+        let ready = self
+            .attacks
+            .iter()
+            .take_while(|(_, ready_ms)| *ready_ms <= time_ms)
+            .count();
+
+        self.attacks
+            .drain(..ready)
+            .map(|(lines, _)| lines)
+            .collect()
+    }
+
+    /// The number of waiting lines, and how many of them are ready to enter at `time_ms`.
+    pub fn pending(&self, time_ms: u64) -> (usize, usize) {
+        let total = self.attacks.iter().map(|(lines, _)| lines).sum();
+        let ready = self
+            .attacks
+            .iter()
+            .filter(|(_, ready_ms)| *ready_ms <= time_ms)
+            .map(|(lines, _)| lines)
+            .sum();
+
+        (total, ready)
+    }
+}
+
+impl Default for GarbageQueue {
+    fn default() -> Self {
+        GarbageQueue::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The lines sent by each clear, with a piece clearing nothing in between
+    fn sent_apart(clears: &[(usize, bool)]) -> Vec<usize> {
+        let mut attack = Attack::new();
+        clears
+            .iter()
+            .map(|&(rows, t_spin)| {
+                let lines = attack.lines_sent(rows, t_spin);
+                attack.lines_sent(0, false);
+                lines
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sends_lines_by_rows_cleared() {
+        let clears = [(0, false), (1, false), (2, false), (3, false), (4, false)];
+        assert_eq!(sent_apart(&clears), [0, 0, 1, 2, 4]);
+    }
+
+    #[test]
+    fn sends_more_lines_for_t_spins() {
+        let sent: Vec<usize> = (0..4)
+            .map(|rows| Attack::new().lines_sent(rows, true))
+            .collect();
+        assert_eq!(sent, [0, 2, 4, 6]);
+    }
+
+    #[test]
+    fn adds_combo_lines_for_clears_in_a_row() {
+        let mut attack = Attack::new();
+        let sent: Vec<usize> = (0..6).map(|_| attack.lines_sent(1, false)).collect();
+        assert_eq!(sent, [0, 0, 1, 1, 2, 2]);
+
+        // A piece clearing nothing breaks the combo
+        assert_eq!(attack.lines_sent(0, false), 0);
+        assert_eq!(attack.lines_sent(1, false), 0);
+    }
+
+    #[test]
+    fn keeps_the_last_combo_bonus_for_long_combos() {
+        let mut attack = Attack::new();
+        let sent: Vec<usize> = (0..14).map(|_| attack.lines_sent(1, false)).collect();
+        assert_eq!(sent[11..], [5, 5, 5]);
+    }
+
+    #[test]
+    fn adds_a_line_for_back_to_back_difficult_clears() {
+        // A piece clearing nothing doesn't break the chain
+        let clears = [(4, false), (4, false), (2, true), (1, false), (4, false)];
+        assert_eq!(sent_apart(&clears), [4, 5, 5, 0, 4]);
+    }
+
+    #[test]
+    fn cancels_waiting_garbage_oldest_first() {
+        let mut queue = GarbageQueue::new();
+        queue.receive(2, 0);
+        queue.receive(3, 100);
+        assert_eq!(queue.offset(1), 0);
+        assert_eq!(queue.pending(0), (4, 0));
+
+        assert_eq!(queue.offset(2), 0);
+        assert_eq!(queue.pending(0), (2, 0));
+
+        // Lines left over once everything is cancelled are sent
+        assert_eq!(queue.offset(5), 3);
+        assert_eq!(queue.pending(10_000), (0, 0));
+        assert_eq!(queue.offset(2), 2);
+    }
+
+    #[test]
+    fn holds_garbage_back_for_a_delay() {
+        let mut queue = GarbageQueue::new();
+        queue.receive(2, 0);
+        queue.receive(0, 100);
+        queue.receive(3, 500);
+
+        assert_eq!(queue.pending(GARBAGE_DELAY_MS - 1), (5, 0));
+        assert!(queue.take_ready(GARBAGE_DELAY_MS - 1).is_empty());
+
+        assert_eq!(queue.pending(GARBAGE_DELAY_MS), (5, 2));
+        assert_eq!(queue.take_ready(GARBAGE_DELAY_MS), [2]);
+        assert_eq!(queue.pending(GARBAGE_DELAY_MS), (3, 0));
+
+        assert_eq!(queue.take_ready(GARBAGE_DELAY_MS + 500), [3]);
+        assert_eq!(queue.pending(GARBAGE_DELAY_MS + 500), (0, 0));
+    }
+}
@@ -0,0 +1,503 @@
+use std::fs;
+use std::path::PathBuf;
+
+use clap::ValueEnum;
+
+use crate::garbage::GarbageStyle;
+use crate::glyphs::GlyphStyle;
+use crate::keys::{self, Action, KeyBindings};
+use crate::mode::{GameMode, TopOut};
+use crate::randomizer::Randomizer;
+use crate::theme::DEFAULT_THEME;
+use crate::{
+    data_dir, Args, GameError, Result, MAX_LEVEL, MAX_NAME_LENGTH,
+    MAX_NUMBER_OF_LINES_ALREADY_FILLED,
+};
+
+const CONFIG_FILE: &str = "config";
+
+pub const MAX_PREVIEW_COUNT: usize = 5;
+pub const DEFAULT_DAS: u64 = 0;
+pub const DEFAULT_ARR: u64 = 0;
+pub const DEFAULT_SPRINT_LINES: usize = 40;
+pub const MAX_SPRINT_LINES: usize = 1000;
+pub const DEFAULT_ULTRA_SECONDS: u64 = 120;
+pub const MAX_ULTRA_SECONDS: u64 = 3600;
+pub const DEFAULT_DIG_LINES: usize = 10;
+pub const MAX_DIG_LINES: usize = 100;
+pub const DEFAULT_DIG_RISE_SECONDS: u64 = 10;
+pub const MAX_DIG_RISE_SECONDS: u64 = 60;
+pub const MAX_INVISIBLE_FADE_SECONDS: u64 = 30;
+pub const DEFAULT_GARBAGE_MESSINESS: u8 = 30;
+pub const MAX_GARBAGE_MESSINESS: u8 = 100;
+pub const DEFAULT_BEST_OF: u8 = 3;
+pub const MAX_BEST_OF: u8 = 9;
+
+/// Settings saved in `~/.tetris/config`. Command line flags take precedence.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub level: usize,
+    pub number_of_lines_already_filled: usize,
+    pub theme: String,
+    pub glyphs: GlyphStyle,
+    // Delayed Auto Shift: how long (in ms) a held left/right key waits before repeating
+    pub das: u64,
+    // Auto Repeat Rate: the minimum delay (in ms) between repeated moves, 0 follows the terminal
+    pub arr: u64,
+    pub ghost: bool,
+    pub preview_count: usize,
+    pub randomizer: Randomizer,
+    // How often (in percent) the hole of received or rising garbage moves to another column
+    pub garbage_messiness: u8,
+    // The number of rounds of a 2-player series, always odd so that someone wins it
+    pub best_of: u8,
+    pub default_name: String,
+    pub keys: KeyBindings,
+    // Chosen on the command line or in the start menu, never saved
+    pub mode: GameMode,
+    pub sprint_lines: usize,
+    pub ultra_seconds: u64,
+    // The number of garbage lines to clear in Dig mode
+    pub dig_lines: usize,
+    pub dig_garbage: GarbageStyle,
+    // How often (in s) a new garbage row rises in Dig mode, 0 turns it off
+    pub dig_rise_seconds: u64,
+    pub zen_gravity: bool,
+    pub zen_top_out: TopOut,
+    pub invisible_fade_seconds: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            level: 0,
+            number_of_lines_already_filled: 0,
+            theme: DEFAULT_THEME.to_string(),
+            glyphs: GlyphStyle::default(),
+            das: DEFAULT_DAS,
+            arr: DEFAULT_ARR,
+            ghost: true,
+            preview_count: 1,
+            randomizer: Randomizer::default(),
+            garbage_messiness: DEFAULT_GARBAGE_MESSINESS,
+            best_of: DEFAULT_BEST_OF,
+            default_name: String::new(),
+            keys: KeyBindings::default(),
+            mode: GameMode::default(),
+            sprint_lines: DEFAULT_SPRINT_LINES,
+            ultra_seconds: DEFAULT_ULTRA_SECONDS,
+            dig_lines: DEFAULT_DIG_LINES,
+            dig_garbage: GarbageStyle::default(),
+            dig_rise_seconds: DEFAULT_DIG_RISE_SECONDS,
+            zen_gravity: false,
+            zen_top_out: TopOut::default(),
+            invisible_fade_seconds: 0,
+        }
+    }
+}
+
+impl Config {
+    pub fn path() -> Result<PathBuf> {
+        Ok(data_dir()?.join(CONFIG_FILE))
+    }
+
+    /// Loads the saved settings, falling back to the defaults when there are none.
+    pub fn load() -> Result<Self> {
+        let path = Config::path()?;
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+
+        let content = fs::read_to_string(&path)?;
+        Config::parse(&content).map_err(|err| {
+            Box::new(GameError {
+                message: format!("{}: {}", path.display(), err),
+            }) as Box<dyn std::error::Error>
+        })
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut config = Config::default();
+
+        for (line_number, key, value) in parse_key_values(content)? {
+            let invalid = || GameError {
+                message: format!(
+                    "line {}: invalid value '{}' for {}",
+                    line_number, value, key
+                ),
+            };
+
+            match key.as_str() {
+                "level" => config.level = value.parse().map_err(|_| invalid())?,
+                "number_of_lines_already_filled" => {
+                    config.number_of_lines_already_filled = value.parse().map_err(|_| invalid())?
+                }
+                "theme" => config.theme = value,
+                "glyphs" => {
+                    config.glyphs = GlyphStyle::from_str(&value, true).map_err(|_| invalid())?
+                }
+                "das" => config.das = value.parse().map_err(|_| invalid())?,
+                "arr" => config.arr = value.parse().map_err(|_| invalid())?,
+                "ghost" => config.ghost = value.parse().map_err(|_| invalid())?,
+                "preview" => config.preview_count = value.parse().map_err(|_| invalid())?,
+                "randomizer" => {
+                    config.randomizer = Randomizer::from_str(&value, true).map_err(|_| invalid())?
+                }
+                "garbage_messiness" => {
+                    config.garbage_messiness = value.parse().map_err(|_| invalid())?
+                }
+                "best_of" => config.best_of = value.parse().map_err(|_| invalid())?,
+                "name" => config.default_name = value,
+                "sprint_lines" => config.sprint_lines = value.parse().map_err(|_| invalid())?,
+                "ultra_seconds" => config.ultra_seconds = value.parse().map_err(|_| invalid())?,
+                "dig_lines" => config.dig_lines = value.parse().map_err(|_| invalid())?,
+                "dig_garbage" => {
+                    config.dig_garbage =
+                        GarbageStyle::from_str(&value, true).map_err(|_| invalid())?
+                }
+                "dig_rise_seconds" => {
+                    config.dig_rise_seconds = value.parse().map_err(|_| invalid())?
+                }
+                "zen_gravity" => config.zen_gravity = value.parse().map_err(|_| invalid())?,
+                "zen_top_out" => {
+                    config.zen_top_out = TopOut::from_str(&value, true).map_err(|_| invalid())?
+                }
+                "invisible_fade_seconds" => {
+                    config.invisible_fade_seconds = value.parse().map_err(|_| invalid())?
+                }
+                _ if key.starts_with("key.") => {
+                    let action =
+                        Action::from_config_name(&key["key.".len()..]).ok_or_else(|| {
+                            GameError {
+                                message: format!("line {}: unknown action '{}'", line_number, key),
+                            }
+                        })?;
+                    config
+                        .keys
+                        .set(action, keys::parse_keys(&value).ok_or_else(invalid)?);
+                }
+                _ => {
+                    return Err(Box::new(GameError {
+                        message: format!("line {}: unknown setting '{}'", line_number, key),
+                    }))
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        fs::write(Config::path()?, self.to_string())?;
+        Ok(())
+    }
+
+    /// Applies the flags given on the command line on top of the saved settings.
+    pub fn with_args(mut self, args: &Args) -> Self {
+        if let Some(level) = args.level {
+            self.level = level;
+        }
+        if let Some(lines) = args.number_of_lines_already_filled {
+            self.number_of_lines_already_filled = lines;
+        }
+        if let Some(theme) = &args.theme {
+            self.theme = theme.clone();
+        }
+        if let Some(glyphs) = args.glyphs {
+            self.glyphs = glyphs;
+        }
+        if let Some(das) = args.das {
+            self.das = das;
+        }
+        if let Some(arr) = args.arr {
+            self.arr = arr;
+        }
+        if let Some(ghost) = args.ghost {
+            self.ghost = ghost;
+        }
+        if let Some(preview_count) = args.preview {
+            self.preview_count = preview_count;
+        }
+        if let Some(randomizer) = args.randomizer {
+            self.randomizer = randomizer;
+        }
+        if let Some(garbage_messiness) = args.garbage_messiness {
+            self.garbage_messiness = garbage_messiness;
+        }
+        if let Some(best_of) = args.best_of {
+            self.best_of = best_of;
+        }
+        if let Some(name) = &args.name {
+            self.default_name = name.clone();
+        }
+        if let Some(sprint_lines) = args.sprint_lines {
+            self.sprint_lines = sprint_lines;
+        }
+        if let Some(ultra_seconds) = args.ultra_seconds {
+            self.ultra_seconds = ultra_seconds;
+        }
+        if let Some(dig_lines) = args.dig_lines {
+            self.dig_lines = dig_lines;
+        }
+        if let Some(dig_garbage) = args.dig_garbage {
+            self.dig_garbage = dig_garbage;
+        }
+        if let Some(dig_rise_seconds) = args.dig_rise_seconds {
+            self.dig_rise_seconds = dig_rise_seconds;
+        }
+        if let Some(zen_gravity) = args.zen_gravity {
+            self.zen_gravity = zen_gravity;
+        }
+        if let Some(zen_top_out) = args.zen_top_out {
+            self.zen_top_out = zen_top_out;
+        }
+        if let Some(invisible_fade_seconds) = args.invisible_fade_seconds {
+            self.invisible_fade_seconds = invisible_fade_seconds;
+        }
+        self.mode = args.mode;
+
+        self
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let message = if self.number_of_lines_already_filled > MAX_NUMBER_OF_LINES_ALREADY_FILLED {
+            format!(
+                "The number of lines already filled must be less than or equal {}.",
+                MAX_NUMBER_OF_LINES_ALREADY_FILLED
+            )
+        } else if self.level > MAX_LEVEL {
+            format!("Level must be between 0 and {}.", MAX_LEVEL)
+        } else if self.preview_count == 0 || self.preview_count > MAX_PREVIEW_COUNT {
+            format!(
+                "The number of previews must be between 1 and {}.",
+                MAX_PREVIEW_COUNT
+            )
+        } else if self.default_name.chars().count() > MAX_NAME_LENGTH {
+            format!(
+                "The name must be at most {} characters long.",
+                MAX_NAME_LENGTH
+            )
+        } else if self.sprint_lines == 0 || self.sprint_lines > MAX_SPRINT_LINES {
+            format!(
+                "The number of Sprint lines must be between 1 and {}.",
+                MAX_SPRINT_LINES
+            )
+        } else if self.ultra_seconds == 0 || self.ultra_seconds > MAX_ULTRA_SECONDS {
+            format!(
+                "The Ultra time limit must be between 1 and {} seconds.",
+                MAX_ULTRA_SECONDS
+            )
+        } else if self.dig_lines == 0 || self.dig_lines > MAX_DIG_LINES {
+            format!(
+                "The number of Dig lines must be between 1 and {}.",
+                MAX_DIG_LINES
+            )
+        } else if self.dig_rise_seconds > MAX_DIG_RISE_SECONDS {
+            format!(
+                "Garbage must rise at least every {} seconds.",
+                MAX_DIG_RISE_SECONDS
+            )
+        } else if self.garbage_messiness > MAX_GARBAGE_MESSINESS {
+            format!(
+                "The garbage messiness must be between 0 and {} percent.",
+                MAX_GARBAGE_MESSINESS
+            )
+        } else if self.best_of.is_multiple_of(2) || self.best_of > MAX_BEST_OF {
+            format!(
+                "A series must be the best of an odd number of rounds up to {}.",
+                MAX_BEST_OF
+            )
+        } else if self.invisible_fade_seconds > MAX_INVISIBLE_FADE_SECONDS {
+            format!(
+                "Blocks must fade out within {} seconds.",
+                MAX_INVISIBLE_FADE_SECONDS
+            )
+        } else {
+            return self.keys.validate();
+        };
+
+        Err(Box::new(GameError { message }))
+    }
+}
+
+impl std::fmt::Display for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "level = {}", self.level)?;
+        writeln!(
+            f,
+            "number_of_lines_already_filled = {}",
+            self.number_of_lines_already_filled
+        )?;
+        writeln!(f, "theme = {}", self.theme)?;
+        writeln!(
+            f,
+            "glyphs = {}",
+            self.glyphs.to_possible_value().unwrap().get_name()
+        )?;
+        writeln!(f, "das = {}", self.das)?;
+        writeln!(f, "arr = {}", self.arr)?;
+        writeln!(f, "ghost = {}", self.ghost)?;
+        writeln!(f, "preview = {}", self.preview_count)?;
+        writeln!(
+            f,
+            "randomizer = {}",
+            self.randomizer.to_possible_value().unwrap().get_name()
+        )?;
+        writeln!(f, "garbage_messiness = {}", self.garbage_messiness)?;
+        writeln!(f, "best_of = {}", self.best_of)?;
+        writeln!(f, "name = {}", self.default_name)?;
+        writeln!(f, "sprint_lines = {}", self.sprint_lines)?;
+        writeln!(f, "ultra_seconds = {}", self.ultra_seconds)?;
+        writeln!(f, "dig_lines = {}", self.dig_lines)?;
+        writeln!(
+            f,
+            "dig_garbage = {}",
+            self.dig_garbage.to_possible_value().unwrap().get_name()
+        )?;
+        writeln!(f, "dig_rise_seconds = {}", self.dig_rise_seconds)?;
+        writeln!(f, "zen_gravity = {}", self.zen_gravity)?;
+        writeln!(
+            f,
+            "zen_top_out = {}",
+            self.zen_top_out.to_possible_value().unwrap().get_name()
+        )?;
+        writeln!(
+            f,
+            "invisible_fade_seconds = {}",
+            self.invisible_fade_seconds
+        )?;
+        for line in self.keys.to_config_lines() {
+            writeln!(f, "{}", line)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Splits `key = value` lines, skipping blank lines and `#` comments.
+/// Keys are lowercased; each pair is returned with its 1-based line number.
+pub fn parse_key_values(content: &str) -> Result<Vec<(usize, String, String)>> {
+    let mut pairs = Vec::new();
+
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match line.split_once('=') {
+            Some((key, value)) => pairs.push((
+                index + 1,
+                key.trim().to_lowercase(),
+                value.trim().to_string(),
+            )),
+            None => {
+                return Err(Box::new(GameError {
+                    message: format!("line {}: expected 'key = value'", index + 1),
+                }))
+            }
+        }
+    }
+
+    Ok(pairs)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use crossterm::event::KeyCode;
+
+    use super::*;
+
+    #[test]
+    fn parses_a_config_file() {
+        let config = Config::parse(
+            "# My settings\n\
+             level = 3\n\
+             theme = solarized\n\
+             das = 120\n\
+             ghost = false\n\
+             randomizer = bag\n\
+             best_of = 5\n\
+             name = Ann\n\
+             key.rotate = k, Up\n",
+        )
+        .unwrap();
+
+        assert_eq!(config.level, 3);
+        assert_eq!(config.theme, "solarized");
+        assert_eq!(config.das, 120);
+        assert!(!config.ghost);
+        assert_eq!(config.randomizer, Randomizer::Bag);
+        assert_eq!(config.best_of, 5);
+        assert_eq!(config.default_name, "Ann");
+        assert_eq!(
+            config.keys.keys(Action::Rotate),
+            &[KeyCode::Char('k'), KeyCode::Up]
+        );
+        // What isn't in the file keeps its default
+        assert_eq!(config.arr, DEFAULT_ARR);
+        assert_eq!(config.preview_count, 1);
+    }
+
+    #[test]
+    fn reads_back_what_it_saves() {
+        let mut config = Config {
+            level: 7,
+            garbage_messiness: 60,
+            default_name: "Bob".to_string(),
+            ..Config::default()
+        };
+        config.keys.set(Action::HardDrop, vec![KeyCode::Char(' ')]);
+        config.keys.set(Action::Rotate, vec![KeyCode::Char('x')]);
+
+        assert_eq!(Config::parse(&config.to_string()).unwrap(), config);
+    }
+
+    #[test]
+    fn reports_the_line_of_a_bad_setting() {
+        let message = |content: &str| Config::parse(content).unwrap_err().to_string();
+
+        assert_eq!(
+            message("level = 1\nlevel = high\n"),
+            "line 2: invalid value 'high' for level"
+        );
+        assert_eq!(message("speed = 3\n"), "line 1: unknown setting 'speed'");
+        assert_eq!(message("key.fly = f\n"), "line 1: unknown action 'key.fly'");
+        assert_eq!(message("level 3\n"), "line 1: expected 'key = value'");
+    }
+
+    #[test]
+    fn rejects_values_out_of_range() {
+        let invalid = |change: fn(&mut Config)| {
+            let mut config = Config::default();
+            change(&mut config);
+            config.validate().is_err()
+        };
+
+        assert!(!invalid(|_| {}));
+        assert!(invalid(|config| config.level = MAX_LEVEL + 1));
+        assert!(invalid(|config| config.preview_count = 0));
+        assert!(invalid(|config| config.garbage_messiness = 101));
+        assert!(invalid(|config| config.best_of = 4));
+        assert!(invalid(|config| config.best_of = MAX_BEST_OF + 2));
+        assert!(!invalid(|config| config.best_of = 1));
+        assert!(invalid(|config| config.default_name = "x".repeat(13)));
+        assert!(invalid(|config| config
+            .keys
+            .set(Action::HardDrop, vec![KeyCode::Char(' ')])));
+    }
+
+    #[test]
+    fn lets_command_line_flags_override_the_file() {
+        let saved = Config::parse("level = 3\ntheme = solarized\ndas = 120\n").unwrap();
+        let args = Args::parse_from(["tetris-tui", "--level", "9", "--best-of", "7"]);
+
+        let config = saved.clone().with_args(&args);
+        assert_eq!(config.level, 9);
+        assert_eq!(config.best_of, 7);
+        // The settings without a flag stay as saved
+        assert_eq!(config.theme, "solarized");
+        assert_eq!(config.das, 120);
+    }
+}
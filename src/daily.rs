@@ -0,0 +1,114 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::mode::GameMode;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// The daily challenge goes through these modes, one a day
+const MODES: [GameMode; 3] = [GameMode::Sprint, GameMode::Ultra, GameMode::Dig];
+
+/// The challenge of a day. Days change at midnight UTC, so that players
+/// in different time zones get the same challenge.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Daily {
+    /// The date, e.g. `2024-05-31`
+    pub day: String,
+    pub mode: GameMode,
+    /// Decides the pieces and the garbage of the day
+    pub seed: u64,
+}
+
+impl Daily {
+    pub fn today() -> Self {
+        let days = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() / SECONDS_PER_DAY);
+
+        Daily::from_days(days)
+    }
+
+    /// The challenge of the day `days` days after 1970-01-01.
+    pub fn from_days(days: u64) -> Self {
+        Daily {
+            day: format_day(days),
+            mode: MODES[(days % MODES.len() as u64) as usize],
+            seed: days,
+        }
+    }
+
+    /// Switches to the mode of the day, with its default settings for everyone.
+    pub fn apply(&self, config: &mut Config) {
+        let defaults = Config::default();
+        config.mode = self.mode;
+        config.level = defaults.level;
+        config.number_of_lines_already_filled = defaults.number_of_lines_already_filled;
+        config.sprint_lines = defaults.sprint_lines;
+        config.ultra_seconds = defaults.ultra_seconds;
+        config.dig_lines = defaults.dig_lines;
+        config.dig_garbage = defaults.dig_garbage;
+        config.dig_rise_seconds = defaults.dig_rise_seconds;
+    }
+}
+
+/// Formats a number of days since 1970-01-01 as a date, see
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn format_day(days: u64) -> String {
+    // Days since 0000-03-01, in eras of 400 years
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Months start from March, so that February comes last
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_the_first_days() {
+        assert_eq!(format_day(0), "1970-01-01");
+        assert_eq!(format_day(364), "1970-12-31");
+        assert_eq!(format_day(365), "1971-01-01");
+    }
+
+    #[test]
+    fn formats_leap_days() {
+        assert_eq!(format_day(11_015), "2000-02-28");
+        assert_eq!(format_day(11_016), "2000-02-29");
+        assert_eq!(format_day(11_017), "2000-03-01");
+        assert_eq!(format_day(19_782), "2024-02-29");
+
+        // 2100 isn't a leap year
+        assert_eq!(format_day(47_540), "2100-02-28");
+        assert_eq!(format_day(47_541), "2100-03-01");
+    }
+
+    #[test]
+    fn formats_a_year_boundary() {
+        assert_eq!(format_day(19_722), "2023-12-31");
+        assert_eq!(format_day(19_723), "2024-01-01");
+    }
+
+    #[test]
+    fn changes_challenge_every_day() {
+        let today = Daily::from_days(19_723);
+        assert_eq!(today.day, "2024-01-01");
+        assert_eq!(today, Daily::from_days(19_723));
+
+        let tomorrow = Daily::from_days(19_724);
+        assert_eq!(tomorrow.day, "2024-01-02");
+        assert_ne!(tomorrow.seed, today.seed);
+        assert_ne!(tomorrow.mode, today.mode);
+        assert_eq!(Daily::from_days(19_726).mode, today.mode);
+    }
+}
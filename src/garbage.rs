@@ -0,0 +1,167 @@
+use clap::ValueEnum;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::{Cell, EMPTY_CELL, GARBAGE_CELL, PLAY_WIDTH};
+
+// How often (in percent) the hole of clean and messy Dig garbage moves to another column
+const CLEAN_MESSINESS: u8 = 10;
+const MESSY_MESSINESS: u8 = 100;
+// How often garbage rises in Survival mode, 10% faster every level
+const SURVIVAL_RISE_MS: u64 = 8000;
+const MIN_SURVIVAL_RISE_MS: u64 = 1000;
+
+/// How the holes of consecutive garbage rows line up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum GarbageStyle {
+    /// The hole rarely moves, so several rows can be cleared at once
+    #[default]
+    Clean,
+    /// The hole moves on every row
+    Messy,
+}
+
+impl GarbageStyle {
+    pub fn messiness(self) -> u8 {
+        match self {
+            GarbageStyle::Clean => CLEAN_MESSINESS,
+            GarbageStyle::Messy => MESSY_MESSINESS,
+        }
+    }
+}
+
+/// Produces garbage rows, each one full except for a single hole. The same
+/// seed and messiness always give the same rows, so that games can be replayed
+/// and both players of a 2-player game get the same garbage.
+pub struct GarbageGenerator {
+    // How often (in percent) the hole moves to another column
+    messiness: u8,
+    hole: usize,
+    rng: StdRng,
+}
+
+impl GarbageGenerator {
+    pub fn new(messiness: u8) -> Self {
+        GarbageGenerator::with_rng(messiness, StdRng::from_entropy())
+    }
+
+    /// The same rows for the same seed, e.g. for the daily challenge.
+    pub fn seeded(messiness: u8, seed: u64) -> Self {
+        GarbageGenerator::with_rng(messiness, StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(messiness: u8, mut rng: StdRng) -> Self {
+        GarbageGenerator {
+            messiness: messiness.min(100),
+            hole: rng.gen_range(0..PLAY_WIDTH),
+            rng,
+        }
+    }
+
+    /// A row whose hole has moved `messiness` percent of the time.
+    pub fn next_row(&mut self) -> Vec<Cell> {
+        self.move_hole();
+        self.row()
+    }
+
+    /// The rows of an attack of `lines` lines, which all have their hole in the
+    /// same column. It has moved since the last attack `messiness` percent of the time.
+    pub fn next_attack(&mut self, lines: usize) -> Vec<Vec<Cell>> {
+        self.move_hole();
+        vec![self.row(); lines]
+    }
+
+    fn move_hole(&mut self) {
+        if self.rng.gen_ratio(self.messiness as u32, 100) {
+            // Never the same column twice in a row, otherwise the change wouldn't show
+            self.hole = (self.hole + self.rng.gen_range(1..PLAY_WIDTH)) % PLAY_WIDTH;
+        }
+    }
+
+    fn row(&self) -> Vec<Cell> {
+        let mut row = vec![GARBAGE_CELL; PLAY_WIDTH];
+        row[self.hole] = EMPTY_CELL;
+        row
+    }
+}
+
+/// How long (in ms) until the next garbage row rises in Survival mode.
+pub fn survival_rise_ms(level: usize) -> u64 {
+    let mut rise_ms = SURVIVAL_RISE_MS;
+    for _ in 0..level {
+        rise_ms -= rise_ms / 10;
+    }
+
+    rise_ms.max(MIN_SURVIVAL_RISE_MS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn holes(rows: &[Vec<Cell>]) -> Vec<usize> {
+        rows.iter()
+            .map(|row| row.iter().position(|cell| *cell == EMPTY_CELL).unwrap())
+            .collect()
+    }
+
+    fn rows(generator: &mut GarbageGenerator, count: usize) -> Vec<Vec<Cell>> {
+        (0..count).map(|_| generator.next_row()).collect()
+    }
+
+    #[test]
+    fn gives_the_same_rows_for_the_same_seed() {
+        let mut generator = GarbageGenerator::seeded(50, 42);
+        let mut again = GarbageGenerator::seeded(50, 42);
+        assert_eq!(rows(&mut generator, 50), rows(&mut again, 50));
+        assert_eq!(generator.next_attack(3), again.next_attack(3));
+    }
+
+    #[test]
+    fn leaves_exactly_one_hole_in_every_row() {
+        for messiness in [0, CLEAN_MESSINESS, 50, MESSY_MESSINESS] {
+            let mut generator = GarbageGenerator::seeded(messiness, 7);
+            for row in rows(&mut generator, 100) {
+                assert_eq!(row.len(), PLAY_WIDTH);
+                assert_eq!(row.iter().filter(|cell| **cell == EMPTY_CELL).count(), 1);
+                assert!(row
+                    .iter()
+                    .all(|cell| *cell == EMPTY_CELL || *cell == GARBAGE_CELL));
+            }
+        }
+    }
+
+    #[test]
+    fn never_moves_the_hole_without_messiness() {
+        let mut generator = GarbageGenerator::seeded(0, 7);
+        let holes = holes(&rows(&mut generator, 100));
+        assert!(holes.iter().all(|hole| *hole == holes[0]));
+    }
+
+    #[test]
+    fn always_moves_the_hole_at_full_messiness() {
+        let mut generator = GarbageGenerator::seeded(MESSY_MESSINESS, 7);
+        let holes = holes(&rows(&mut generator, 100));
+        assert!(holes.windows(2).all(|pair| pair[0] != pair[1]));
+    }
+
+    #[test]
+    fn keeps_the_hole_of_an_attack_in_one_column() {
+        let mut generator = GarbageGenerator::seeded(MESSY_MESSINESS, 7);
+        let first = holes(&generator.next_attack(4));
+        assert_eq!(first.len(), 4);
+        assert!(first.iter().all(|hole| *hole == first[0]));
+
+        let second = holes(&generator.next_attack(2));
+        assert!(second.iter().all(|hole| *hole == second[0]));
+        assert_ne!(first[0], second[0]);
+    }
+
+    #[test]
+    fn rises_faster_every_survival_level() {
+        assert_eq!(survival_rise_ms(0), SURVIVAL_RISE_MS);
+        assert_eq!(survival_rise_ms(1), 7200);
+        assert_eq!(survival_rise_ms(2), 6480);
+        assert_eq!(survival_rise_ms(100), MIN_SURVIVAL_RISE_MS);
+    }
+}
@@ -0,0 +1,48 @@
+use clap::ValueEnum;
+
+use crate::CellKind;
+
+/// How a filled cell is drawn. Every glyph is `CELL_WIDTH` columns wide.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum GlyphStyle {
+    /// `[ ]` for every piece, which are told apart by color only
+    #[default]
+    Brackets,
+    /// The piece letter inside brackets: `[I]`, `[T]`, ...
+    Letters,
+    /// A distinct shading pattern per piece, e.g. `▓▓▓` for I
+    Patterns,
+}
+
+impl GlyphStyle {
+    pub fn symbols(&self, kind: CellKind) -> &'static str {
+        match self {
+            GlyphStyle::Brackets => match kind {
+                CellKind::Empty => "   ",
+                _ => "[ ]",
+            },
+            GlyphStyle::Letters => match kind {
+                CellKind::Empty => "   ",
+                CellKind::I => "[I]",
+                CellKind::O => "[O]",
+                CellKind::T => "[T]",
+                CellKind::S => "[S]",
+                CellKind::Z => "[Z]",
+                CellKind::J => "[J]",
+                CellKind::L => "[L]",
+                CellKind::Garbage => "[#]",
+            },
+            GlyphStyle::Patterns => match kind {
+                CellKind::Empty => "   ",
+                CellKind::I => "▓▓▓",
+                CellKind::O => "███",
+                CellKind::T => "▒▒▒",
+                CellKind::S => "░░░",
+                CellKind::Z => "╳╳╳",
+                CellKind::J => "┼┼┼",
+                CellKind::L => "▚▚▚",
+                CellKind::Garbage => "▞▞▞",
+            },
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::time::{Duration, Instant};
+
+// Terminals repeat a held key every ~30-50ms. A press arriving later than this
+// after the previous one is treated as a new tap rather than a held key.
+const REPEAT_GAP: Duration = Duration::from_millis(60);
+
+/// Applies DAS (Delayed Auto Shift) and ARR (Auto Repeat Rate) to the left/right keys.
+///
+/// Terminals don't report key releases, so a key counts as held while its repeated
+/// presses keep coming in. The terminal's own repeat delay happens before DAS starts,
+/// and an ARR of 0 follows the terminal's repeat rate.
+pub struct AutoShift {
+    das: Duration,
+    arr: Duration,
+    direction: isize,
+    started_at: Instant,
+    last_press_at: Instant,
+    last_shift_at: Instant,
+}
+
+impl AutoShift {
+    pub fn new(das: u64, arr: u64) -> Self {
+        let now = Instant::now();
+        AutoShift {
+            das: Duration::from_millis(das),
+            arr: Duration::from_millis(arr),
+            direction: 0,
+            started_at: now,
+            last_press_at: now,
+            last_shift_at: now,
+        }
+    }
+
+    /// Records a press towards `direction` (-1 for left, 1 for right)
+    /// and returns whether the piece should move.
+    pub fn press(&mut self, direction: isize) -> bool {
+        self.press_at(direction, Instant::now())
+    }
+
+    fn press_at(&mut self, direction: isize, now: Instant) -> bool {
+        let held = direction == self.direction && now - self.last_press_at <= REPEAT_GAP;
+        self.last_press_at = now;
+
+        if !held {
+            self.direction = direction;
+            self.started_at = now;
+            self.last_shift_at = now;
+            return true;
+        }
+
+        if now - self.started_at < self.das || now - self.last_shift_at < self.arr {
+            return false;
+        }
+
+        self.last_shift_at = now;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Presses `direction` every `every` ms, as a terminal repeats a held key,
+    /// and returns the times (in ms) the piece moved.
+    fn hold(auto_shift: &mut AutoShift, direction: isize, every: u64, until: u64) -> Vec<u64> {
+        let start = Instant::now();
+        (0..=until)
+            .step_by(every as usize)
+            .filter(|ms| auto_shift.press_at(direction, start + Duration::from_millis(*ms)))
+            .collect()
+    }
+
+    #[test]
+    fn starts_repeating_after_das() {
+        let mut auto_shift = AutoShift::new(100, 0);
+        assert_eq!(hold(&mut auto_shift, 1, 30, 180), vec![0, 120, 150, 180]);
+    }
+
+    #[test]
+    fn repeats_at_most_every_arr() {
+        let mut auto_shift = AutoShift::new(100, 50);
+        assert_eq!(hold(&mut auto_shift, -1, 20, 260), vec![0, 100, 160, 220]);
+    }
+
+    #[test]
+    fn follows_the_terminal_without_das() {
+        let mut auto_shift = AutoShift::new(0, 0);
+        assert_eq!(hold(&mut auto_shift, 1, 40, 120), vec![0, 40, 80, 120]);
+    }
+
+    #[test]
+    fn treats_a_late_or_opposite_press_as_a_new_tap() {
+        let mut auto_shift = AutoShift::new(100, 0);
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        assert!(auto_shift.press_at(1, at(0)));
+        assert!(!auto_shift.press_at(1, at(30)));
+        // Another direction moves at once, and waits for DAS again
+        assert!(auto_shift.press_at(-1, at(60)));
+        assert!(!auto_shift.press_at(-1, at(90)));
+        // Longer than a repeat since the last press, the key was released
+        assert!(auto_shift.press_at(-1, at(200)));
+        assert!(!auto_shift.press_at(-1, at(230)));
+    }
+}
@@ -0,0 +1,91 @@
+use crate::{PLAY_HEIGHT, PLAY_WIDTH};
+
+/// How long (in ms) the stack shows up after a line clear in Invisible mode.
+pub const REVEAL_MS: u64 = 1000;
+
+/// When (in ms of playing time) each block of the stack was locked, so that
+/// Invisible mode can hide it afterwards. The play grid itself doesn't change,
+/// only what is drawn of it: the rows here move along with the rows of the grid.
+pub struct LockTimes {
+    rows: Vec<Vec<u64>>,
+}
+
+impl LockTimes {
+    pub fn new() -> Self {
+        LockTimes {
+            rows: vec![vec![0; PLAY_WIDTH]; PLAY_HEIGHT],
+        }
+    }
+
+    pub fn get(&self, row: usize, col: usize) -> u64 {
+        self.rows[row][col]
+    }
+
+    pub fn lock(&mut self, row: usize, col: usize, time_ms: u64) {
+        self.rows[row][col] = time_ms;
+    }
+
+    /// Whether the block at `row`, `col` was locked `fade_seconds` or more before `time_ms`.
+    pub fn has_faded(&self, row: usize, col: usize, time_ms: u64, fade_seconds: u64) -> bool {
+        time_ms >= self.get(row, col) + fade_seconds * 1000
+    }
+
+    /// A cleared row: the rows above it move down.
+    pub fn remove_row(&mut self, row: usize) {
+        self.rows.remove(row);
+        self.rows.insert(0, vec![0; PLAY_WIDTH]);
+    }
+
+    /// A row pushed in from the bottom, the top row is dropped.
+    pub fn push_row(&mut self, time_ms: u64) {
+        self.rows.remove(0);
+        self.rows.push(vec![time_ms; PLAY_WIDTH]);
+    }
+}
+
+impl Default for LockTimes {
+    fn default() -> Self {
+        LockTimes::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOTTOM: usize = PLAY_HEIGHT - 1;
+
+    #[test]
+    fn hides_a_block_once_the_fade_time_has_passed() {
+        let mut lock_times = LockTimes::new();
+        lock_times.lock(BOTTOM, 3, 5_000);
+        assert!(!lock_times.has_faded(BOTTOM, 3, 5_000, 2));
+        assert!(!lock_times.has_faded(BOTTOM, 3, 6_999, 2));
+        assert!(lock_times.has_faded(BOTTOM, 3, 7_000, 2));
+
+        // No fade time hides blocks as soon as they lock
+        assert!(lock_times.has_faded(BOTTOM, 3, 5_000, 0));
+    }
+
+    #[test]
+    fn moves_lock_times_down_with_cleared_rows() {
+        let mut lock_times = LockTimes::new();
+        lock_times.lock(BOTTOM - 1, 0, 1_000);
+        lock_times.lock(BOTTOM, 0, 2_000);
+
+        lock_times.remove_row(BOTTOM);
+        assert_eq!(lock_times.get(BOTTOM, 0), 1_000);
+        assert_eq!(lock_times.get(BOTTOM - 1, 0), 0);
+        assert_eq!(lock_times.get(0, 0), 0);
+    }
+
+    #[test]
+    fn moves_lock_times_up_with_rising_rows() {
+        let mut lock_times = LockTimes::new();
+        lock_times.lock(BOTTOM, 0, 1_000);
+
+        lock_times.push_row(3_000);
+        assert_eq!(lock_times.get(BOTTOM - 1, 0), 1_000);
+        assert_eq!(lock_times.get(BOTTOM, 9), 3_000);
+    }
+}
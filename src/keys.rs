@@ -0,0 +1,481 @@
+use std::collections::HashMap;
+
+use crossterm::event::KeyCode;
+
+use crate::{GameError, Result};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    MoveLeft,
+    MoveRight,
+    Rotate,
+    SoftDrop,
+    HardDrop,
+    Pause,
+    Quit,
+    Continue,
+    Restart,
+    Yes,
+    No,
+    Undo,
+    NextPiece,
+    EditBoard,
+    Paint,
+    Wait,
+    Ready,
+}
+
+pub const ACTIONS: [Action; 17] = [
+    Action::MoveLeft,
+    Action::MoveRight,
+    Action::Rotate,
+    Action::SoftDrop,
+    Action::HardDrop,
+    Action::Pause,
+    Action::Quit,
+    Action::Continue,
+    Action::Restart,
+    Action::Yes,
+    Action::No,
+    Action::Undo,
+    Action::NextPiece,
+    Action::EditBoard,
+    Action::Paint,
+    Action::Wait,
+    Action::Ready,
+];
+
+/// Where keys are read. Each context must not bind the same key to two actions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Context {
+    Game,
+    Paused,
+    QuitDialog,
+    GameOver,
+    /// Between the rounds of a 2-player game
+    RoundOver,
+    /// The vote for a rematch once a 2-player series is over
+    Rematch,
+    /// The game in Sandbox mode, which has more actions
+    Sandbox,
+    /// The board editor of Sandbox mode, where the move keys move the cursor
+    Editor,
+    /// The other player's connection was lost
+    Disconnected,
+}
+
+const CONTEXTS: [Context; 9] = [
+    Context::Game,
+    Context::Paused,
+    Context::QuitDialog,
+    Context::GameOver,
+    Context::RoundOver,
+    Context::Rematch,
+    Context::Sandbox,
+    Context::Editor,
+    Context::Disconnected,
+];
+
+impl Context {
+    pub fn actions(&self) -> &'static [Action] {
+        match self {
+            Context::Game => &[
+                Action::MoveLeft,
+                Action::MoveRight,
+                Action::Rotate,
+                Action::SoftDrop,
+                Action::HardDrop,
+                Action::Pause,
+                Action::Quit,
+            ],
+            Context::Paused => &[Action::Continue, Action::Quit],
+            Context::QuitDialog => &[Action::Yes, Action::No],
+            Context::GameOver => &[Action::Restart, Action::Quit],
+            Context::RoundOver => &[Action::Ready, Action::Quit],
+            Context::Rematch => &[Action::Yes, Action::No],
+            Context::Sandbox => &[
+                Action::MoveLeft,
+                Action::MoveRight,
+                Action::Rotate,
+                Action::SoftDrop,
+                Action::HardDrop,
+                Action::Undo,
+                Action::NextPiece,
+                Action::EditBoard,
+                Action::Pause,
+                Action::Quit,
+            ],
+            Context::Editor => &[
+                Action::MoveLeft,
+                Action::MoveRight,
+                Action::SoftDrop,
+                Action::HardDrop,
+                Action::Paint,
+                Action::EditBoard,
+            ],
+            Context::Disconnected => &[Action::Wait, Action::Continue, Action::Quit],
+        }
+    }
+}
+
+impl Action {
+    /// The name used in the config file, e.g. `key.move_left = h, Left`
+    pub fn config_name(&self) -> &'static str {
+        match self {
+            Action::MoveLeft => "move_left",
+            Action::MoveRight => "move_right",
+            Action::Rotate => "rotate",
+            Action::SoftDrop => "soft_drop",
+            Action::HardDrop => "hard_drop",
+            Action::Pause => "pause",
+            Action::Quit => "quit",
+            Action::Continue => "continue",
+            Action::Restart => "restart",
+            Action::Yes => "yes",
+            Action::No => "no",
+            Action::Undo => "undo",
+            Action::NextPiece => "next_piece",
+            Action::EditBoard => "edit_board",
+            Action::Paint => "paint",
+            Action::Wait => "wait",
+            Action::Ready => "ready",
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Action::MoveLeft => "Left",
+            Action::MoveRight => "Right",
+            Action::Rotate => "Rotate",
+            Action::SoftDrop => "Soft Drop",
+            Action::HardDrop => "Hard Drop",
+            Action::Pause => "Pause",
+            Action::Quit => "Quit",
+            Action::Continue => "Continue",
+            Action::Restart => "Restart",
+            Action::Yes => "Yes",
+            Action::No => "No",
+            Action::Undo => "Undo",
+            Action::NextPiece => "Pick next",
+            Action::EditBoard => "Edit",
+            Action::Paint => "Paint",
+            Action::Wait => "Wait",
+            Action::Ready => "Ready",
+        }
+    }
+
+    fn default_keys(&self) -> Vec<KeyCode> {
+        match self {
+            Action::MoveLeft => vec![KeyCode::Char('h'), KeyCode::Left],
+            Action::MoveRight => vec![KeyCode::Char('l'), KeyCode::Right],
+            Action::Rotate => vec![KeyCode::Char(' ')],
+            Action::SoftDrop => vec![KeyCode::Char('s'), KeyCode::Up],
+            Action::HardDrop => vec![KeyCode::Char('j'), KeyCode::Down],
+            Action::Pause => vec![KeyCode::Char('p')],
+            Action::Quit => vec![KeyCode::Char('q')],
+            Action::Continue => vec![KeyCode::Enter, KeyCode::Char('c')],
+            Action::Restart => vec![KeyCode::Char('r')],
+            Action::Yes => vec![KeyCode::Enter, KeyCode::Char('y')],
+            Action::No => vec![KeyCode::Esc, KeyCode::Char('n')],
+            Action::Undo => vec![KeyCode::Char('u')],
+            Action::NextPiece => vec![KeyCode::Char('n')],
+            Action::EditBoard => vec![KeyCode::Char('e')],
+            Action::Paint => vec![KeyCode::Char(' '), KeyCode::Char('x')],
+            Action::Wait => vec![KeyCode::Char('w')],
+            Action::Ready => vec![KeyCode::Enter, KeyCode::Char('r')],
+        }
+    }
+
+    pub fn from_config_name(name: &str) -> Option<Action> {
+        ACTIONS
+            .iter()
+            .find(|action| action.config_name() == name)
+            .copied()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyBindings {
+    keys: HashMap<Action, Vec<KeyCode>>,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        KeyBindings {
+            keys: ACTIONS
+                .iter()
+                .map(|action| (*action, action.default_keys()))
+                .collect(),
+        }
+    }
+}
+
+impl KeyBindings {
+    pub fn keys(&self, action: Action) -> &[KeyCode] {
+        self.keys.get(&action).map_or(&[], |keys| keys.as_slice())
+    }
+
+    pub fn set(&mut self, action: Action, keys: Vec<KeyCode>) {
+        self.keys.insert(action, keys);
+    }
+
+    /// Returns the action bound to `code` among the ones available in `context`.
+    pub fn action(&self, context: Context, code: KeyCode) -> Option<Action> {
+        context
+            .actions()
+            .iter()
+            .find(|action| self.keys(**action).contains(&code))
+            .copied()
+    }
+
+    /// Fails when a key triggers two different actions in the same context.
+    pub fn validate(&self) -> Result<()> {
+        for context in CONTEXTS.iter() {
+            let actions = context.actions();
+            for (index, action) in actions.iter().enumerate() {
+                for other in actions[index + 1..].iter() {
+                    if let Some(key) = self
+                        .keys(*action)
+                        .iter()
+                        .find(|key| self.keys(*other).contains(key))
+                    {
+                        return Err(Box::new(GameError {
+                            message: format!(
+                                "The key '{}' is bound to both {} and {}.",
+                                key_name(key),
+                                action.config_name(),
+                                other.config_name()
+                            ),
+                        }));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The keys of an action as shown in the Help panel, e.g. `h, ←`
+    pub fn describe(&self, action: Action) -> String {
+        self.keys(action)
+            .iter()
+            .take(2)
+            .map(key_symbol)
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    /// Builds a prompt like `(C)ontinue | (Q)uit` from the active bindings.
+    /// Actions whose first key isn't their initial are shown as `Continue (x)`.
+    pub fn prompt(&self, actions: &[Action]) -> String {
+        actions
+            .iter()
+            .map(|action| {
+                let label = action.label();
+                let key = self.keys(*action).iter().find_map(|key| match key {
+                    KeyCode::Char(c) if *c != ' ' => Some(*c),
+                    _ => None,
+                });
+
+                match key {
+                    Some(c) if label.to_lowercase().starts_with(c) => {
+                        format!("({}){}", c.to_ascii_uppercase(), &label[1..])
+                    }
+                    _ => match self.keys(*action).first() {
+                        Some(key) => format!("{} ({})", label, key_symbol(key)),
+                        None => label.to_string(),
+                    },
+                }
+            })
+            .collect::<Vec<String>>()
+            .join(" | ")
+    }
+
+    pub fn to_config_lines(&self) -> Vec<String> {
+        ACTIONS
+            .iter()
+            .map(|action| {
+                format!(
+                    "key.{} = {}",
+                    action.config_name(),
+                    self.keys(*action)
+                        .iter()
+                        .map(key_name)
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                )
+            })
+            .collect()
+    }
+}
+
+/// Parses a comma separated list of keys, e.g. `h, Left`.
+pub fn parse_keys(value: &str) -> Option<Vec<KeyCode>> {
+    value
+        .split(',')
+        .map(|key| parse_key(key.trim()))
+        .collect::<Option<Vec<KeyCode>>>()
+        .filter(|keys| !keys.is_empty())
+}
+
+pub fn parse_key(name: &str) -> Option<KeyCode> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Some(KeyCode::Char(c));
+    }
+
+    let code = match name.to_lowercase().as_str() {
+        "space" => KeyCode::Char(' '),
+        "comma" => KeyCode::Char(','),
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "enter" => KeyCode::Enter,
+        "esc" => KeyCode::Esc,
+        "tab" => KeyCode::Tab,
+        "backspace" => KeyCode::Backspace,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        "pageup" => KeyCode::PageUp,
+        "pagedown" => KeyCode::PageDown,
+        "insert" => KeyCode::Insert,
+        "delete" => KeyCode::Delete,
+        other => {
+            let number = other.strip_prefix('f')?.parse().ok()?;
+            KeyCode::F(number)
+        }
+    };
+
+    Some(code)
+}
+
+/// The name of a key in the config file.
+pub fn key_name(code: &KeyCode) -> String {
+    match code {
+        KeyCode::Char(' ') => "Space".to_string(),
+        KeyCode::Char(',') => "Comma".to_string(),
+        KeyCode::Char(c) => c.to_string(),
+        KeyCode::F(number) => format!("F{}", number),
+        KeyCode::PageUp => "PageUp".to_string(),
+        KeyCode::PageDown => "PageDown".to_string(),
+        other => format!("{:?}", other),
+    }
+}
+
+/// Whether a key can be saved in the config file and read back, which rules out
+/// keys such as BackTab or the media keys.
+pub fn can_be_saved(code: &KeyCode) -> bool {
+    parse_key(&key_name(code)) == Some(*code)
+}
+
+/// The name of a key on screen.
+fn key_symbol(code: &KeyCode) -> String {
+    match code {
+        KeyCode::Left => "←".to_string(),
+        KeyCode::Right => "→".to_string(),
+        KeyCode::Up => "↑".to_string(),
+        KeyCode::Down => "↓".to_string(),
+        // The Help panel splits its lines on ':'
+        KeyCode::Char(':') => "Colon".to_string(),
+        other => key_name(other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossterm::event::MediaKeyCode;
+
+    use super::*;
+
+    #[test]
+    fn reads_back_the_name_of_a_key() {
+        let codes = [
+            KeyCode::Char('h'),
+            KeyCode::Char('H'),
+            KeyCode::Char(' '),
+            KeyCode::Char(','),
+            KeyCode::Char(':'),
+            KeyCode::Left,
+            KeyCode::Right,
+            KeyCode::Up,
+            KeyCode::Down,
+            KeyCode::Enter,
+            KeyCode::Esc,
+            KeyCode::Tab,
+            KeyCode::Backspace,
+            KeyCode::Home,
+            KeyCode::End,
+            KeyCode::PageUp,
+            KeyCode::PageDown,
+            KeyCode::Insert,
+            KeyCode::Delete,
+            KeyCode::F(1),
+            KeyCode::F(12),
+        ];
+        for code in codes {
+            assert_eq!(parse_key(&key_name(&code)), Some(code), "{:?}", code);
+            assert!(can_be_saved(&code));
+        }
+    }
+
+    #[test]
+    fn refuses_keys_it_cant_read_back() {
+        for code in [
+            KeyCode::BackTab,
+            KeyCode::Null,
+            KeyCode::CapsLock,
+            KeyCode::Media(MediaKeyCode::Play),
+        ] {
+            assert!(!can_be_saved(&code), "{:?}", code);
+        }
+        assert_eq!(parse_key("BackTab"), None);
+        assert_eq!(parse_key("Fx"), None);
+    }
+
+    #[test]
+    fn parses_lists_of_keys() {
+        assert_eq!(
+            parse_keys("h, Left"),
+            Some(vec![KeyCode::Char('h'), KeyCode::Left])
+        );
+        assert_eq!(
+            parse_keys("SPACE,comma"),
+            Some(vec![KeyCode::Char(' '), KeyCode::Char(',')])
+        );
+        assert_eq!(parse_keys("h, nope"), None);
+        assert_eq!(parse_keys(""), None);
+    }
+
+    #[test]
+    fn refuses_a_key_bound_twice_on_the_same_screen() {
+        let mut bindings = KeyBindings::default();
+        assert!(bindings.validate().is_ok());
+
+        bindings.set(Action::HardDrop, vec![KeyCode::Char(' ')]);
+        assert_eq!(
+            bindings.validate().unwrap_err().to_string(),
+            "The key 'Space' is bound to both rotate and hard_drop."
+        );
+    }
+
+    #[test]
+    fn allows_a_key_bound_twice_on_different_screens() {
+        let mut bindings = KeyBindings::default();
+        // Pause is only read during the game, Yes only in the quit dialog
+        bindings.set(Action::Yes, vec![KeyCode::Char('p')]);
+        assert!(bindings.validate().is_ok());
+        assert_eq!(
+            bindings.action(Context::Game, KeyCode::Char('p')),
+            Some(Action::Pause)
+        );
+        assert_eq!(
+            bindings.action(Context::QuitDialog, KeyCode::Char('p')),
+            Some(Action::Yes)
+        );
+
+        // Sandbox mode reads the move keys and its own keys together
+        bindings.set(Action::Undo, vec![KeyCode::Char('h')]);
+        assert!(bindings.validate().is_err());
+    }
+}
@@ -1,72 +1,338 @@
 use core::fmt;
 use rand::Rng;
+use std::collections::VecDeque;
 use std::error::Error;
+use std::fs;
 use std::io::{self, Write};
-use std::net::{TcpListener, TcpStream};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::path::PathBuf;
 use std::result;
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
 use std::thread;
 use std::time::{Duration, Instant};
 
 use crossterm::{
     cursor::{self, MoveLeft, MoveRight, MoveTo, RestorePosition, SavePosition},
-    event::{poll, read, Event, KeyCode, KeyEvent, KeyEventKind},
+    event::{
+        poll, read, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent,
+        KeyEventKind, MouseButton, MouseEvent, MouseEventKind,
+    },
     execute,
     style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor},
     terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
     ExecutableCommand,
 };
 
-use clap::Parser;
-use local_ip_address::local_ip;
-
-use multiplayer::MessageType;
+use clap::{Parser, Subcommand};
+
+use attack::{Attack, GarbageQueue};
+use config::Config;
+use daily::Daily;
+use garbage::{GarbageGenerator, GarbageStyle};
+use glyphs::GlyphStyle;
+use input::AutoShift;
+use invisible::LockTimes;
+use keys::{Action, Context};
+use master::Master;
+use menu::{Menu, MenuChoice};
+use mode::{GameMode, Stopwatch, TopOut};
+use multiplayer::{Guest, MessageType, Rules, Spectators, Writer};
+use puzzle::{Puzzle, ScriptedTetromino};
+use randomizer::{Randomizer, SeededTetromino};
+use sandbox::{History, Snapshot};
+use server::Targeting;
 use sqlite::HighScoreRepo;
-
+use theme::{ColorSupport, Theme};
+
+mod attack;
+pub mod config;
+mod daily;
+pub mod garbage;
+pub mod glyphs;
+mod input;
+mod invisible;
+pub mod keys;
+mod master;
+mod menu;
+pub mod mode;
 mod multiplayer;
+mod puzzle;
+pub mod randomizer;
+mod sandbox;
+pub mod server;
+mod spectator;
 pub mod sqlite;
+pub mod theme;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Clone, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     #[arg(short, long, default_value_t = false)]
     multiplayer: bool,
 
-    #[arg(short, long)]
+    /// The address of the host to join, e.g. `192.168.1.183:8080` or `[fe80::1]:8080`
+    #[arg(short, long, verbatim_doc_comment)]
     server_address: Option<String>,
 
-    /// The number of lines already filled
-    #[arg(short, long, default_value_t = 0, verbatim_doc_comment)]
-    pub number_of_lines_already_filled: usize,
+    /// Watch the game of the host at --server-address without playing
+    #[arg(long, default_value_t = false)]
+    spectate: bool,
+
+    /// The address to host a game on, `::` for every IPv6 (and IPv4) interface
+    #[arg(long, global = true, default_value = "0.0.0.0", verbatim_doc_comment)]
+    bind: IpAddr,
+
+    /// The port to host a game on, 0 picks a free one
+    #[arg(long, global = true, default_value_t = multiplayer::DEFAULT_PORT, verbatim_doc_comment)]
+    port: u16,
+
+    /// The number of lines already filled [default: 0]
+    #[arg(short, long, verbatim_doc_comment)]
+    pub number_of_lines_already_filled: Option<usize>,
+
+    /// Start at level [default: 0]
+    #[arg(short, long, verbatim_doc_comment)]
+    pub level: Option<usize>,
+
+    /// Color theme: classic, guideline, monochrome, high-contrast, solarized
+    /// or the name of a file in ~/.tetris/themes (without the .theme extension) [default: classic]
+    #[arg(long, verbatim_doc_comment)]
+    pub theme: Option<String>,
+
+    /// How pieces are drawn. `letters` and `patterns` make every piece
+    /// distinguishable without colors [default: brackets]
+    #[arg(long, value_enum, verbatim_doc_comment)]
+    pub glyphs: Option<GlyphStyle>,
+
+    /// Delayed Auto Shift in milliseconds: how long a held left/right key
+    /// waits before the piece starts sliding [default: 0]
+    #[arg(long, verbatim_doc_comment)]
+    pub das: Option<u64>,
+
+    /// Auto Repeat Rate in milliseconds: the minimum delay between moves
+    /// while sliding, 0 follows the terminal's key repeat [default: 0]
+    #[arg(long, verbatim_doc_comment)]
+    pub arr: Option<u64>,
+
+    /// Show where the piece will land [default: true]
+    #[arg(long, verbatim_doc_comment)]
+    pub ghost: Option<bool>,
+
+    /// The number of next pieces to show, between 1 and 5 [default: 1]
+    #[arg(long, verbatim_doc_comment)]
+    pub preview: Option<usize>,
+
+    /// How the next piece is picked: `random`, or `bag` to get all 7 pieces
+    /// in a random order before any of them comes again [default: random]
+    #[arg(long, value_enum, verbatim_doc_comment)]
+    pub randomizer: Option<Randomizer>,
+
+    /// How often (in percent) the hole of garbage sent by the other player, rising
+    /// in Survival mode or already filled moves to another column [default: 30]
+    #[arg(long, verbatim_doc_comment)]
+    pub garbage_messiness: Option<u8>,
+
+    /// The number of rounds of a 2-player series, an odd number up to 9.
+    /// The host's choice is played [default: 3]
+    #[arg(long, verbatim_doc_comment)]
+    pub best_of: Option<u8>,
+
+    /// The name suggested when you make a high score
+    #[arg(long)]
+    pub name: Option<String>,
+
+    /// Game mode
+    #[arg(long, value_enum, default_value_t = GameMode::Marathon)]
+    pub mode: GameMode,
+
+    /// The number of lines to clear in Sprint mode [default: 40]
+    #[arg(long, verbatim_doc_comment)]
+    pub sprint_lines: Option<usize>,
+
+    /// The time limit of Ultra mode in seconds [default: 120]
+    #[arg(long, verbatim_doc_comment)]
+    pub ultra_seconds: Option<u64>,
+
+    /// The number of garbage lines to clear in Dig mode [default: 10]
+    #[arg(long, verbatim_doc_comment)]
+    pub dig_lines: Option<usize>,
+
+    /// The garbage of Dig mode: `clean` holes line up, `messy` ones don't [default: clean]
+    #[arg(long, value_enum, verbatim_doc_comment)]
+    pub dig_garbage: Option<GarbageStyle>,
+
+    /// How often (in seconds) a new garbage row rises in Dig mode, 0 turns it off [default: 10]
+    #[arg(long, verbatim_doc_comment)]
+    pub dig_rise_seconds: Option<u64>,
+
+    /// Whether pieces fall by themselves in Zen mode [default: false]
+    #[arg(long, verbatim_doc_comment)]
+    pub zen_gravity: Option<bool>,
+
+    /// What happens when the stack reaches the top in Zen mode [default: clear]
+    #[arg(long, value_enum, verbatim_doc_comment)]
+    pub zen_top_out: Option<TopOut>,
+
+    /// How long (in seconds) locked blocks stay visible in Invisible mode [default: 0]
+    #[arg(long, verbatim_doc_comment)]
+    pub invisible_fade_seconds: Option<u64>,
+
+    /// The puzzle to play in Puzzle mode: `pack/name`, `pack/number`, or a pack
+    /// to play its first unsolved puzzle [default: the first unsolved puzzle]
+    #[arg(long, verbatim_doc_comment)]
+    pub puzzle: Option<String>,
+
+    /// Play today's daily challenge: everyone gets the same mode, pieces and garbage
+    #[arg(long, default_value_t = false)]
+    pub daily: bool,
+
+    /// Start the game right away instead of showing the start menu
+    #[arg(long, default_value_t = false)]
+    pub no_menu: bool,
+}
 
-    /// Start at level
-    #[arg(short, long, default_value_t = 0, verbatim_doc_comment)]
-    pub level: usize,
+#[derive(Subcommand, Clone, Debug)]
+pub enum Command {
+    /// Host a battle between more than two players, who join it with `-m -s`
+    Serve {
+        /// The number of players a battle starts with
+        #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(2..))]
+        players: u16,
+
+        /// Who the attacks of each player go to
+        #[arg(long, value_enum, default_value_t = Targeting::Random)]
+        targeting: Targeting,
+    },
 }
 
-pub fn start(args: &Args, term_width: u16, term_height: u16) -> Result<()> {
-    let start_x = (term_width as usize - PLAY_WIDTH * CELL_WIDTH - 2) / 2;
-    let start_y = (term_height as usize - PLAY_HEIGHT - 2) / 2;
+/// Runs a subcommand, which doesn't need a terminal for the game.
+pub fn run(command: &Command, args: &Args, config: &Config) -> Result<()> {
+    match command {
+        Command::Serve { players, targeting } => {
+            let listener = multiplayer::listen(SocketAddr::new(args.bind, args.port))?;
+            println!(
+                "Battle server started. Please invite {} players to connect to {}.",
+                players,
+                multiplayer::invite_address(&listener)?
+            );
 
-    let terminal = Box::new(RealTerminal);
-    let tetromino_spawner = Box::new(RandomTetromino);
+            // The server's settings are the rules of every battle
+            server::serve(
+                listener,
+                *players as usize,
+                *targeting,
+                Rules::from_config(config),
+            )
+        }
+    }
+}
 
+/// Starts a game with the `saved` settings, on top of which the command line flags apply.
+/// Only the changes made in the start menu are saved, the flags are for this game only.
+pub fn start(args: &Args, saved: Config, term_width: u16, term_height: u16) -> Result<()> {
     let conn = sqlite::open()?;
     let sqlite_highscore_repo = Box::new(HighScoreRepo { conn });
+    sqlite_highscore_repo.create_table()?;
+
+    if args.spectate {
+        let server_address = args.server_address.as_ref().ok_or_else(|| GameError {
+            message:
+                "Spectators need the address of the host, e.g. `--spectate -s 192.168.1.183:8080`."
+                    .to_string(),
+        })?;
+        let mut stream = TcpStream::connect(server_address)?;
+        let host = multiplayer::watch(&mut stream)?;
+        let config = saved.with_args(args);
+
+        let theme = Theme::load(&config.theme)?.with_color_support(ColorSupport::detect());
+        let terminal = Box::new(RealTerminal {
+            background: theme.background,
+        });
+        return spectator::watch(stream, &host, terminal, theme, &config);
+    }
+
+    // The menu is skipped when the command line already says what to play
+    let mut args = args.clone();
+    let mut saved = saved;
+    if !args.no_menu && !args.multiplayer {
+        let mut menu = Menu::new(sqlite_highscore_repo.as_ref(), &mut args, &mut saved)?;
+        match menu.show(&mut io::stdout())? {
+            MenuChoice::Play => {}
+            MenuChoice::Host | MenuChoice::Join => menu.close()?,
+            MenuChoice::Quit => {
+                menu.close()?;
+                return Ok(());
+            }
+        }
+    }
+
+    let mut config = saved.with_args(&args);
+
+    let daily = (args.daily && !args.multiplayer).then(Daily::today);
+    if let Some(daily) = &daily {
+        daily.apply(&mut config);
+    }
+
+    let (required_width, required_height) = required_size(&config);
+    // The other player's board is only shown when the terminal is wide enough for it
+    let show_opponent =
+        args.multiplayer && term_width as usize >= required_width + DISTANCE + OPPONENT_WIDTH;
+    let mut start_x = (term_width as usize - PLAY_WIDTH * CELL_WIDTH - 2) / 2;
+    if show_opponent {
+        start_x -= (DISTANCE + OPPONENT_WIDTH) / 2;
+    }
+    let start_y = (term_height as usize).saturating_sub(required_height) / 2;
+
+    let theme = Theme::load(&config.theme)?.with_color_support(ColorSupport::detect());
+    let terminal = Box::new(RealTerminal {
+        background: theme.background,
+    });
+    let tetromino_spawner = Box::new(SeededTetromino::new(rand::random(), config.randomizer));
+
+    // Players are introduced to each other by their name
+    let name = |fallback: &str| {
+        if config.default_name.is_empty() {
+            fallback.to_string()
+        } else {
+            config.default_name.clone()
+        }
+    };
 
     if args.multiplayer {
         if args.server_address == None {
-            let listener = TcpListener::bind("0.0.0.0:8080")?;
-            let my_local_ip = local_ip()?;
+            let listener = multiplayer::listen(SocketAddr::new(args.bind, args.port))?;
             println!(
                 "Server started. Please invite your competitor to connect to {}.",
-                format!("{}:8080", my_local_ip)
+                multiplayer::invite_address(&listener)?
             );
 
-            let (stream, _) = listener.accept()?;
-            println!("Player 2 connected.");
-
+            // The host's settings are the rules of the game
+            let rules = Rules::from_config(&config);
+            let host = name("Player 1");
+            let spectators = Spectators::default();
+            let (stream, competitor) = loop {
+                let (mut stream, _) = listener.accept()?;
+                match multiplayer::welcome(&mut stream, &host, &rules) {
+                    Ok(Guest::Player(competitor)) => break (stream, competitor),
+                    Ok(Guest::Spectator) => spectators.add(stream),
+                    Err(err) => eprintln!("{}", err),
+                }
+            };
+            println!("{} connected.", competitor);
+            let reconnections = multiplayer::accept_guests(
+                listener,
+                host.clone(),
+                rules.clone(),
+                competitor.clone(),
+                spectators.clone(),
+            );
             let mut stream_clone = stream.try_clone()?;
+            let writer = Writer::new(stream);
+            multiplayer::keep_alive(writer.clone());
+
             let (sender, receiver): (Sender<MessageType>, Receiver<MessageType>) = channel();
             let mut game = Game::new(
                 terminal,
@@ -74,12 +340,18 @@ pub fn start(args: &Args, term_width: u16, term_height: u16) -> Result<()> {
                 sqlite_highscore_repo,
                 start_x,
                 start_y,
-                args.number_of_lines_already_filled,
-                args.level,
-                Some(stream),
+                config.clone(),
+                theme.clone(),
+                Some(writer),
                 Some(receiver),
                 None,
             )?;
+            game.set_competitor(host, competitor, rules);
+            game.set_spectators(spectators);
+            game.set_reconnections(reconnections);
+            if show_opponent {
+                game.show_opponent_board();
+            }
 
             thread::spawn(move || {
                 multiplayer::forward_to_main_thread(&mut stream_clone, sender);
@@ -88,9 +360,14 @@ pub fn start(args: &Args, term_width: u16, term_height: u16) -> Result<()> {
             game.start()?;
         } else {
             if let Some(server_address) = &args.server_address {
-                let stream = TcpStream::connect(server_address)?;
-
+                let mut stream = TcpStream::connect(server_address)?;
+                let player = name("Player 2");
+                let (competitor, rules) = multiplayer::join(&mut stream, &player)?;
+                rules.apply(&mut config);
                 let mut stream_clone = stream.try_clone()?;
+                let writer = Writer::new(stream);
+                multiplayer::keep_alive(writer.clone());
+
                 let (sender, receiver): (Sender<MessageType>, Receiver<MessageType>) = channel();
                 let mut game = Game::new(
                     terminal,
@@ -98,12 +375,17 @@ pub fn start(args: &Args, term_width: u16, term_height: u16) -> Result<()> {
                     sqlite_highscore_repo,
                     start_x,
                     start_y,
-                    args.number_of_lines_already_filled,
-                    args.level,
-                    Some(stream),
+                    config.clone(),
+                    theme.clone(),
+                    Some(writer),
                     Some(receiver),
                     None,
                 )?;
+                game.set_competitor(player.clone(), competitor, rules);
+                game.set_rejoin(server_address.clone(), player);
+                if show_opponent {
+                    game.show_opponent_board();
+                }
 
                 thread::spawn(move || {
                     multiplayer::forward_to_main_thread(&mut stream_clone, sender);
@@ -113,18 +395,35 @@ pub fn start(args: &Args, term_width: u16, term_height: u16) -> Result<()> {
             }
         }
     } else {
+        let puzzle = if config.mode == GameMode::Puzzle {
+            let solved = sqlite_highscore_repo.get_solved_puzzles()?;
+            Some(puzzle::find(
+                &puzzle::load_puzzles()?,
+                args.puzzle.as_deref(),
+                &solved,
+            )?)
+        } else {
+            None
+        };
+
         let mut game = Game::new(
             terminal,
             tetromino_spawner,
             sqlite_highscore_repo,
             start_x,
             start_y,
-            args.number_of_lines_already_filled,
-            args.level,
+            config,
+            theme,
             None,
             None,
             None,
         )?;
+        if let Some(puzzle) = puzzle {
+            game.set_puzzle(puzzle);
+        }
+        if let Some(daily) = daily {
+            game.set_daily(daily);
+        }
         game.start()?;
     }
 
@@ -138,16 +437,80 @@ pub const DISTANCE: usize = 6;
 
 pub const NEXT_WIDTH: usize = 6;
 const NEXT_HEIGHT: usize = 5;
+// Every extra preview piece makes the Next frame taller
+const PREVIEW_SPACING: usize = 3;
 
 pub const STATS_WIDTH: usize = 18;
 
+// Received garbage is shown in a meter this far left of the board
+const GARBAGE_METER_DISTANCE: usize = 2;
+
+// The other player's board is drawn with cells of this width, right of the Next frame
+const OPPONENT_CELL_WIDTH: usize = 2;
+const OPPONENT_WIDTH: usize = PLAY_WIDTH * OPPONENT_CELL_WIDTH + 2;
+
 pub const MAX_LEVEL: usize = 20;
+pub const MAX_NUMBER_OF_LINES_ALREADY_FILLED: usize = 10;
 const LINES_PER_LEVEL: usize = 20;
 
+/// Where the keys are read while playing in `mode`.
+fn game_context(mode: GameMode) -> Context {
+    match mode {
+        GameMode::Sandbox => Context::Sandbox,
+        _ => Context::Game,
+    }
+}
+
+/// The Help panel lists the in-game bindings, with a blank line above and below.
+fn help_messages(config: &Config) -> Vec<String> {
+    let mut messages = vec![String::new()];
+    for action in game_context(config.mode).actions() {
+        messages.push(format!(
+            "{}: {}",
+            action.label(),
+            config.keys.describe(*action)
+        ));
+    }
+    messages.push(String::new());
+
+    messages
+}
+
+fn next_height(preview_count: usize) -> usize {
+    NEXT_HEIGHT + PREVIEW_SPACING * (preview_count - 1)
+}
+
+/// Returns the terminal width and height needed to play with these settings.
+pub fn required_size(config: &Config) -> (usize, usize) {
+    let play_width = PLAY_WIDTH * CELL_WIDTH + 2;
+    let width = (STATS_WIDTH + 2 + DISTANCE) * 2 + play_width;
+
+    // The Help panel sits below the Next frame
+    let help_bottom =
+        next_height(config.preview_count) + 7 + game_context(config.mode).actions().len() + 2;
+    let height = (PLAY_HEIGHT + 2).max(help_bottom + 1);
+
+    (width, height)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CellKind {
+    Empty,
+    I,
+    O,
+    T,
+    S,
+    Z,
+    J,
+    L,
+    Garbage,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Cell {
     symbols: &'static str,
-    color: Color,
+    // The color is looked up in the active theme when the cell is rendered
+    kind: CellKind,
 }
 
 const SPACE: &str = "   ";
@@ -156,53 +519,53 @@ pub const CELL_WIDTH: usize = 3;
 
 pub const EMPTY_CELL: Cell = Cell {
     symbols: SPACE,
-    color: Color::Reset,
+    kind: CellKind::Empty,
 };
 
 pub const I_CELL: Cell = Cell {
     symbols: SQUARE_BRACKETS,
-    color: Color::Cyan,
+    kind: CellKind::I,
 };
 
 pub const O_CELL: Cell = Cell {
     symbols: SQUARE_BRACKETS,
-    color: Color::Yellow,
+    kind: CellKind::O,
 };
 
 pub const T_CELL: Cell = Cell {
     symbols: SQUARE_BRACKETS,
-    color: Color::Rgb {
-        r: 207,
-        g: 159,
-        b: 255,
-    },
+    kind: CellKind::T,
 };
 
 pub const S_CELL: Cell = Cell {
     symbols: SQUARE_BRACKETS,
-    color: Color::Green,
+    kind: CellKind::S,
 };
 
 pub const Z_CELL: Cell = Cell {
     symbols: SQUARE_BRACKETS,
-    color: Color::Red,
+    kind: CellKind::Z,
 };
 
 pub const J_CELL: Cell = Cell {
     symbols: SQUARE_BRACKETS,
-    color: Color::Blue,
+    kind: CellKind::J,
 };
 
 pub const L_CELL: Cell = Cell {
     symbols: SQUARE_BRACKETS,
-    color: Color::Rgb {
-        r: 255,
-        g: 165,
-        b: 0,
-    },
+    kind: CellKind::L,
 };
 
-#[derive(Clone)]
+pub const GARBAGE_CELL: Cell = Cell {
+    symbols: SQUARE_BRACKETS,
+    kind: CellKind::Garbage,
+};
+
+// Dig mode never shows more garbage rows than this at once
+const DIG_VISIBLE_ROWS: usize = 10;
+
+#[derive(Clone, PartialEq, Eq)]
 pub struct Position {
     // Empty row/column can go outside of the playing field
     pub row: isize,
@@ -230,12 +593,35 @@ pub struct Player {
     pub score: u64,
 }
 
+/// A finished game in a mode other than Marathon.
+pub struct Record {
+    pub name: String,
+    pub score: u64,
+    pub time_ms: u64,
+}
+
+impl Record {
+    /// Whether a game of `mode` ranks above this record: faster in the modes
+    /// ranked by time, otherwise a higher score or the same score faster.
+    fn is_beaten_by(&self, mode: GameMode, score: u64, time_ms: u64) -> bool {
+        if mode.ranks_by_time() {
+            time_ms < self.time_ms
+        } else {
+            score > self.score || (score == self.score && time_ms < self.time_ms)
+        }
+    }
+}
+
 const ENTER_YOUR_NAME_MESSAGE: &str = "Enter your name: ";
 const MAX_NAME_LENGTH: usize = 12;
 const DEFAULT_INTERVAL: u64 = 500;
+// The results of a long Sprint only show its last splits
+const MAX_SHOWN_SPLITS: usize = 10;
+// The rounds of a 2-player game start after counting down from this
+const COUNTDOWN_SECONDS: u64 = 3;
 
 #[derive(Debug)]
-struct GameError {
+pub(crate) struct GameError {
     message: String,
 }
 
@@ -249,9 +635,68 @@ impl Error for GameError {}
 
 pub type Result<T> = result::Result<T, Box<dyn Error>>;
 
+/// Returns `~/.tetris`, creating it if needed. The high score database,
+/// user themes and settings all live there.
+pub fn data_dir() -> Result<PathBuf> {
+    let home_dir = match dirs::home_dir() {
+        Some(path) => path,
+        None => {
+            return Err(Box::new(io::Error::other(
+                "Failed to get the user's home directory.",
+            )));
+        }
+    };
+
+    let dir = home_dir.join(".tetris");
+    fs::create_dir_all(&dir)?;
+
+    Ok(dir)
+}
+
+/// The rounds won by each player of the current series.
 struct MultiplayerScore {
     my_score: u8,
     competitor_score: u8,
+    my_name: String,
+    competitor_name: String,
+    best_of: u8,
+}
+
+impl MultiplayerScore {
+    /// The rounds to win for the series, more than half of them.
+    fn wins_needed(&self) -> u8 {
+        self.best_of / 2 + 1
+    }
+
+    fn is_series_over(&self) -> bool {
+        self.my_score.max(self.competitor_score) >= self.wins_needed()
+    }
+
+    /// The number of the round being played, or about to be.
+    fn round(&self) -> u8 {
+        self.my_score + self.competitor_score + 1
+    }
+
+    fn line(&self) -> String {
+        format!(
+            "{} {} - {} {}",
+            self.my_name, self.my_score, self.competitor_score, self.competitor_name
+        )
+    }
+}
+
+/// What each player answered to starting the next round, or to a rematch.
+/// The other player hasn't answered yet while `theirs` is `None`.
+#[derive(Default)]
+struct Votes {
+    mine: bool,
+    theirs: Option<bool>,
+}
+
+impl Votes {
+    fn agreed(&self) -> bool {
+        self.mine && self.theirs == Some(true)
+    }
 }
 
 pub trait HighScore {
@@ -260,6 +705,40 @@ pub trait HighScore {
     fn get_player_at_rank(&self, rank: usize) -> Result<Player>;
     fn get_top_players(&self) -> Result<Vec<Player>>;
     fn insert(&mut self, name: &str, score: usize) -> Result<()>;
+    fn get_top_records(&self, mode: GameMode) -> Result<Vec<Record>>;
+    /// Keeps a record, with the `splits` of a Sprint run.
+    fn insert_record(
+        &mut self,
+        mode: GameMode,
+        name: &str,
+        score: usize,
+        time_ms: u64,
+        splits: &[u64],
+    ) -> Result<()>;
+    /// The splits of the fastest record of `mode`, empty without one.
+    fn get_best_splits(&self, mode: GameMode) -> Result<Vec<u64>>;
+    /// The ids (`pack/name`) of the puzzles solved at least once.
+    fn get_solved_puzzles(&self) -> Result<Vec<String>>;
+    fn insert_solved_puzzle(&mut self, pack: &str, name: &str, pieces: usize) -> Result<()>;
+    /// The best try of each player at the challenge of `day`.
+    fn get_daily_records(&self, day: &str, mode: GameMode) -> Result<Vec<Record>>;
+    fn insert_daily_record(
+        &mut self,
+        day: &str,
+        mode: GameMode,
+        name: &str,
+        score: usize,
+        time_ms: u64,
+    ) -> Result<()>;
+    /// The result of a 2-player series, `wins` to `losses` in rounds.
+    fn insert_series(
+        &mut self,
+        name: &str,
+        opponent: &str,
+        best_of: u8,
+        wins: u8,
+        losses: u8,
+    ) -> Result<()>;
 }
 
 pub trait Terminal {
@@ -273,7 +752,9 @@ pub trait Terminal {
     fn disable_raw_mode(&self) -> Result<()>;
 }
 
-pub struct RealTerminal;
+pub struct RealTerminal {
+    pub background: Color,
+}
 
 impl Terminal for RealTerminal {
     fn enable_raw_mode(&self) -> Result<()> {
@@ -297,7 +778,7 @@ impl Terminal for RealTerminal {
             io::stdout(),
             SavePosition,
             SetForegroundColor(foreground_color),
-            SetBackgroundColor(Color::Black),
+            SetBackgroundColor(self.background),
             MoveTo(col, row),
             Print(msg),
             ResetColor,
@@ -335,7 +816,27 @@ pub struct RandomTetromino;
 
 impl TetrominoSpawner for RandomTetromino {
     fn spawn(&self, is_next: bool) -> Tetromino {
-        let i_tetromino_states: Vec<Vec<Vec<Cell>>> = vec![
+        let mut rng = rand::thread_rng();
+        let kind = TETROMINO_KINDS[rng.gen_range(0..TETROMINO_KINDS.len())];
+
+        Tetromino::new(kind, is_next)
+    }
+}
+
+const TETROMINO_KINDS: [CellKind; 7] = [
+    CellKind::I,
+    CellKind::O,
+    CellKind::T,
+    CellKind::S,
+    CellKind::Z,
+    CellKind::J,
+    CellKind::L,
+];
+
+/// The rotation states of a piece. Other kinds of cells make a piece with no blocks.
+fn tetromino_states(kind: CellKind) -> Vec<Vec<Vec<Cell>>> {
+    match kind {
+        CellKind::I => vec![
             vec![
                 vec![EMPTY_CELL, EMPTY_CELL, EMPTY_CELL, EMPTY_CELL],
                 vec![I_CELL, I_CELL, I_CELL, I_CELL],
@@ -360,12 +861,9 @@ impl TetrominoSpawner for RandomTetromino {
                 vec![EMPTY_CELL, I_CELL, EMPTY_CELL, EMPTY_CELL],
                 vec![EMPTY_CELL, I_CELL, EMPTY_CELL, EMPTY_CELL],
             ],
-        ];
-
-        let o_tetromino_states: Vec<Vec<Vec<Cell>>> =
-            vec![vec![vec![O_CELL, O_CELL], vec![O_CELL, O_CELL]]];
-
-        let t_tetromino_states: Vec<Vec<Vec<Cell>>> = vec![
+        ],
+        CellKind::O => vec![vec![vec![O_CELL, O_CELL], vec![O_CELL, O_CELL]]],
+        CellKind::T => vec![
             vec![
                 vec![EMPTY_CELL, T_CELL, EMPTY_CELL],
                 vec![T_CELL, T_CELL, T_CELL],
@@ -386,9 +884,8 @@ impl TetrominoSpawner for RandomTetromino {
                 vec![T_CELL, T_CELL, EMPTY_CELL],
                 vec![EMPTY_CELL, T_CELL, EMPTY_CELL],
             ],
-        ];
-
-        let s_tetromino_states: Vec<Vec<Vec<Cell>>> = vec![
+        ],
+        CellKind::S => vec![
             vec![
                 vec![EMPTY_CELL, S_CELL, S_CELL],
                 vec![S_CELL, S_CELL, EMPTY_CELL],
@@ -409,9 +906,8 @@ impl TetrominoSpawner for RandomTetromino {
                 vec![S_CELL, S_CELL, EMPTY_CELL],
                 vec![EMPTY_CELL, S_CELL, EMPTY_CELL],
             ],
-        ];
-
-        let z_tetromino_states: Vec<Vec<Vec<Cell>>> = vec![
+        ],
+        CellKind::Z => vec![
             vec![
                 vec![Z_CELL, Z_CELL, EMPTY_CELL],
                 vec![EMPTY_CELL, Z_CELL, Z_CELL],
@@ -432,9 +928,8 @@ impl TetrominoSpawner for RandomTetromino {
                 vec![Z_CELL, Z_CELL, EMPTY_CELL],
                 vec![Z_CELL, EMPTY_CELL, EMPTY_CELL],
             ],
-        ];
-
-        let j_tetromino_states: Vec<Vec<Vec<Cell>>> = vec![
+        ],
+        CellKind::J => vec![
             vec![
                 vec![J_CELL, EMPTY_CELL, EMPTY_CELL],
                 vec![J_CELL, J_CELL, J_CELL],
@@ -455,9 +950,8 @@ impl TetrominoSpawner for RandomTetromino {
                 vec![EMPTY_CELL, J_CELL, EMPTY_CELL],
                 vec![J_CELL, J_CELL, EMPTY_CELL],
             ],
-        ];
-
-        let l_tetromino_states: Vec<Vec<Vec<Cell>>> = vec![
+        ],
+        CellKind::L => vec![
             vec![
                 vec![EMPTY_CELL, EMPTY_CELL, L_CELL],
                 vec![L_CELL, L_CELL, L_CELL],
@@ -478,22 +972,15 @@ impl TetrominoSpawner for RandomTetromino {
                 vec![EMPTY_CELL, L_CELL, EMPTY_CELL],
                 vec![EMPTY_CELL, L_CELL, EMPTY_CELL],
             ],
-        ];
-
-        let tetromino_states = vec![
-            i_tetromino_states.clone(),
-            o_tetromino_states.clone(),
-            t_tetromino_states.clone(),
-            s_tetromino_states.clone(),
-            z_tetromino_states.clone(),
-            j_tetromino_states.clone(),
-            l_tetromino_states.clone(),
-        ];
-
-        let mut rng = rand::thread_rng();
-        let random_tetromino_index = rng.gen_range(0..tetromino_states.len());
+        ],
+        CellKind::Empty | CellKind::Garbage => vec![vec![vec![EMPTY_CELL]]],
+    }
+}
 
-        let states = tetromino_states[random_tetromino_index].clone();
+impl Tetromino {
+    /// A new piece at the top of the board, or in the Next frame.
+    pub fn new(kind: CellKind, is_next: bool) -> Tetromino {
+        let states = tetromino_states(kind);
         let tetromino_with = tetromino_width(&states[0]);
 
         let mut row = 0;
@@ -509,6 +996,16 @@ impl TetrominoSpawner for RandomTetromino {
             position: Position { row, col },
         }
     }
+
+    /// The kind of the blocks of the piece, `Empty` for a piece with no blocks.
+    pub fn kind(&self) -> CellKind {
+        self.states[0]
+            .iter()
+            .flatten()
+            .map(|cell| cell.kind)
+            .find(|kind| *kind != CellKind::Empty)
+            .unwrap_or(CellKind::Empty)
+    }
 }
 
 pub struct Game {
@@ -517,19 +1014,68 @@ pub struct Game {
     highscore_repo: Box<dyn HighScore + Send>,
     play_grid: Vec<Vec<Cell>>,
     pub current_tetromino: Tetromino,
-    next_tetromino: Tetromino,
+    next_tetrominoes: VecDeque<Tetromino>,
     start_x: usize,
     start_y: usize,
     lines: usize,
-    start_at_level: usize,
     level: usize,
     pub score: usize,
     drop_interval: u64,
     paused: bool,
-    stream: Option<TcpStream>,
+    // Playing time and the number of locked pieces, shown in timed modes
+    clock: Stopwatch,
+    pieces: usize,
+    // Where garbage rows come from, whatever brings them in
+    garbage: GarbageGenerator,
+    // Dig mode: how many rows were added and cleared, and when (in ms of
+    // playing time) the next one rises
+    garbage_added: usize,
+    garbage_cleared: usize,
+    next_rise_ms: u64,
+    // Sprint mode: the time every `mode::SPLIT_LINES` lines
+    splits: Vec<u64>,
+    // Master mode: section times, combo, and the gravity and lock delay of the piece
+    master: Master,
+    // Invisible mode: when the blocks were locked, and until when the stack is shown
+    lock_times: LockTimes,
+    revealed_until_ms: u64,
+    // Puzzle mode: the puzzle being played and whether its goal has been reached
+    puzzle: Option<Puzzle>,
+    puzzle_solved: bool,
+    // Whether the last move of the current piece was a rotation, for T-spins
+    rotated_last: bool,
+    // Sandbox mode: the moves and edits that can be undone
+    history: History,
+    // The daily challenge being played, whose seed decides the pieces and garbage
+    daily: Option<Daily>,
+    // The rules agreed with the other player, whose seed decides the pieces and garbage
+    rules: Option<Rules>,
+    // The last board received from the other player, if it is shown
+    opponent_board: Option<Vec<Vec<CellKind>>>,
+    // The last board sent to the other player
+    sent_board: Vec<Vec<CellKind>>,
+    // The garbage lines our clears send to the other player
+    attack: Attack,
+    // The garbage lines received from the other player, and the last ones shown in the meter
+    incoming: GarbageQueue,
+    shown_incoming: (usize, usize),
+    config: Config,
+    theme: Theme,
+    stream: Option<Writer>,
     receiver: Option<Receiver<MessageType>>,
+    // Who watches the game of the host, and sees both boards
+    spectators: Option<Spectators>,
+    // When the other player was last heard from, and whether they are gone since
+    last_heard: Instant,
+    disconnected: bool,
+    // Where the other player's new connection comes from after a disconnect, and the
+    // address and name to connect again with when we joined the game
+    reconnections: Option<Receiver<TcpStream>>,
+    rejoin: Option<(String, String)>,
     multiplayer_score: MultiplayerScore,
-    start_with_number_of_filled_lines: usize,
+    // Whether the players are ready for the next round, and want a rematch once the series is over
+    ready: Votes,
+    rematch: Votes,
     // This is only used for integration testing purposes
     state_sender: Option<Sender<Vec<Vec<Cell>>>>,
 }
@@ -541,48 +1087,83 @@ impl Game {
         sqlite_highscore_repo: Box<dyn HighScore + Send>,
         start_x: usize,
         start_y: usize,
-        start_with_number_of_filled_lines: usize,
-        start_at_level: usize,
-        stream: Option<TcpStream>,
+        config: Config,
+        theme: Theme,
+        stream: Option<Writer>,
         receiver: Option<Receiver<MessageType>>,
         state_sender: Option<Sender<Vec<Vec<Cell>>>>,
     ) -> Result<Self> {
-        let play_grid = create_grid(PLAY_WIDTH, PLAY_HEIGHT, start_with_number_of_filled_lines);
-
         let current_tetromino = tetromino_spawner.spawn(false);
-        let next_tetromino = tetromino_spawner.spawn(true);
+        let next_tetrominoes = (0..config.preview_count)
+            .map(|_| tetromino_spawner.spawn(true))
+            .collect();
 
         let mut drop_interval: u64 = DEFAULT_INTERVAL;
-        for _i in 1..=start_at_level {
+        for _i in 1..=config.level {
             drop_interval -= drop_interval / 10;
         }
 
         sqlite_highscore_repo.create_table()?;
 
-        Ok(Game {
+        let mut game = Game {
             terminal,
             tetromino_spawner,
             highscore_repo: sqlite_highscore_repo,
-            play_grid,
+            // Filled by set_up_mode
+            play_grid: Vec::new(),
             current_tetromino,
-            next_tetromino,
+            next_tetrominoes,
             start_x,
             start_y,
             lines: 0,
-            start_at_level,
-            level: start_at_level,
+            level: config.level,
             score: 0,
             drop_interval,
             paused: false,
-            stream,
-            receiver,
+            clock: Stopwatch::start(),
+            pieces: 0,
+            garbage: GarbageGenerator::new(config.garbage_messiness),
+            garbage_added: 0,
+            garbage_cleared: 0,
+            next_rise_ms: 0,
+            splits: Vec::new(),
+            master: Master::new(),
+            lock_times: LockTimes::new(),
+            revealed_until_ms: 0,
+            puzzle: None,
+            puzzle_solved: false,
+            rotated_last: false,
+            history: History::new(),
+            daily: None,
+            rules: None,
+            opponent_board: None,
+            sent_board: Vec::new(),
+            attack: Attack::new(),
+            incoming: GarbageQueue::new(),
+            shown_incoming: (0, 0),
+            spectators: None,
+            last_heard: Instant::now(),
+            disconnected: false,
+            reconnections: None,
+            rejoin: None,
             multiplayer_score: MultiplayerScore {
                 my_score: 0,
                 competitor_score: 0,
+                my_name: String::new(),
+                competitor_name: String::new(),
+                best_of: config.best_of,
             },
-            start_with_number_of_filled_lines,
+            ready: Votes::default(),
+            rematch: Votes::default(),
+            config,
+            theme,
+            stream,
+            receiver,
             state_sender,
-        })
+        };
+        game.set_up_mode();
+
+        Ok(game)
     }
 
     pub fn start(&mut self) -> Result<()> {
@@ -591,6 +1172,11 @@ impl Game {
 
         let mut stdout = io::stdout();
         self.render(&mut stdout)?;
+        if self.stream.is_some() {
+            self.wait_until_ready("GET READY", &mut stdout)?;
+            self.render(&mut stdout)?;
+            self.count_down(&mut stdout)?;
+        }
 
         match self.handle_event(&mut stdout) {
             Ok(_) => {}
@@ -601,24 +1187,22 @@ impl Game {
     }
 
     pub fn reset(&mut self) {
-        // Reset play and preview grids
-        self.play_grid = create_grid(
-            PLAY_WIDTH,
-            PLAY_HEIGHT,
-            self.start_with_number_of_filled_lines,
-        );
-
         // Reset tetrominos
         self.current_tetromino = self.tetromino_spawner.spawn(false);
-        self.next_tetromino = self.tetromino_spawner.spawn(true);
+        self.next_tetrominoes = (0..self.config.preview_count)
+            .map(|_| self.tetromino_spawner.spawn(true))
+            .collect();
 
         // Reset game statistics
         self.lines = 0;
-        self.level = self.start_at_level;
+        self.level = self.config.level;
         self.score = 0;
+        self.clock = Stopwatch::start();
+        self.pieces = 0;
+        self.set_up_mode();
 
         let mut drop_interval: u64 = DEFAULT_INTERVAL;
-        for _i in 1..=self.start_at_level {
+        for _i in 1..=self.config.level {
             drop_interval -= drop_interval / 10;
         }
         self.drop_interval = drop_interval;
@@ -632,6 +1216,168 @@ impl Game {
         self.paused = false;
     }
 
+    /// Prepares the board with its lines already filled, and whatever the game mode needs.
+    fn set_up_mode(&mut self) {
+        self.lock_times = LockTimes::new();
+        self.revealed_until_ms = 0;
+        self.history = History::new();
+        self.attack = Attack::new();
+        self.incoming = GarbageQueue::new();
+        self.splits = Vec::new();
+
+        // Garbage is the same for everyone playing the daily challenge, and for
+        // both players of a 2-player game
+        let seed = match (&self.daily, &self.rules) {
+            (Some(daily), _) => Some(daily.seed),
+            (None, Some(rules)) => Some(rules.seed),
+            (None, None) => None,
+        };
+        let messiness = if self.config.mode == GameMode::Dig {
+            self.config.dig_garbage.messiness()
+        } else {
+            self.config.garbage_messiness
+        };
+        self.garbage = match seed {
+            Some(seed) => GarbageGenerator::seeded(messiness, seed),
+            None => GarbageGenerator::new(messiness),
+        };
+        self.play_grid = create_grid(
+            PLAY_WIDTH,
+            PLAY_HEIGHT,
+            self.config.number_of_lines_already_filled,
+            &mut self.garbage,
+        );
+
+        if self.config.mode == GameMode::Master {
+            self.level = 0;
+            self.master = Master::new();
+        }
+
+        if let Some(puzzle) = &self.puzzle {
+            // Puzzles start over from their first piece
+            self.play_grid = puzzle.board();
+            self.set_spawner(Box::new(ScriptedTetromino::new(puzzle.pieces.clone())));
+            self.puzzle_solved = false;
+        }
+
+        if let Some(daily) = &self.daily {
+            // Every try of the day starts over from the same pieces
+            self.set_spawner(Box::new(SeededTetromino::new(
+                daily.seed,
+                Randomizer::Random,
+            )));
+        }
+
+        if let Some(rules) = &self.rules {
+            // Both players start every game from the same pieces
+            self.set_spawner(Box::new(SeededTetromino::new(rules.seed, rules.randomizer)));
+        }
+
+        if self.config.mode == GameMode::Survival {
+            self.next_rise_ms = garbage::survival_rise_ms(self.level);
+        }
+
+        if self.config.mode == GameMode::Dig {
+            self.play_grid = create_grid(PLAY_WIDTH, PLAY_HEIGHT, 0, &mut self.garbage);
+            self.garbage_added = 0;
+            self.garbage_cleared = 0;
+            self.next_rise_ms = self.config.dig_rise_seconds * 1000;
+            self.refill_garbage();
+        }
+    }
+
+    /// Plays `puzzle` instead of the usual empty board and random pieces.
+    pub fn set_puzzle(&mut self, puzzle: Puzzle) {
+        self.puzzle = Some(puzzle);
+        self.set_up_mode();
+    }
+
+    /// Plays the pieces and garbage of a daily challenge, and ranks the result
+    /// among the other tries of the day.
+    pub fn set_daily(&mut self, daily: Daily) {
+        self.daily = Some(daily);
+        self.set_up_mode();
+    }
+
+    /// Plays a 2-player series as `name` against `competitor`, with the rules agreed
+    /// in the handshake.
+    pub fn set_competitor(&mut self, name: String, competitor: String, rules: Rules) {
+        self.multiplayer_score.my_name = name;
+        self.multiplayer_score.competitor_name = competitor;
+        self.multiplayer_score.best_of = rules.best_of;
+        self.rules = Some(rules);
+        self.set_up_mode();
+    }
+
+    /// Shows both boards to `spectators` as the game goes.
+    pub fn set_spectators(&mut self, spectators: Spectators) {
+        self.spectators = Some(spectators);
+    }
+
+    /// Takes the other player back from `reconnections` when they connect again.
+    pub fn set_reconnections(&mut self, reconnections: Receiver<TcpStream>) {
+        self.reconnections = Some(reconnections);
+    }
+
+    /// Connects again to the host at `address` as `name` when the connection is lost.
+    pub fn set_rejoin(&mut self, address: String, name: String) {
+        self.rejoin = Some((address, name));
+    }
+
+    /// Draws the other player's board beside ours, as they play.
+    pub fn show_opponent_board(&mut self) {
+        self.opponent_board = Some(vec![vec![CellKind::Empty; PLAY_WIDTH]; PLAY_HEIGHT]);
+    }
+
+    /// Takes the pieces from `spawner` from now on, the current and next ones included.
+    fn set_spawner(&mut self, spawner: Box<dyn TetrominoSpawner + Send>) {
+        self.tetromino_spawner = spawner;
+        self.current_tetromino = self.tetromino_spawner.spawn(false);
+        self.next_tetrominoes = (0..self.config.preview_count)
+            .map(|_| self.tetromino_spawner.spawn(true))
+            .collect();
+    }
+
+    /// Adds garbage below the stack until there are `DIG_VISIBLE_ROWS` of them
+    /// or all the garbage lines of the game have been added.
+    fn refill_garbage(&mut self) {
+        let visible_rows = self
+            .play_grid
+            .iter()
+            .filter(|row| row.iter().any(|cell| cell.kind == CellKind::Garbage))
+            .count();
+        let missing_rows = DIG_VISIBLE_ROWS
+            .saturating_sub(visible_rows)
+            .min(self.config.dig_lines - self.garbage_added);
+
+        let rows = (0..missing_rows).map(|_| self.garbage.next_row()).collect();
+        self.garbage_added += missing_rows;
+        self.add_garbage_rows(rows);
+    }
+
+    /// Pushes rows in from the bottom of the board, the top rows are dropped.
+    /// The current piece moves up if the stack now overlaps it.
+    fn add_garbage_rows(&mut self, rows: Vec<Vec<Cell>>) {
+        let time_ms = self.time_ms();
+        for row in rows {
+            self.play_grid.remove(0);
+            self.play_grid.push(row);
+            self.lock_times.push_row(time_ms);
+        }
+
+        self.lift_current_tetromino();
+    }
+
+    /// Moves the current piece up until it no longer overlaps the stack.
+    fn lift_current_tetromino(&mut self) {
+        let tetromino = &self.current_tetromino;
+        let mut row = tetromino.position.row;
+        while row > 0 && !self.can_move(tetromino, row as i16, tetromino.position.col as i16) {
+            row -= 1;
+        }
+        self.current_tetromino.position.row = row;
+    }
+
     pub fn render(&self, stdout: &mut std::io::Stdout) -> Result<()> {
         self.terminal.clear()?;
 
@@ -655,26 +1401,44 @@ impl Game {
             next_start_x,
             self.start_y,
             NEXT_WIDTH * 3,
-            NEXT_HEIGHT + 1,
+            next_height(self.config.preview_count) + 1,
         )?;
         self.render_next_tetromino()?;
 
         let stats_start_x = self.start_x - DISTANCE - STATS_WIDTH - 1;
+        let stats: Vec<String> = self
+            .stats()
+            .iter()
+            .map(|(label, value)| format!("{}: {}", label, value))
+            .collect();
         self.print_left_aligned_messages(
             stdout,
             "Stats",
             Some(STATS_WIDTH.into()),
             stats_start_x as u16,
             self.start_y as u16 + 1,
-            vec![
-                "",
-                format!("Score: {}", self.score).as_str(),
-                format!("Lines: {}", self.lines).as_str(),
-                format!("Level: {}", self.level).as_str(),
-                "",
-            ],
+            [""].into_iter()
+                .chain(stats.iter().map(|s| s.as_str()))
+                .chain([""])
+                .collect(),
         )?;
 
+        if self.stream.is_some() {
+            self.render_garbage_meter()?;
+        }
+
+        if let Some(board) = &self.opponent_board {
+            self.render_frame(
+                stdout,
+                "Rival",
+                self.opponent_start_x(),
+                self.start_y,
+                PLAY_WIDTH * OPPONENT_CELL_WIDTH,
+                PLAY_HEIGHT + 1,
+            )?;
+            self.render_opponent_cells(board, None)?;
+        }
+
         if let Some(_) = &self.stream {
             self.print_left_aligned_messages(
                 stdout,
@@ -689,28 +1453,20 @@ impl Game {
                         self.multiplayer_score.my_score, self.multiplayer_score.competitor_score,
                     )
                     .as_str(),
+                    format!("Rival: {}", self.competitor_label()).as_str(),
                     "",
                 ],
             )?;
         }
 
+        let help_messages = help_messages(&self.config);
         self.print_left_aligned_messages(
             stdout,
             "Help",
             None,
             next_start_x as u16,
-            self.start_y as u16 + NEXT_HEIGHT as u16 + 7,
-            vec![
-                "",
-                "Left: h, ←",
-                "Right: l, →",
-                "Rotate: Space",
-                "Soft Drop: s, ↑",
-                "Hard Drop: j, ↓",
-                "Pause: p",
-                "Quit: q",
-                "",
-            ],
+            self.start_y as u16 + next_height(self.config.preview_count) as u16 + 7,
+            help_messages.iter().map(|s| s.as_str()).collect(),
         )?;
 
         Ok(())
@@ -728,7 +1484,7 @@ impl Game {
         // Print the top border
         let left = (width - title.len() - 2) / 2;
         self.terminal.write(
-            Color::White,
+            self.theme.foreground,
             start_x as u16,
             start_y as u16,
             format!(
@@ -743,13 +1499,13 @@ impl Game {
         // Print the left and right borders
         for index in 1..height {
             self.terminal.write(
-                Color::White,
+                self.theme.foreground,
                 start_x as u16,
                 start_y as u16 + index as u16,
                 "|",
             )?;
             self.terminal.write(
-                Color::White,
+                self.theme.foreground,
                 start_x as u16 + width as u16 + 1,
                 start_y as u16 + index as u16,
                 "|",
@@ -758,7 +1514,7 @@ impl Game {
 
         // Print the bottom border
         self.terminal.write(
-            Color::White,
+            self.theme.foreground,
             start_x as u16,
             start_y as u16 + height as u16,
             format!("|{}|", ("-").repeat(width as usize)).as_str(),
@@ -789,7 +1545,7 @@ impl Game {
         // Print the top border
         let left = (frame_width - title.len() - 2) / 2;
         self.terminal.write(
-            Color::White,
+            self.theme.foreground,
             start_x,
             start_y - 1,
             format!(
@@ -807,23 +1563,25 @@ impl Game {
         for (index, message) in messages.iter().enumerate() {
             if message.len() == 0 {
                 self.terminal.write(
-                    Color::White,
+                    self.theme.foreground,
                     start_x,
                     start_y + index as u16,
                     format!("|{}|", " ".repeat(frame_width)).as_str(),
                 )?;
             } else {
-                let parts: Vec<&str> = message.split(':').collect();
+                let parts: Vec<&str> = message.splitn(2, ':').collect();
 
                 let right_padding_spaces: String;
                 if let Some(value) = width {
-                    right_padding_spaces = " ".repeat(value - 2 - message.chars().count());
+                    // Keys are padded to the longest one
+                    right_padding_spaces =
+                        " ".repeat(value - 3 - longest_key_length - parts[1].chars().count());
                 } else {
                     right_padding_spaces =
                         " ".repeat(longest_value_length - parts[1].chars().count());
                 }
                 self.terminal.write(
-                    Color::White,
+                    self.theme.foreground,
                     start_x,
                     start_y + index as u16,
                     format!(
@@ -841,7 +1599,7 @@ impl Game {
         // Print the bottom border
         let bottom_border_y = start_y + messages.len() as u16;
         self.terminal.write(
-            Color::White,
+            self.theme.foreground,
             start_x,
             bottom_border_y,
             format!("{}{}{}", "|", ("-").repeat(frame_width), "|").as_str(),
@@ -852,79 +1610,796 @@ impl Game {
         Ok(())
     }
 
-    pub fn render_changed_portions(&self) -> Result<()> {
-        self.render_play_grid()?;
+    /// Draws the received garbage beside the board, from the bottom up: `#` for
+    /// lines ready to enter the board, `+` for lines still waiting.
+    fn render_garbage_meter(&self) -> Result<()> {
+        let (total, ready) = self.incoming.pending(self.time_ms());
+        for height in 0..PLAY_HEIGHT {
+            let (color, symbol) = if height < ready {
+                (self.theme.cell_color(CellKind::Z), "#")
+            } else if height < total {
+                (self.theme.cell_color(CellKind::Garbage), "+")
+            } else {
+                (self.theme.foreground, " ")
+            };
+            self.terminal.write(
+                color,
+                (self.start_x - GARBAGE_METER_DISTANCE) as u16,
+                (self.start_y + PLAY_HEIGHT - height) as u16,
+                symbol,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Redraws the garbage meter when garbage is received, cancelled, gets ready or enters the board.
+    fn update_garbage_meter(&mut self) -> Result<()> {
+        if self.stream.is_none() {
+            return Ok(());
+        }
+
+        let incoming = self.incoming.pending(self.time_ms());
+        if incoming != self.shown_incoming {
+            self.shown_incoming = incoming;
+            self.render_garbage_meter()?;
+        }
+
+        Ok(())
+    }
+
+    /// The other player's name, cut to fit in the 2-Player panel.
+    fn competitor_label(&self) -> String {
+        self.multiplayer_score
+            .competitor_name
+            .chars()
+            .take(STATS_WIDTH - "Rival: ".len() - 2)
+            .collect()
+    }
+
+    /// Shows the new rival picked by a battle server.
+    fn update_competitor_name(&mut self, name: String) -> Result<()> {
+        self.multiplayer_score.competitor_name = name;
 
         let stats_start_x = self.start_x - STATS_WIDTH - DISTANCE - 1;
+        let width = STATS_WIDTH - "Rival: ".len() - 2;
         self.terminal.write(
-            Color::White,
-            stats_start_x as u16 + 2 + "Score: ".len() as u16,
-            self.start_y as u16 + 2,
-            self.score.to_string().as_str(),
-        )?;
-        self.terminal.write(
-            Color::White,
-            stats_start_x as u16 + 2 + "Lines: ".len() as u16,
-            self.start_y as u16 + 3,
-            self.lines.to_string().as_str(),
-        )?;
-        self.terminal.write(
-            Color::White,
-            stats_start_x as u16 + 2 + "Level: ".len() as u16,
-            self.start_y as u16 + 4,
-            self.level.to_string().as_str(),
+            self.theme.foreground,
+            stats_start_x as u16 + 2 + "Rival: ".len() as u16,
+            self.start_y as u16 + 11,
+            format!("{:<width$}", self.competitor_label(), width = width).as_str(),
         )?;
 
         Ok(())
     }
 
-    pub fn render_play_grid(&self) -> Result<()> {
-        for (y, row) in self.play_grid.iter().enumerate() {
-            for (x, &ref cell) in row.iter().enumerate() {
-                let screen_x = self.start_x + 1 + x * CELL_WIDTH;
-                let screen_y = self.start_y + 1 + y;
-                self.terminal
-                    .write(cell.color, screen_x as u16, screen_y as u16, cell.symbols)?;
+    fn opponent_start_x(&self) -> usize {
+        let next_start_x = self.start_x + PLAY_WIDTH * CELL_WIDTH + 1 + DISTANCE;
+        next_start_x + NEXT_WIDTH * CELL_WIDTH + 1 + DISTANCE
+    }
+
+    /// Draws the cells of the other player's board, only those that differ from
+    /// `previous` when it is given.
+    fn render_opponent_cells(
+        &self,
+        board: &[Vec<CellKind>],
+        previous: Option<&[Vec<CellKind>]>,
+    ) -> Result<()> {
+        let start_x = self.opponent_start_x();
+        for (y, row) in board.iter().take(PLAY_HEIGHT).enumerate() {
+            for (x, &kind) in row.iter().take(PLAY_WIDTH).enumerate() {
+                if previous.and_then(|previous| previous.get(y)?.get(x)) == Some(&kind) {
+                    continue;
+                }
+
+                self.terminal.write(
+                    self.theme.cell_color(kind),
+                    (start_x + 1 + x * OPPONENT_CELL_WIDTH) as u16,
+                    (self.start_y + 1 + y) as u16,
+                    if kind == CellKind::Empty { "  " } else { "[]" },
+                )?;
             }
         }
 
         Ok(())
     }
 
-    pub fn handle_event(&mut self, stdout: &mut std::io::Stdout) -> Result<()> {
-        let mut drop_timer = Instant::now();
-        let mut soft_drop_timer = Instant::now();
+    /// Our board with the current piece, as the other player sees it.
+    fn board_snapshot(&self) -> Vec<Vec<CellKind>> {
+        let mut board: Vec<Vec<CellKind>> = self
+            .play_grid
+            .iter()
+            .map(|row| row.iter().map(|cell| cell.kind).collect())
+            .collect();
 
-        let mut reset_needed = false;
-        loop {
-            if self.paused {
-                self.handle_pause_event(stdout)?;
-            } else {
-                if self.level <= MAX_LEVEL && self.lines >= LINES_PER_LEVEL * (self.level + 1) {
-                    self.level += 1;
-                    self.drop_interval -= self.drop_interval / 10;
+        let tetromino = &self.current_tetromino;
+        for (row_index, row) in tetromino.get_cells().iter().enumerate() {
+            for (col_index, cell) in row.iter().enumerate() {
+                let grid_y = tetromino.position.row + row_index as isize;
+                let grid_x = tetromino.position.col + col_index as isize;
+                if cell.kind != CellKind::Empty
+                    && (0..PLAY_HEIGHT as isize).contains(&grid_y)
+                    && (0..PLAY_WIDTH as isize).contains(&grid_x)
+                {
+                    board[grid_y as usize][grid_x as usize] = cell.kind;
                 }
+            }
+        }
 
-                if drop_timer.elapsed() >= Duration::from_millis(self.drop_interval) {
-                    let mut tetromino = self.current_tetromino.clone();
-                    let can_move_down = self.can_move(
-                        &tetromino,
-                        tetromino.position.row as i16 + 1,
-                        tetromino.position.col as i16,
-                    );
-
-                    if can_move_down {
-                        tetromino.move_down(self, stdout)?;
-                        self.current_tetromino = tetromino;
-                    } else {
-                        self.lock_and_move_to_next(&tetromino, stdout)?;
-                    }
+        board
+    }
+
+    /// Sends `message` to the other player. A lost connection is noticed by the heartbeats.
+    fn send(&mut self, message: MessageType) {
+        if let Some(stream) = &self.stream {
+            let _ = stream.send(&message);
+        }
+    }
+
+    /// The messages of the other player since the last call, and whether their connection is closed.
+    fn receive(&mut self) -> (Vec<MessageType>, bool) {
+        let mut messages = Vec::new();
+        let mut closed = false;
+        if let Some(receiver) = &self.receiver {
+            loop {
+                match receiver.try_recv() {
+                    Ok(message) => messages.push(message),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        closed = true;
+                        break;
+                    }
+                }
+            }
+        }
+        if !messages.is_empty() {
+            self.last_heard = Instant::now();
+        }
+
+        (messages, closed)
+    }
+
+    /// Notices when the other player is gone, and resumes the match when they are back.
+    /// Returns whether the screen was drawn again.
+    fn check_connection(&mut self, closed: bool, stdout: &mut io::Stdout) -> Result<bool> {
+        let reconnection = self
+            .reconnections
+            .as_ref()
+            .and_then(|reconnections| reconnections.try_recv().ok());
+        match reconnection {
+            Some(stream) if self.disconnected => self.resume_match(stream, stdout)?,
+            // Someone else connected with their name, the other player is still here
+            Some(_) => return Ok(false),
+            None if !self.disconnected
+                && (closed || self.last_heard.elapsed() >= multiplayer::HEARTBEAT_TIMEOUT) =>
+            {
+                self.handle_disconnect(stdout)?
+            }
+            None => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    /// Pauses the game until the other player connects again, or until we choose
+    /// to go on alone or to quit. The match resumes whenever they come back.
+    fn handle_disconnect(&mut self, stdout: &mut io::Stdout) -> Result<()> {
+        self.disconnected = true;
+        if let Some((address, name)) = &self.rejoin {
+            self.reconnections = Some(multiplayer::reconnect(address.clone(), name.clone()));
+        }
+
+        self.paused = true;
+        self.clock.pause();
+        let lost = format!("{} disconnected.", self.multiplayer_score.competitor_name);
+        let prompt = self.config.keys.prompt(Context::Disconnected.actions());
+        self.print_centered_messages(
+            stdout,
+            None,
+            vec![&lost, "Wait for them or continue solo?", "", &prompt],
+        )?;
+
+        loop {
+            let reconnection = self
+                .reconnections
+                .as_ref()
+                .and_then(|reconnections| reconnections.try_recv().ok());
+            if let Some(stream) = reconnection {
+                return self.resume_match(stream, stdout);
+            }
+
+            if !self.terminal.poll_event(Duration::from_millis(10))? {
+                continue;
+            }
+            if let Event::Key(KeyEvent {
+                code,
+                kind: KeyEventKind::Press,
+                ..
+            }) = self.terminal.read_event()?
+            {
+                match self.config.keys.action(Context::Disconnected, code) {
+                    Some(Action::Wait) => {
+                        let waiting = format!(
+                            "Waiting for {} to connect again.",
+                            self.multiplayer_score.competitor_name
+                        );
+                        let prompt = self.config.keys.prompt(&[Action::Continue, Action::Quit]);
+                        self.render(stdout)?;
+                        self.print_centered_messages(stdout, None, vec![&waiting, "", &prompt])?;
+                    }
+                    Some(Action::Continue) => {
+                        self.render(stdout)?;
+                        self.paused = false;
+                        self.clock.resume();
+                        return Ok(());
+                    }
+                    Some(Action::Quit) => self.quit()?,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Goes on playing with the other player over their new connection.
+    fn resume_match(&mut self, stream: TcpStream, stdout: &mut io::Stdout) -> Result<()> {
+        if let Some(lost) = self.stream.take() {
+            lost.shutdown();
+        }
+
+        let mut stream_clone = stream.try_clone()?;
+        let writer = Writer::new(stream);
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            multiplayer::forward_to_main_thread(&mut stream_clone, sender);
+        });
+        multiplayer::keep_alive(writer.clone());
+        self.stream = Some(writer);
+        self.receiver = Some(receiver);
+
+        self.disconnected = false;
+        self.last_heard = Instant::now();
+        if self.rejoin.is_some() {
+            self.reconnections = None;
+        }
+        // The other player sees our whole board again
+        self.sent_board = Vec::new();
+        // Our votes may have been lost with the old connection
+        if self.ready.mine {
+            self.send(MessageType::Ready);
+        }
+        if self.rematch.mine {
+            self.send(MessageType::Rematch(true));
+        }
+
+        if self.paused {
+            self.paused = false;
+            self.clock.resume();
+        }
+        self.render(stdout)
+    }
+
+    /// Handles what the other player sends, whether a round is being played or not.
+    /// The garbage and the end of a round only matter to the round being played.
+    fn handle_message(&mut self, message: MessageType) -> Result<()> {
+        match message {
+            MessageType::Board { score, rows } => {
+                if let Some(spectators) = &self.spectators {
+                    spectators.send(&MessageType::View {
+                        player: 1,
+                        name: self.multiplayer_score.competitor_name.clone(),
+                        score,
+                        wins: self.multiplayer_score.competitor_score as usize,
+                        rows: rows.clone(),
+                    });
+                }
+                if let Some(previous) = self.opponent_board.take() {
+                    self.render_opponent_cells(&rows, Some(&previous))?;
+                    self.opponent_board = Some(rows);
+                }
+            }
+            MessageType::Target(name) => {
+                // A battle server doesn't take players back
+                self.rejoin = None;
+                self.update_competitor_name(name)?;
+            }
+            MessageType::Ready => self.ready.theirs = Some(true),
+            MessageType::Rematch(vote) => self.rematch.theirs = Some(vote),
+            MessageType::Garbage(_) | MessageType::Notification(_) => {}
+            // The handshake is over once the game has started
+            MessageType::Hello { .. }
+            | MessageType::Welcome { .. }
+            | MessageType::Reject(_)
+            | MessageType::Lobby { .. } => {}
+            // Only spectators are sent what they see
+            MessageType::Watch { .. } | MessageType::View { .. } => {}
+            // The other player was heard from
+            MessageType::Heartbeat => {}
+        }
+
+        Ok(())
+    }
+
+    /// Reads what the other player sent since the last round ended.
+    /// Returns whether the screen was drawn again for a lost or resumed connection.
+    fn receive_between_rounds(&mut self, stdout: &mut io::Stdout) -> Result<bool> {
+        let (messages, closed) = self.receive();
+        for message in messages {
+            self.handle_message(message)?;
+        }
+
+        self.check_connection(closed, stdout)
+    }
+
+    /// Ends a round of a 2-player game. The next one starts once both players are
+    /// ready for it, or once both want a rematch when the series is over.
+    fn end_round(&mut self, title: &str, stdout: &mut io::Stdout) -> Result<()> {
+        self.paused = true;
+        self.clock.pause();
+        self.render_multiplayer_score()?;
+
+        if self.multiplayer_score.is_series_over() {
+            self.end_series(title, stdout)?;
+        } else {
+            self.wait_until_ready(title, stdout)?;
+        }
+
+        reset_game(self, stdout)
+    }
+
+    fn render_multiplayer_score(&self) -> Result<()> {
+        let stats_start_x = self.start_x - STATS_WIDTH - DISTANCE - 1;
+        self.terminal.write(
+            self.theme.foreground,
+            stats_start_x as u16 + 2 + "Score: ".len() as u16,
+            self.start_y as u16 + 10,
+            format!(
+                "{} - {}",
+                self.multiplayer_score.my_score, self.multiplayer_score.competitor_score
+            )
+            .as_str(),
+        )
+    }
+
+    /// Shows the score of the series until both players are ready for the next round.
+    /// Whoever goes on solo after losing the other player doesn't wait.
+    fn wait_until_ready(&mut self, title: &str, stdout: &mut io::Stdout) -> Result<()> {
+        self.paused = true;
+        self.clock.pause();
+        self.show_round_over(title, stdout)?;
+
+        while !self.ready.agreed() && !self.disconnected {
+            if self.receive_between_rounds(stdout)? {
+                self.show_round_over(title, stdout)?;
+            }
+
+            if !self.terminal.poll_event(Duration::from_millis(10))? {
+                continue;
+            }
+            if let Event::Key(KeyEvent {
+                code,
+                kind: KeyEventKind::Press,
+                ..
+            }) = self.terminal.read_event()?
+            {
+                match self.config.keys.action(Context::RoundOver, code) {
+                    Some(Action::Ready) if !self.ready.mine => {
+                        self.ready.mine = true;
+                        self.send(MessageType::Ready);
+                        self.show_round_over(title, stdout)?;
+                    }
+                    Some(Action::Quit) => self.quit()?,
+                    _ => {}
+                }
+            }
+        }
+
+        self.ready = Votes::default();
+        Ok(())
+    }
+
+    fn show_round_over(&self, title: &str, stdout: &mut io::Stdout) -> Result<()> {
+        let score = self.multiplayer_score.line();
+        let round = format!(
+            "Round {} of {}",
+            self.multiplayer_score.round(),
+            self.multiplayer_score.best_of
+        );
+        let prompt = self.config.keys.prompt(Context::RoundOver.actions());
+        let waiting = "Waiting for the next round.";
+        // The frame keeps its width once we are ready
+        let width = find_longest_message_length(&[title, &score, &round, &prompt, waiting]);
+
+        self.print_centered_messages(
+            stdout,
+            Some(width + MARGIN * 2),
+            vec![
+                title,
+                "",
+                &score,
+                &round,
+                "",
+                if self.ready.mine { waiting } else { &prompt },
+            ],
+        )
+    }
+
+    /// Records the series that just ended, then starts another one once both players
+    /// want a rematch. The other player declining it leaves nothing to do but quit.
+    fn end_series(&mut self, title: &str, stdout: &mut io::Stdout) -> Result<()> {
+        let score = &self.multiplayer_score;
+        self.highscore_repo.insert_series(
+            &score.my_name,
+            &score.competitor_name,
+            score.best_of,
+            score.my_score,
+            score.competitor_score,
+        )?;
+        self.show_series_over(title, stdout)?;
+
+        while !self.rematch.agreed() && !self.disconnected {
+            let declined = self.rematch.theirs == Some(false);
+            // They quit after declining, there is nothing more to hear from them
+            if !declined {
+                let redrawn = self.receive_between_rounds(stdout)?;
+                if redrawn || self.rematch.theirs == Some(false) {
+                    self.show_series_over(title, stdout)?;
+                }
+            }
+
+            if !self.terminal.poll_event(Duration::from_millis(10))? {
+                continue;
+            }
+            if let Event::Key(KeyEvent {
+                code,
+                kind: KeyEventKind::Press,
+                ..
+            }) = self.terminal.read_event()?
+            {
+                if declined {
+                    if self.config.keys.keys(Action::Quit).contains(&code) {
+                        self.quit()?;
+                    }
+                    continue;
+                }
+
+                match self.config.keys.action(Context::Rematch, code) {
+                    Some(Action::Yes) if !self.rematch.mine => {
+                        self.rematch.mine = true;
+                        self.send(MessageType::Rematch(true));
+                        self.show_series_over(title, stdout)?;
+                    }
+                    Some(Action::No) => {
+                        self.send(MessageType::Rematch(false));
+                        self.quit()?;
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        self.rematch = Votes::default();
+        self.multiplayer_score.my_score = 0;
+        self.multiplayer_score.competitor_score = 0;
+        Ok(())
+    }
+
+    fn show_series_over(&self, title: &str, stdout: &mut io::Stdout) -> Result<()> {
+        let score = &self.multiplayer_score;
+        let result = if score.my_score > score.competitor_score {
+            format!(
+                "You win the series {} - {}!",
+                score.my_score, score.competitor_score
+            )
+        } else {
+            format!(
+                "{} wins the series {} - {}.",
+                score.competitor_name, score.competitor_score, score.my_score
+            )
+        };
+        let vote = self.config.keys.prompt(Context::Rematch.actions());
+        let waiting = format!("Waiting for {}.", score.competitor_name);
+        let declined = format!("{} doesn't want a rematch.", score.competitor_name);
+        let quit = self.config.keys.prompt(&[Action::Quit]);
+        let (question, prompt) = if self.rematch.theirs == Some(false) {
+            (declined.as_str(), quit.as_str())
+        } else if self.rematch.mine {
+            (waiting.as_str(), "")
+        } else {
+            ("Rematch?", vote.as_str())
+        };
+        let width = find_longest_message_length(&[title, &result, &vote, &waiting, &declined]);
+
+        self.print_centered_messages(
+            stdout,
+            Some(width + MARGIN * 2),
+            vec![title, "", &result, "", question, prompt],
+        )
+    }
+
+    /// Counts down to the start of a round, which both players see at the same time.
+    fn count_down(&mut self, stdout: &mut io::Stdout) -> Result<()> {
+        self.clock.pause();
+        for second in (1..=COUNTDOWN_SECONDS).rev() {
+            self.print_centered_messages(stdout, None, vec![&second.to_string()])?;
+            thread::sleep(Duration::from_secs(1));
+        }
+
+        self.render(stdout)?;
+        self.paused = false;
+        self.clock.resume();
+        Ok(())
+    }
+
+    /// Lets the other player watch our board, whenever it changes.
+    fn send_board(&mut self) {
+        if self.stream.is_none() {
+            return;
+        }
+
+        let board = self.board_snapshot();
+        if board != self.sent_board {
+            let message = MessageType::Board {
+                score: self.score,
+                rows: board.clone(),
+            };
+            if let Some(stream) = &self.stream {
+                // Boards are sent all the time, a lost connection is reported by the other messages
+                let _ = stream.send(&message);
+            }
+            if let Some(spectators) = &self.spectators {
+                spectators.send(&MessageType::View {
+                    player: 0,
+                    name: self.multiplayer_score.my_name.clone(),
+                    score: self.score,
+                    wins: self.multiplayer_score.my_score as usize,
+                    rows: board.clone(),
+                });
+            }
+            self.sent_board = board;
+        }
+    }
+
+    pub fn render_changed_portions(&self) -> Result<()> {
+        self.render_play_grid()?;
+        self.render_stats()
+    }
+
+    /// The lines of the Stats panel, which depend on the game mode.
+    fn stats(&self) -> Vec<(&'static str, String)> {
+        match self.config.mode {
+            GameMode::Marathon | GameMode::Invisible => vec![
+                ("Score", self.score.to_string()),
+                ("Lines", self.lines.to_string()),
+                ("Level", self.level.to_string()),
+            ],
+            GameMode::Sprint => vec![
+                ("Time", mode::format_time(self.time_ms())),
+                (
+                    "Lines",
+                    format!("{}/{}", self.lines, self.config.sprint_lines),
+                ),
+                ("PPS", format!("{:.2}", self.pieces_per_second())),
+            ],
+            GameMode::Zen | GameMode::Sandbox => vec![
+                ("Score", self.score.to_string()),
+                ("Lines", self.lines.to_string()),
+                ("Pieces", self.pieces.to_string()),
+            ],
+            GameMode::Dig => vec![
+                ("Time", mode::format_time(self.time_ms())),
+                (
+                    "Dug",
+                    format!("{}/{}", self.garbage_cleared, self.config.dig_lines),
+                ),
+                ("PPS", format!("{:.2}", self.pieces_per_second())),
+            ],
+            GameMode::Ultra => vec![
+                (
+                    "Time",
+                    mode::format_time(self.time_limit_ms().saturating_sub(self.time_ms())),
+                ),
+                ("Score", self.score.to_string()),
+                ("Lines", self.lines.to_string()),
+            ],
+            GameMode::Survival => vec![
+                ("Time", mode::format_time(self.time_ms())),
+                ("Score", self.score.to_string()),
+                ("Level", self.level.to_string()),
+            ],
+            GameMode::Puzzle => match &self.puzzle {
+                Some(puzzle) => vec![
+                    ("Goal", puzzle.goal.short_name()),
+                    ("Pieces", format!("{}/{}", self.pieces, puzzle.pieces.len())),
+                    ("Lines", self.lines.to_string()),
+                ],
+                None => vec![],
+            },
+            GameMode::Master => vec![
+                (
+                    "Grade",
+                    master::grade_name(self.master.grade(self.score)).to_string(),
+                ),
+                (
+                    "Level",
+                    format!("{}/{}", self.level, master::section_end(self.level)),
+                ),
+                ("Time", mode::format_time(self.time_ms())),
+            ],
+        }
+    }
+
+    fn render_stats(&self) -> Result<()> {
+        let stats = self.stats();
+        let longest_label_length = stats
+            .iter()
+            .map(|(label, _)| label.len())
+            .max()
+            .unwrap_or(0);
+
+        // Values are padded so that a shorter one erases the previous one
+        let stats_start_x = self.start_x - STATS_WIDTH - DISTANCE - 1;
+        for (index, (_, value)) in stats.iter().enumerate() {
+            self.terminal.write(
+                self.theme.foreground,
+                (stats_start_x + 2 + longest_label_length + 2) as u16,
+                self.start_y as u16 + 2 + index as u16,
+                format!(
+                    "{:<width$}",
+                    value,
+                    width = STATS_WIDTH - longest_label_length - 4
+                )
+                .as_str(),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// The playing time, which can't go past the time limit of Ultra.
+    fn time_ms(&self) -> u64 {
+        let time_ms = self.clock.elapsed().as_millis() as u64;
+        match self.config.mode {
+            GameMode::Ultra => time_ms.min(self.time_limit_ms()),
+            _ => time_ms,
+        }
+    }
+
+    fn time_limit_ms(&self) -> u64 {
+        self.config.ultra_seconds * 1000
+    }
+
+    fn pieces_per_second(&self) -> f64 {
+        let seconds = self.clock.elapsed().as_secs_f64();
+        if seconds > 0.0 {
+            self.pieces as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+
+    pub fn render_play_grid(&self) -> Result<()> {
+        let time_ms = self.time_ms();
+        for (y, row) in self.play_grid.iter().enumerate() {
+            for (x, &ref cell) in row.iter().enumerate() {
+                let cell = if self.is_hidden(y, x, time_ms) {
+                    &EMPTY_CELL
+                } else {
+                    cell
+                };
+                let screen_x = self.start_x + 1 + x * CELL_WIDTH;
+                let screen_y = self.start_y + 1 + y;
+                self.terminal.write(
+                    self.theme.cell_color(cell.kind),
+                    screen_x as u16,
+                    screen_y as u16,
+                    self.config.glyphs.symbols(cell.kind),
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether a locked block is hidden at `time_ms` in Invisible mode.
+    fn is_hidden(&self, row: usize, col: usize, time_ms: u64) -> bool {
+        self.config.mode == GameMode::Invisible
+            && self.play_grid[row][col].kind != CellKind::Empty
+            && time_ms >= self.revealed_until_ms
+            && self
+                .lock_times
+                .has_faded(row, col, time_ms, self.config.invisible_fade_seconds)
+    }
+
+    fn hidden_count(&self) -> usize {
+        let time_ms = self.time_ms();
+        (0..PLAY_HEIGHT)
+            .flat_map(|row| (0..PLAY_WIDTH).map(move |col| (row, col)))
+            .filter(|&(row, col)| self.is_hidden(row, col, time_ms))
+            .count()
+    }
+
+    pub fn handle_event(&mut self, stdout: &mut std::io::Stdout) -> Result<()> {
+        let mut drop_timer = Instant::now();
+        let mut soft_drop_timer = Instant::now();
+        let mut auto_shift = AutoShift::new(self.config.das, self.config.arr);
+
+        let mut round_won = None;
+        let mut hidden_count = 0;
+        loop {
+            if self.paused {
+                self.handle_pause_event(stdout)?;
+            } else {
+                if matches!(
+                    self.config.mode,
+                    GameMode::Marathon | GameMode::Invisible | GameMode::Survival
+                ) && self.level <= MAX_LEVEL
+                    && self.lines >= LINES_PER_LEVEL * (self.level + 1)
+                {
+                    self.level += 1;
+                    self.drop_interval -= self.drop_interval / 10;
+                }
+
+                if self.config.mode == GameMode::Master {
+                    self.apply_master_gravity(stdout)?;
+                } else if self.has_gravity()
+                    && drop_timer.elapsed() >= Duration::from_millis(self.drop_interval)
+                {
+                    let mut tetromino = self.current_tetromino.clone();
+                    let can_move_down = self.can_move(
+                        &tetromino,
+                        tetromino.position.row as i16 + 1,
+                        tetromino.position.col as i16,
+                    );
+
+                    if can_move_down {
+                        tetromino.move_down(self, stdout)?;
+                        self.update_current_tetromino(tetromino);
+                    } else {
+                        self.lock_and_move_to_next(&tetromino, stdout)?;
+                    }
 
                     self.render_current_tetromino()?;
 
                     drop_timer = Instant::now();
                 }
 
+                // Blocks fade out and show up again after a line clear while nothing else is drawn
+                if self.config.mode == GameMode::Invisible && self.hidden_count() != hidden_count {
+                    hidden_count = self.hidden_count();
+                    self.render_play_grid()?;
+                    self.render_current_tetromino()?;
+                }
+
+                if self.config.mode.has_clock() {
+                    if self.config.mode == GameMode::Dig
+                        && self.config.dig_rise_seconds > 0
+                        && self.garbage_added < self.config.dig_lines
+                        && self.time_ms() >= self.next_rise_ms
+                    {
+                        let row = self.garbage.next_row();
+                        self.garbage_added += 1;
+                        self.add_garbage_rows(vec![row]);
+                        self.render_play_grid()?;
+                        self.render_current_tetromino()?;
+                        self.next_rise_ms += self.config.dig_rise_seconds * 1000;
+                    }
+
+                    if self.config.mode == GameMode::Survival && self.time_ms() >= self.next_rise_ms
+                    {
+                        let row = self.garbage.next_row();
+                        self.add_garbage_rows(vec![row]);
+                        self.render_play_grid()?;
+                        self.render_current_tetromino()?;
+                        self.next_rise_ms += garbage::survival_rise_ms(self.level);
+                    }
+
+                    self.render_stats()?;
+
+                    if self.is_finished() {
+                        self.handle_finish(stdout)?;
+                    }
+                }
+
                 if self.terminal.poll_event(Duration::from_millis(10))? {
                     if let Ok(event) = self.terminal.read_event() {
                         match event {
@@ -936,20 +2411,21 @@ impl Game {
                             }) => {
                                 if kind == KeyEventKind::Press {
                                     let mut tetromino = self.current_tetromino.clone();
-                                    match code {
-                                        KeyCode::Char('h') | KeyCode::Left => {
+                                    let context = game_context(self.config.mode);
+                                    match self.config.keys.action(context, code) {
+                                        Some(Action::MoveLeft) if auto_shift.press(-1) => {
                                             tetromino.move_left(self, stdout)?;
-                                            self.current_tetromino = tetromino;
+                                            self.update_current_tetromino(tetromino);
                                         }
-                                        KeyCode::Char('l') | KeyCode::Right => {
+                                        Some(Action::MoveRight) if auto_shift.press(1) => {
                                             tetromino.move_right(self, stdout)?;
-                                            self.current_tetromino = tetromino;
+                                            self.update_current_tetromino(tetromino);
                                         }
-                                        KeyCode::Char(' ') => {
+                                        Some(Action::Rotate) => {
                                             tetromino.rotate(self, stdout)?;
-                                            self.current_tetromino = tetromino;
+                                            self.update_current_tetromino(tetromino);
                                         }
-                                        KeyCode::Char('s') | KeyCode::Up => {
+                                        Some(Action::SoftDrop) => {
                                             if soft_drop_timer.elapsed()
                                                 >= (Duration::from_millis(self.drop_interval / 8))
                                             {
@@ -960,7 +2436,7 @@ impl Game {
                                                     tetromino.position.col as i16,
                                                 ) {
                                                     tetromino.move_down(self, stdout)?;
-                                                    self.current_tetromino = tetromino;
+                                                    self.update_current_tetromino(tetromino);
                                                 } else {
                                                     self.lock_and_move_to_next(&tetromino, stdout)?;
                                                 }
@@ -968,14 +2444,24 @@ impl Game {
                                                 soft_drop_timer = Instant::now();
                                             }
                                         }
-                                        KeyCode::Char('j') | KeyCode::Down => {
+                                        Some(Action::HardDrop) => {
                                             tetromino.hard_drop(self, stdout)?;
+                                            self.update_current_tetromino(tetromino.clone());
                                             self.lock_and_move_to_next(&tetromino, stdout)?;
                                         }
-                                        KeyCode::Char('p') => {
+                                        Some(Action::Undo) => {
+                                            self.undo()?;
+                                        }
+                                        Some(Action::NextPiece) => {
+                                            self.choose_next_piece()?;
+                                        }
+                                        Some(Action::EditBoard) => {
+                                            self.edit_board(stdout)?;
+                                        }
+                                        Some(Action::Pause) => {
                                             self.paused = !self.paused;
                                         }
-                                        KeyCode::Char('q') => {
+                                        Some(Action::Quit) => {
                                             self.handle_quit_event(stdout)?;
                                         }
                                         _ => {}
@@ -988,105 +2474,38 @@ impl Game {
                     }
                 }
 
-                if let Some(receiver) = &self.receiver {
-                    for message in receiver.try_iter() {
+                if self.receiver.is_some() {
+                    let (messages, closed) = self.receive();
+                    for message in messages {
                         match message {
-                            MessageType::ClearedRows(rows) => {
-                                let cells =
-                                    vec![I_CELL, O_CELL, T_CELL, S_CELL, Z_CELL, T_CELL, L_CELL];
-                                let mut rng = rand::thread_rng();
-                                let random_cell_index = rng.gen_range(0..cells.len());
-                                let random_cell = cells[random_cell_index].clone();
-
-                                let mut new_row = vec![random_cell; PLAY_WIDTH];
-                                let random_column = rng.gen_range(0..PLAY_WIDTH);
-                                new_row[random_column] = EMPTY_CELL;
-
-                                for _ in 0..rows {
-                                    self.play_grid.remove(0);
-                                    self.play_grid.insert(PLAY_HEIGHT - 1, new_row.clone());
-                                }
-
-                                self.render_play_grid()?;
-                            }
-                            MessageType::Notification(msg) => {
-                                self.paused = !self.paused;
-
-                                self.print_centered_messages(
-                                    stdout,
-                                    None,
-                                    vec![&msg, "", "(R)estart | (C)ontinue | (Q)uit"],
-                                )?;
-
-                                self.multiplayer_score.my_score += 1;
-
-                                let stats_start_x = self.start_x - STATS_WIDTH - DISTANCE - 1;
-                                if let Some(_) = &self.stream {
-                                    execute!(
-                                        stdout,
-                                        SetForegroundColor(Color::White),
-                                        SetBackgroundColor(Color::Black),
-                                        SavePosition,
-                                        MoveTo(
-                                            stats_start_x as u16 + 2 + "Score: ".len() as u16,
-                                            self.start_y as u16 + 10
-                                        ),
-                                        Print(format!(
-                                            "{} - {}",
-                                            self.multiplayer_score.my_score,
-                                            self.multiplayer_score.competitor_score
-                                        )),
-                                        ResetColor,
-                                        RestorePosition,
-                                    )?;
-                                }
-
-                                loop {
-                                    if poll(Duration::from_millis(10))? {
-                                        let event = read()?;
-                                        match event {
-                                            Event::Key(KeyEvent {
-                                                code,
-                                                modifiers: _,
-                                                kind,
-                                                state: _,
-                                            }) => {
-                                                if kind == KeyEventKind::Press {
-                                                    match code {
-                                                        KeyCode::Enter | KeyCode::Char('c') => {
-                                                            self.render_changed_portions()?;
-                                                            self.paused = false;
-                                                            break;
-                                                        }
-                                                        KeyCode::Char('r') => {
-                                                            reset_needed = true;
-                                                            break;
-                                                        }
-                                                        KeyCode::Char('q') => {
-                                                            self.quit()?;
-                                                        }
-                                                        _ => {}
-                                                    }
-                                                }
-                                            }
-                                            _ => {}
-                                        }
-                                    }
-                                }
+                            MessageType::Garbage(lines) => {
+                                // It enters the board when a piece locks without clearing rows
+                                self.incoming.receive(lines, self.time_ms());
                             }
+                            // The other player topped out
+                            MessageType::Notification(title) => round_won = Some(title),
+                            other => self.handle_message(other)?,
                         }
                     }
+
+                    self.check_connection(closed, stdout)?;
                 }
 
-                if reset_needed {
-                    reset_game(self, stdout)?;
+                self.update_garbage_meter()?;
+                self.send_board();
+
+                if let Some(title) = round_won.take() {
+                    self.multiplayer_score.my_score += 1;
+                    self.end_round(&title, stdout)?;
                 }
             }
         }
     }
 
     fn handle_pause_event(&mut self, stdout: &mut io::Stdout) -> Result<()> {
-        self.print_centered_messages(stdout, None, vec!["PAUSED", "", "(C)ontinue | (Q)uit"])?;
+        self.clock.pause();
+        let prompt = self.config.keys.prompt(Context::Paused.actions());
+        self.print_centered_messages(stdout, None, vec!["PAUSED", "", &prompt])?;
 
         loop {
             if self.terminal.poll_event(Duration::from_millis(10))? {
@@ -1099,13 +2518,14 @@ impl Game {
                         state: _,
                     }) => {
                         if kind == KeyEventKind::Press {
-                            match code {
-                                KeyCode::Enter | KeyCode::Char('c') => {
+                            match self.config.keys.action(Context::Paused, code) {
+                                Some(Action::Continue) => {
                                     self.render_changed_portions()?;
                                     self.paused = false;
+                                    self.clock.resume();
                                     break;
                                 }
-                                KeyCode::Char('q') => {
+                                Some(Action::Quit) => {
                                     self.quit()?;
                                 }
                                 _ => {}
@@ -1121,7 +2541,9 @@ impl Game {
     }
 
     fn handle_quit_event(&mut self, stdout: &mut io::Stdout) -> Result<()> {
-        self.print_centered_messages(stdout, None, vec!["QUIT?", "", "(Y)es | (N)o"])?;
+        self.clock.pause();
+        let prompt = self.config.keys.prompt(Context::QuitDialog.actions());
+        self.print_centered_messages(stdout, None, vec!["QUIT?", "", &prompt])?;
 
         loop {
             if self.terminal.poll_event(Duration::from_millis(10))? {
@@ -1134,13 +2556,14 @@ impl Game {
                         state: _,
                     }) => {
                         if kind == KeyEventKind::Press {
-                            match code {
-                                KeyCode::Enter | KeyCode::Char('y') => {
+                            match self.config.keys.action(Context::QuitDialog, code) {
+                                Some(Action::Yes) => {
                                     self.quit()?;
                                 }
-                                KeyCode::Esc | KeyCode::Char('n') => {
+                                Some(Action::No) => {
                                     self.render_changed_portions()?;
                                     self.paused = false;
+                                    self.clock.resume();
                                     break;
                                 }
                                 _ => {}
@@ -1155,7 +2578,7 @@ impl Game {
         Ok(())
     }
 
-    pub fn can_move(&mut self, tetromino: &Tetromino, new_row: i16, new_col: i16) -> bool {
+    pub fn can_move(&self, tetromino: &Tetromino, new_row: i16, new_col: i16) -> bool {
         for (t_row, row) in tetromino.get_cells().iter().enumerate() {
             for (t_col, &ref cell) in row.iter().enumerate() {
                 if cell.symbols == SQUARE_BRACKETS {
@@ -1179,24 +2602,31 @@ impl Game {
 
     pub fn clear_tetromino(&mut self, stdout: &mut std::io::Stdout) -> Result<()> {
         let tetromino = &self.current_tetromino;
+        let mut rows = vec![tetromino.position.row];
+        if self.config.ghost {
+            rows.push(self.ghost_row());
+        }
+
         for (row_index, row) in tetromino.states[tetromino.current_state].iter().enumerate() {
             for (col_index, &ref cell) in row.iter().enumerate() {
-                let grid_x = tetromino.position.col + col_index as isize;
-                let grid_y = tetromino.position.row + row_index as isize;
-
-                if cell.symbols != SPACE {
-                    execute!(
-                        stdout,
-                        SetBackgroundColor(Color::Black),
-                        SavePosition,
-                        MoveTo(
-                            self.start_x as u16 + 1 + grid_x as u16 * CELL_WIDTH as u16,
-                            self.start_y as u16 + 1 + grid_y as u16,
-                        ),
-                        Print(SPACE),
-                        ResetColor,
-                        RestorePosition
-                    )?;
+                for &start_row in rows.iter() {
+                    let grid_x = tetromino.position.col + col_index as isize;
+                    let grid_y = start_row + row_index as isize;
+
+                    if cell.symbols != SPACE {
+                        execute!(
+                            stdout,
+                            SetBackgroundColor(self.theme.background),
+                            SavePosition,
+                            MoveTo(
+                                self.start_x as u16 + 1 + grid_x as u16 * CELL_WIDTH as u16,
+                                self.start_y as u16 + 1 + grid_y as u16,
+                            ),
+                            Print(SPACE),
+                            ResetColor,
+                            RestorePosition
+                        )?;
+                    }
                 }
             }
         }
@@ -1204,12 +2634,32 @@ impl Game {
         Ok(())
     }
 
+    // The row where the current tetromino would land after a hard drop
+    fn ghost_row(&self) -> isize {
+        let tetromino = &self.current_tetromino;
+        let mut row = tetromino.position.row;
+        while self.can_move(tetromino, row as i16 + 1, tetromino.position.col as i16) {
+            row += 1;
+        }
+
+        row
+    }
+
     fn lock_and_move_to_next(
         &mut self,
         tetromino: &Tetromino,
         stdout: &mut io::Stdout,
     ) -> Result<()> {
+        if self.config.mode == GameMode::Sandbox {
+            self.history.push(self.snapshot());
+        }
+
         self.lock_tetromino(tetromino)?;
+        self.pieces += 1;
+        if self.config.mode == GameMode::Master {
+            self.reach_master_level(master::level_after_piece(self.level));
+            self.master.next_piece();
+        }
 
         // When performing integration testing, Game instance is started in a spawned thread
         // This sends the play grid state to the main thread, so it can be asserted.
@@ -1217,16 +2667,60 @@ impl Game {
             state_sender.send(self.play_grid.clone())?;
         }
 
+        if self.is_finished() {
+            return self.handle_finish(stdout);
+        }
+
         self.move_to_next()?;
 
         if self.is_game_over() {
-            self.handle_game_over(stdout)?;
+            self.handle_top_out(stdout)?;
         }
 
         Ok(())
     }
 
+    /// Replaces the current piece with its moved or rotated copy.
+    fn update_current_tetromino(&mut self, tetromino: Tetromino) {
+        if tetromino.position != self.current_tetromino.position {
+            self.rotated_last = false;
+        } else if tetromino.current_state != self.current_tetromino.current_state {
+            self.rotated_last = true;
+        }
+        self.current_tetromino = tetromino;
+    }
+
+    /// A T piece locked right after a rotation with 3 of the 4 corners around
+    /// its center taken, the walls and the floor count as taken.
+    fn is_t_spin(&self, tetromino: &Tetromino) -> bool {
+        let is_t = tetromino
+            .get_cells()
+            .iter()
+            .flatten()
+            .any(|cell| cell.kind == CellKind::T);
+        if !is_t || !self.rotated_last {
+            return false;
+        }
+
+        let corners = [(0, 0), (0, 2), (2, 0), (2, 2)];
+        let taken = corners
+            .iter()
+            .filter(|(row, col)| {
+                let row = tetromino.position.row + row;
+                let col = tetromino.position.col + col;
+                row >= PLAY_HEIGHT as isize
+                    || col < 0
+                    || col >= PLAY_WIDTH as isize
+                    || (row >= 0
+                        && self.play_grid[row as usize][col as usize].symbols == SQUARE_BRACKETS)
+            })
+            .count();
+
+        taken >= 3
+    }
+
     fn lock_tetromino(&mut self, tetromino: &Tetromino) -> Result<()> {
+        let t_spin = self.is_t_spin(tetromino);
         for (ty, row) in tetromino.get_cells().iter().enumerate() {
             for (tx, &ref cell) in row.iter().enumerate() {
                 if cell.symbols == SQUARE_BRACKETS {
@@ -1234,29 +2728,44 @@ impl Game {
                     let grid_y = (tetromino.position.row as usize).wrapping_add(ty);
 
                     self.play_grid[grid_y][grid_x] = cell.clone();
+                    self.lock_times.lock(grid_y, grid_x, self.time_ms());
                 }
             }
         }
 
-        self.clear_filled_rows()?;
+        let cleared = self.clear_filled_rows(t_spin)?;
+
+        if let Some(puzzle) = &self.puzzle {
+            let board_empty = self
+                .play_grid
+                .iter()
+                .all(|row| row.iter().all(|cell| cell.symbols != SQUARE_BRACKETS));
+            if puzzle.goal.is_met(self.lines, cleared, t_spin, board_empty) {
+                self.puzzle_solved = true;
+            }
+        }
 
         Ok(())
     }
 
     fn move_to_next(&mut self) -> Result<()> {
-        self.current_tetromino = self.next_tetromino.clone();
+        if let Some(next_tetromino) = self.next_tetrominoes.pop_front() {
+            self.current_tetromino = next_tetromino;
+        }
         self.current_tetromino.position.row = 0;
         self.current_tetromino.position.col =
             (PLAY_WIDTH - tetromino_width(&self.current_tetromino.states[0])) as isize / 2;
         self.render_current_tetromino()?;
 
-        self.next_tetromino = self.tetromino_spawner.spawn(true);
+        self.next_tetrominoes
+            .push_back(self.tetromino_spawner.spawn(true));
         self.render_next_tetromino()?;
 
         Ok(())
     }
 
-    fn clear_filled_rows(&mut self) -> Result<()> {
+    /// Removes the full rows and returns how many there were.
+    fn clear_filled_rows(&mut self, t_spin: bool) -> Result<usize> {
         let mut filled_rows: Vec<usize> = Vec::new();
 
         for row_index in (0..PLAY_HEIGHT).rev() {
@@ -1268,47 +2777,111 @@ impl Game {
             }
         }
 
+        let garbage_rows = filled_rows
+            .iter()
+            .filter(|&&row_index| {
+                self.play_grid[row_index]
+                    .iter()
+                    .any(|cell| cell.kind == CellKind::Garbage)
+            })
+            .count();
+        self.garbage_cleared += garbage_rows;
+
         let new_row = vec![EMPTY_CELL; PLAY_WIDTH];
         for &row_index in filled_rows.iter().rev() {
             self.play_grid.remove(row_index);
             self.play_grid.insert(0, new_row.clone());
+            self.lock_times.remove_row(row_index);
 
             self.lines += 1;
         }
 
         let num_filled_rows = filled_rows.len();
-        match num_filled_rows {
-            1 => {
-                self.score += 100 * (self.level + 1);
-            }
-            2 => {
-                self.score += 300 * (self.level + 1);
+        if self.config.mode == GameMode::Sprint {
+            let time_ms = self.time_ms();
+            mode::add_splits(&mut self.splits, self.lines, time_ms);
+        }
+        if self.config.mode == GameMode::Invisible && num_filled_rows > 0 {
+            self.revealed_until_ms = self.time_ms() + invisible::REVEAL_MS;
+        }
+
+        if self.config.mode == GameMode::Master {
+            let perfect_clear = self
+                .play_grid
+                .iter()
+                .all(|row| row.iter().all(|cell| cell.symbols != SQUARE_BRACKETS));
+            self.score += self
+                .master
+                .score(self.level, num_filled_rows, perfect_clear);
+            self.reach_master_level(master::level_after_lines(self.level, num_filled_rows));
+        } else {
+            match num_filled_rows {
+                1 => {
+                    self.score += 100 * (self.level + 1);
+                }
+                2 => {
+                    self.score += 300 * (self.level + 1);
+                }
+                3 => {
+                    self.score += 500 * (self.level + 1);
+                }
+                4 => {
+                    self.score += 800 * (self.level + 1);
+                }
+                _ => (),
             }
-            3 => {
-                self.score += 500 * (self.level + 1);
+        }
+
+        if self.stream.is_some() {
+            // Our clears cancel the garbage waiting to enter our board before reaching the other player
+            let lines = self.attack.lines_sent(num_filled_rows, t_spin);
+            let lines = self.incoming.offset(lines);
+            if lines > 0 {
+                self.send(MessageType::Garbage(lines));
             }
-            4 => {
-                self.score += 800 * (self.level + 1);
+
+            if num_filled_rows == 0 {
+                for lines in self.incoming.take_ready(self.time_ms()) {
+                    let rows = self.garbage.next_attack(lines);
+                    self.add_garbage_rows(rows);
+                }
             }
-            _ => (),
         }
 
-        if let Some(stream) = &mut self.stream {
-            if num_filled_rows > 0 {
-                multiplayer::send_to_other_player(
-                    stream,
-                    MessageType::ClearedRows(num_filled_rows),
-                );
-            }
+        if self.config.mode == GameMode::Dig && garbage_rows > 0 {
+            self.refill_garbage();
         }
 
         self.render_changed_portions()?;
 
-        Ok(())
+        Ok(num_filled_rows)
     }
 
     fn render_current_tetromino(&self) -> Result<()> {
         let current_tetromino = &self.current_tetromino;
+
+        if self.config.ghost {
+            let ghost_row = self.ghost_row();
+            for (row_index, row) in current_tetromino.get_cells().iter().enumerate() {
+                for (col_index, cell) in row.iter().enumerate() {
+                    let grid_x = current_tetromino.position.col + col_index as isize;
+                    let grid_y = ghost_row + row_index as isize;
+
+                    if cell.symbols != SPACE
+                        && grid_x < PLAY_WIDTH as isize
+                        && grid_y < PLAY_HEIGHT as isize
+                    {
+                        self.terminal.write(
+                            self.theme.ghost,
+                            self.start_x as u16 + 1 + grid_x as u16 * CELL_WIDTH as u16,
+                            self.start_y as u16 + 1 + grid_y as u16,
+                            self.config.glyphs.symbols(cell.kind),
+                        )?;
+                    }
+                }
+            }
+        }
+
         for (row_index, row) in current_tetromino.states[current_tetromino.current_state]
             .iter()
             .enumerate()
@@ -1320,164 +2893,617 @@ impl Game {
                 if cell.symbols != SPACE {
                     if grid_x < PLAY_WIDTH as isize && grid_y < PLAY_HEIGHT as isize {
                         self.terminal.write(
-                            cell.color,
+                            self.theme.cell_color(cell.kind),
                             self.start_x as u16 + 1 + grid_x as u16 * CELL_WIDTH as u16,
                             self.start_y as u16 + 1 + grid_y as u16,
-                            cell.symbols,
+                            self.config.glyphs.symbols(cell.kind),
                         )?;
                     }
                 }
             }
         }
 
-        Ok(())
+        Ok(())
+    }
+
+    fn render_next_tetromino(&self) -> Result<()> {
+        let next_start_x = self.start_x + PLAY_WIDTH * CELL_WIDTH + 1 + DISTANCE;
+        let next_height = next_height(self.config.preview_count);
+        for i in 0..next_height {
+            self.terminal.write(
+                self.theme.foreground,
+                next_start_x as u16 + 1,
+                self.start_y as u16 + 1 + i as u16,
+                " ".repeat(NEXT_WIDTH * CELL_WIDTH).as_str(),
+            )?;
+        }
+
+        let next_start_x = self.start_x + PLAY_WIDTH * CELL_WIDTH + 1 + DISTANCE;
+        for (index, next_tetromino) in self.next_tetrominoes.iter().enumerate() {
+            for (row_index, row) in next_tetromino.states[next_tetromino.current_state]
+                .iter()
+                .enumerate()
+            {
+                for (col_index, cell) in row.iter().enumerate() {
+                    let grid_x = next_tetromino.position.col as usize + col_index;
+                    let grid_y =
+                        next_tetromino.position.row as usize + row_index + index * PREVIEW_SPACING;
+
+                    if cell.symbols != SPACE && grid_x < NEXT_WIDTH && grid_y < next_height {
+                        self.terminal.write(
+                            self.theme.cell_color(cell.kind),
+                            next_start_x as u16
+                                + 1
+                                + grid_x as u16 * CELL_WIDTH as u16
+                                + tetromino_width(
+                                    &next_tetromino.states[next_tetromino.current_state],
+                                ) as u16
+                                    % 2,
+                            self.start_y as u16 + grid_y as u16,
+                            self.config.glyphs.symbols(cell.kind),
+                        )?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn is_game_over(&mut self) -> bool {
+        let tetromino = self.current_tetromino.clone();
+
+        let next_state = (tetromino.current_state + 1) % (tetromino.states.len());
+        let mut temp_tetromino = tetromino.clone();
+        temp_tetromino.current_state = next_state;
+
+        if !self.can_move(
+            &tetromino,
+            tetromino.position.row as i16,
+            tetromino.position.col as i16 - 1,
+        ) && !self.can_move(
+            &tetromino,
+            tetromino.position.row as i16,
+            tetromino.position.col as i16 + 1,
+        ) && !self.can_move(
+            &tetromino,
+            tetromino.position.row as i16 + 1,
+            tetromino.position.col as i16,
+        ) && !self.can_move(
+            &temp_tetromino,
+            tetromino.position.row as i16,
+            tetromino.position.col as i16,
+        ) {
+            return true;
+        }
+
+        false
+    }
+
+    fn has_gravity(&self) -> bool {
+        match self.config.mode {
+            GameMode::Zen => self.config.zen_gravity,
+            GameMode::Puzzle | GameMode::Sandbox => false,
+            _ => true,
+        }
+    }
+
+    /// Master gravity can move the piece many rows in a single tick, up to
+    /// 20 rows per frame. A landed piece locks once its lock delay has passed.
+    fn apply_master_gravity(&mut self, stdout: &mut io::Stdout) -> Result<()> {
+        let now = self.clock.elapsed();
+        let rows = self.master.rows_to_fall(self.level, now);
+
+        let mut tetromino = self.current_tetromino.clone();
+        let col = tetromino.position.col as i16;
+        let mut fallen = 0;
+        while fallen < rows && self.can_move(&tetromino, tetromino.position.row as i16 + 1, col) {
+            tetromino.position.row += 1;
+            fallen += 1;
+        }
+
+        if fallen > 0 {
+            self.clear_tetromino(stdout)?;
+            self.update_current_tetromino(tetromino.clone());
+            self.master.lift();
+            self.render_current_tetromino()?;
+        }
+
+        if !self.can_move(&tetromino, tetromino.position.row as i16 + 1, col)
+            && self.master.must_lock(self.level, now)
+        {
+            self.lock_and_move_to_next(&tetromino, stdout)?;
+            self.render_current_tetromino()?;
+        }
+
+        Ok(())
+    }
+
+    fn reach_master_level(&mut self, level: usize) {
+        self.master.reach(level, self.time_ms(), self.score);
+        self.level = level;
+    }
+
+    /// Ends the game, except in Zen mode which makes room and goes on.
+    fn handle_top_out(&mut self, stdout: &mut io::Stdout) -> Result<()> {
+        // The move that topped out is taken back
+        if self.config.mode == GameMode::Sandbox {
+            return self.undo();
+        }
+
+        if self.config.mode != GameMode::Zen {
+            return self.handle_game_over(stdout);
+        }
+
+        let rows = match self.config.zen_top_out {
+            TopOut::Clear => PLAY_HEIGHT,
+            TopOut::Trim => PLAY_HEIGHT / 2,
+        };
+        for row in self.play_grid.iter_mut().take(rows) {
+            *row = vec![EMPTY_CELL; PLAY_WIDTH];
+        }
+
+        self.render_play_grid()?;
+        self.render_current_tetromino()
+    }
+
+    fn handle_game_over(&mut self, stdout: &mut io::Stdout) -> Result<()> {
+        // Going on solo after losing the other player doesn't end a round
+        if self.stream.is_some() && !self.disconnected {
+            self.send(MessageType::Notification("YOU WIN!".to_string()));
+            self.multiplayer_score.competitor_score += 1;
+            return self.end_round("GAME OVER", stdout);
+        }
+
+        if self.config.mode == GameMode::Invisible {
+            self.reveal_stack(stdout)?;
+        }
+
+        if matches!(
+            self.config.mode,
+            GameMode::Ultra
+                | GameMode::Master
+                | GameMode::Invisible
+                | GameMode::Puzzle
+                | GameMode::Survival
+        ) {
+            // Topping out ends these modes early, the score made so far still counts
+            self.handle_finish(stdout)?;
+        } else if self.config.mode != GameMode::Marathon {
+            // Topping out doesn't finish the other modes, so there is nothing to record
+            self.show_high_scores(stdout)?;
+        } else if self.score == 0 {
+            self.show_high_scores(stdout)?;
+        } else {
+            let count: i64 = self.highscore_repo.count()?;
+
+            if count < 5 {
+                self.new_high_score(stdout)?;
+            } else {
+                let player: Player = self.highscore_repo.get_player_at_rank(5)?;
+
+                if (self.score as u64) <= player.score {
+                    self.show_high_scores(stdout)?;
+                } else {
+                    self.new_high_score(stdout)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Shows the whole stack of an Invisible game until a key is pressed,
+    /// before the results hide most of it.
+    fn reveal_stack(&mut self, stdout: &mut io::Stdout) -> Result<()> {
+        self.clock.pause();
+        self.revealed_until_ms = u64::MAX;
+        self.render_play_grid()?;
+
+        let mut title = format!(
+            "GAME OVER - {}",
+            self.config.keys.prompt(&[Action::Continue])
+        );
+        if title.len() > PLAY_WIDTH * CELL_WIDTH - 2 {
+            title = "GAME OVER".to_string();
+        }
+        self.render_frame(
+            stdout,
+            &title,
+            self.start_x,
+            self.start_y,
+            PLAY_WIDTH * 3,
+            PLAY_HEIGHT + 1,
+        )?;
+
+        loop {
+            if self.terminal.poll_event(Duration::from_millis(10))? {
+                if let Event::Key(KeyEvent {
+                    code,
+                    kind: KeyEventKind::Press,
+                    ..
+                }) = self.terminal.read_event()?
+                {
+                    if self.config.keys.keys(Action::Continue).contains(&code) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            play_grid: self.play_grid.clone(),
+            current: self.current_tetromino.kind(),
+            next: self
+                .next_tetrominoes
+                .iter()
+                .map(|next| next.kind())
+                .collect(),
+            score: self.score,
+            lines: self.lines,
+            pieces: self.pieces,
+        }
+    }
+
+    /// Goes back to before the last move or board edit of Sandbox mode.
+    /// The piece comes back to the top of the board.
+    fn undo(&mut self) -> Result<()> {
+        if let Some(snapshot) = self.history.pop() {
+            self.play_grid = snapshot.play_grid;
+            self.current_tetromino = Tetromino::new(snapshot.current, false);
+            self.next_tetrominoes = snapshot
+                .next
+                .into_iter()
+                .map(|kind| Tetromino::new(kind, true))
+                .collect();
+            self.score = snapshot.score;
+            self.lines = snapshot.lines;
+            self.pieces = snapshot.pieces;
+
+            self.render_play_grid()?;
+            self.render_current_tetromino()?;
+            self.render_next_tetromino()?;
+            self.render_stats()?;
+        }
+
+        Ok(())
+    }
+
+    /// Changes the first next piece of Sandbox mode to the following kind of piece.
+    fn choose_next_piece(&mut self) -> Result<()> {
+        if let Some(next) = self.next_tetrominoes.front_mut() {
+            *next = Tetromino::new(sandbox::next_kind(next.kind()), true);
+        }
+
+        self.render_next_tetromino()
     }
 
-    fn render_next_tetromino(&self) -> Result<()> {
-        let next_start_x = self.start_x + PLAY_WIDTH * CELL_WIDTH + 1 + DISTANCE;
-        for i in 0..NEXT_HEIGHT {
-            self.terminal.write(
-                Color::White,
-                next_start_x as u16 + 1,
-                self.start_y as u16 + 1 + i as u16,
-                " ".repeat(NEXT_WIDTH * CELL_WIDTH).as_str(),
-            )?;
+    /// Lets the player paint blocks on the board in Sandbox mode, with the move and
+    /// drop keys and the Paint key, or with the mouse (the right button erases).
+    fn edit_board(&mut self, stdout: &mut io::Stdout) -> Result<()> {
+        self.clock.pause();
+        let snapshot = self.snapshot();
+        execute!(stdout, EnableMouseCapture)?;
+
+        let mut title = self.config.keys.prompt(&[Action::Paint, Action::EditBoard]);
+        if title.len() > PLAY_WIDTH * CELL_WIDTH - 2 {
+            title = "EDITOR".to_string();
         }
+        self.render_frame(
+            stdout,
+            &title,
+            self.start_x,
+            self.start_y,
+            PLAY_WIDTH * 3,
+            PLAY_HEIGHT + 1,
+        )?;
 
-        let next_start_x = self.start_x + PLAY_WIDTH * CELL_WIDTH + 1 + DISTANCE;
-        let next_tetromino = &self.next_tetromino;
-        for (row_index, row) in next_tetromino.states[next_tetromino.current_state]
-            .iter()
-            .enumerate()
-        {
-            for (col_index, &ref cell) in row.iter().enumerate() {
-                let grid_x = next_tetromino.position.col as usize + col_index;
-                let grid_y = next_tetromino.position.row as usize + row_index;
+        let mut cursor = Position {
+            row: PLAY_HEIGHT as isize - 1,
+            col: 0,
+        };
+        self.render_play_grid()?;
+        self.render_editor_cursor(&cursor)?;
 
-                if cell.symbols != SPACE {
-                    if grid_x < NEXT_WIDTH && grid_y < NEXT_HEIGHT {
-                        self.terminal.write(
-                            cell.color,
-                            next_start_x as u16
-                                + 1
-                                + grid_x as u16 * CELL_WIDTH as u16
-                                + tetromino_width(
-                                    &next_tetromino.states[next_tetromino.current_state],
-                                ) as u16
-                                    % 2,
-                            self.start_y as u16 + grid_y as u16,
-                            cell.symbols,
-                        )?;
+        loop {
+            if !self.terminal.poll_event(Duration::from_millis(10))? {
+                continue;
+            }
+
+            match self.terminal.read_event()? {
+                Event::Key(KeyEvent {
+                    code,
+                    kind: KeyEventKind::Press,
+                    ..
+                }) => match self.config.keys.action(Context::Editor, code) {
+                    Some(Action::MoveLeft) => cursor.col = (cursor.col - 1).max(0),
+                    Some(Action::MoveRight) => {
+                        cursor.col = (cursor.col + 1).min(PLAY_WIDTH as isize - 1)
+                    }
+                    Some(Action::SoftDrop) => cursor.row = (cursor.row - 1).max(0),
+                    Some(Action::HardDrop) => {
+                        cursor.row = (cursor.row + 1).min(PLAY_HEIGHT as isize - 1)
+                    }
+                    Some(Action::Paint) => {
+                        let cell = &mut self.play_grid[cursor.row as usize][cursor.col as usize];
+                        *cell = if cell.kind == CellKind::Empty {
+                            GARBAGE_CELL
+                        } else {
+                            EMPTY_CELL
+                        };
+                    }
+                    Some(Action::EditBoard) => break,
+                    _ => {}
+                },
+                Event::Mouse(MouseEvent {
+                    kind, column, row, ..
+                }) => {
+                    let cell = match kind {
+                        MouseEventKind::Down(MouseButton::Left)
+                        | MouseEventKind::Drag(MouseButton::Left) => Some(GARBAGE_CELL),
+                        MouseEventKind::Down(MouseButton::Right)
+                        | MouseEventKind::Drag(MouseButton::Right) => Some(EMPTY_CELL),
+                        _ => None,
+                    };
+                    if let (Some(cell), Some(position)) = (cell, self.grid_position(column, row)) {
+                        self.play_grid[position.row as usize][position.col as usize] = cell;
+                        cursor = position;
                     }
                 }
+                _ => {}
             }
+
+            self.render_play_grid()?;
+            self.render_editor_cursor(&cursor)?;
+        }
+
+        execute!(stdout, DisableMouseCapture)?;
+        if self.play_grid != snapshot.play_grid {
+            self.history.push(snapshot);
         }
+        self.lift_current_tetromino();
+
+        self.render_frame(
+            stdout,
+            "Tetris",
+            self.start_x,
+            self.start_y,
+            PLAY_WIDTH * 3,
+            PLAY_HEIGHT + 1,
+        )?;
+        self.render_play_grid()?;
+        self.render_current_tetromino()?;
+        self.clock.resume();
 
         Ok(())
     }
 
-    fn is_game_over(&mut self) -> bool {
-        let tetromino = self.current_tetromino.clone();
+    fn render_editor_cursor(&self, cursor: &Position) -> Result<()> {
+        self.terminal.write(
+            self.theme.foreground,
+            (self.start_x + 1 + cursor.col as usize * CELL_WIDTH) as u16,
+            (self.start_y + 1 + cursor.row as usize) as u16,
+            sandbox::CURSOR,
+        )
+    }
 
-        let next_state = (tetromino.current_state + 1) % (tetromino.states.len());
-        let mut temp_tetromino = tetromino.clone();
-        temp_tetromino.current_state = next_state;
+    /// The cell of the board at a position of the terminal, if any.
+    fn grid_position(&self, column: u16, row: u16) -> Option<Position> {
+        let col = (column as usize).checked_sub(self.start_x + 1)? / CELL_WIDTH;
+        let row = (row as usize).checked_sub(self.start_y + 1)?;
 
-        if !self.can_move(
-            &tetromino,
-            tetromino.position.row as i16,
-            tetromino.position.col as i16 - 1,
-        ) && !self.can_move(
-            &tetromino,
-            tetromino.position.row as i16,
-            tetromino.position.col as i16 + 1,
-        ) && !self.can_move(
-            &tetromino,
-            tetromino.position.row as i16 + 1,
-            tetromino.position.col as i16,
-        ) && !self.can_move(
-            &temp_tetromino,
-            tetromino.position.row as i16,
-            tetromino.position.col as i16,
-        ) {
-            return true;
-        }
+        (col < PLAY_WIDTH && row < PLAY_HEIGHT).then_some(Position {
+            row: row as isize,
+            col: col as isize,
+        })
+    }
 
-        false
+    /// Whether the goal of the current mode has been reached.
+    fn is_finished(&self) -> bool {
+        match self.config.mode {
+            GameMode::Marathon => false,
+            GameMode::Sprint => self.lines >= self.config.sprint_lines,
+            GameMode::Ultra => self.time_ms() >= self.time_limit_ms(),
+            GameMode::Dig => self.garbage_cleared >= self.config.dig_lines,
+            GameMode::Zen | GameMode::Invisible | GameMode::Survival | GameMode::Sandbox => false,
+            GameMode::Master => self.level >= master::FINAL_LEVEL,
+            GameMode::Puzzle => match &self.puzzle {
+                Some(puzzle) => self.puzzle_solved || self.pieces >= puzzle.pieces.len(),
+                None => false,
+            },
+        }
     }
 
-    fn handle_game_over(&mut self, stdout: &mut io::Stdout) -> Result<()> {
-        if let Some(stream) = &mut self.stream {
-            multiplayer::send_to_other_player(
-                stream,
-                MessageType::Notification("YOU WIN!".to_string()),
-            );
-            self.multiplayer_score.competitor_score += 1;
+    /// What the records of the mode keep when they aren't ranked by time.
+    /// Master records keep the grade, the score only decides it.
+    fn record_score(&self) -> usize {
+        match self.config.mode {
+            GameMode::Master => self.master.grade(self.score),
+            _ => self.score,
+        }
+    }
 
-            let stats_start_x = self.start_x - STATS_WIDTH - DISTANCE - 1;
-            if let Some(_) = &self.stream {
-                self.terminal.write(
-                    Color::White,
-                    stats_start_x as u16 + 2 + "Score: ".len() as u16,
-                    self.start_y as u16 + 10,
-                    format!(
-                        "{} - {}",
-                        self.multiplayer_score.my_score, self.multiplayer_score.competitor_score
-                    )
-                    .as_str(),
+    fn handle_finish(&mut self, stdout: &mut io::Stdout) -> Result<()> {
+        self.clock.pause();
+        self.render_stats()?;
+
+        // Puzzles have no leaderboard, only whether they have been solved
+        if self.config.mode == GameMode::Puzzle {
+            if let Some(puzzle) = self.puzzle.as_ref().filter(|_| self.puzzle_solved) {
+                self.highscore_repo.insert_solved_puzzle(
+                    &puzzle.pack,
+                    &puzzle.name,
+                    self.pieces,
                 )?;
             }
+            return self.show_high_scores(stdout);
         }
 
-        if self.score == 0 {
-            self.show_high_scores(stdout)?;
+        self.show_results(stdout)?;
+
+        // Every finished try of the daily challenge is ranked
+        if self.daily.is_some() {
+            return self.new_high_score(stdout);
+        }
+
+        let mode = self.config.mode;
+        let records = self.highscore_repo.get_top_records(mode)?;
+        let is_new_record = match records.get(4) {
+            _ if !mode.ranks_by_time() && self.score == 0 => false,
+            None => true,
+            Some(last) => last.is_beaten_by(mode, self.record_score() as u64, self.time_ms()),
+        };
+
+        if is_new_record {
+            self.new_high_score(stdout)?;
         } else {
-            let count: i64 = self.highscore_repo.count()?;
+            self.show_high_scores(stdout)?;
+        }
 
-            if count < 5 {
-                self.new_high_score(stdout)?;
-            } else {
-                let player: Player = self.highscore_repo.get_player_at_rank(5)?;
+        Ok(())
+    }
 
-                if (self.score as u64) <= player.score {
-                    self.show_high_scores(stdout)?;
-                } else {
-                    self.new_high_score(stdout)?;
+    /// Sums up a game of a timed mode before the leaderboard.
+    fn show_results(&mut self, stdout: &mut io::Stdout) -> Result<()> {
+        let mut lines = vec![self.result_title(), String::new()];
+        if self.config.mode == GameMode::Master {
+            lines.extend([
+                format!("Grade: {}", master::grade_name(self.record_score())),
+                format!("Level: {}", self.level),
+                format!("Time: {}", mode::format_time(self.time_ms())),
+                String::new(),
+            ]);
+            lines.extend(self.section_lines());
+        } else {
+            lines.extend([
+                format!("Score: {}", self.score),
+                format!("Lines: {}", self.lines),
+                format!("Time: {}", mode::format_time(self.time_ms())),
+                format!("PPS: {:.2}", self.pieces_per_second()),
+            ]);
+            if !self.splits.is_empty() {
+                lines.push(String::new());
+                lines.extend(self.split_lines()?);
+            }
+        }
+        lines.extend([String::new(), self.config.keys.prompt(&[Action::Continue])]);
+        self.print_centered_messages(
+            stdout,
+            Some((PLAY_WIDTH + 2) * CELL_WIDTH),
+            lines.iter().map(|line| line.as_str()).collect(),
+        )?;
+
+        loop {
+            if self.terminal.poll_event(Duration::from_millis(10))? {
+                if let Event::Key(KeyEvent {
+                    code,
+                    kind: KeyEventKind::Press,
+                    ..
+                }) = self.terminal.read_event()?
+                {
+                    if self.config.keys.keys(Action::Continue).contains(&code) {
+                        break;
+                    }
                 }
             }
         }
 
-        Ok(())
+        // The leaderboard is smaller than the results
+        self.render(stdout)
     }
 
-    fn show_high_scores(&mut self, stdout: &mut io::Stdout) -> Result<()> {
-        let mut players_str: Vec<String> = Vec::new();
-        {
-            let players = self.highscore_repo.get_top_players()?;
-            for player in players.iter() {
-                let formatted_str = format!(
-                    "{:<width$}{:>9}",
-                    player.name,
-                    player.score,
-                    width = MAX_NAME_LENGTH + 3
-                );
+    /// The time of each Master section in two columns, e.g. `000 0:58.120   500 1:02.331`
+    fn section_lines(&self) -> Vec<String> {
+        let times: Vec<String> = self
+            .master
+            .section_times(self.time_ms())
+            .iter()
+            .enumerate()
+            .map(|(section, time_ms)| {
+                format!(
+                    "{:03} {}",
+                    section * master::SECTION_LEVELS,
+                    mode::format_time(*time_ms)
+                )
+            })
+            .collect();
+
+        let rows = master::SECTIONS / 2;
+        times
+            .iter()
+            .take(rows)
+            .enumerate()
+            .map(|(row, left)| {
+                let right = times.get(row + rows).map_or("", |time| time.as_str());
+                format!("{:<12}   {:<12}", left, right)
+            })
+            .collect()
+    }
+
+    /// The last Sprint splits, each next to how it did against the fastest
+    /// record, e.g. `30  0:31.250  -0.512`
+    fn split_lines(&self) -> Result<Vec<String>> {
+        let best = self.highscore_repo.get_best_splits(self.config.mode)?;
+        let first = self.splits.len().saturating_sub(MAX_SHOWN_SPLITS);
+        let lines = self.splits[first..]
+            .iter()
+            .enumerate()
+            .map(|(index, &time_ms)| {
+                let index = first + index;
+                let delta = best.get(index).map_or(String::new(), |&best_ms| {
+                    mode::format_delta(time_ms as i64 - best_ms as i64)
+                });
+                format!(
+                    "{:<4}{}  {:>7}",
+                    (index + 1) * mode::SPLIT_LINES,
+                    mode::format_time(time_ms),
+                    delta
+                )
+            })
+            .collect();
+        Ok(lines)
+    }
 
-                players_str.push(formatted_str)
+    fn result_title(&self) -> String {
+        match self.config.mode {
+            GameMode::Sprint | GameMode::Dig | GameMode::Master if self.is_finished() => {
+                format!("FINISHED IN {}", mode::format_time(self.time_ms()))
             }
+            GameMode::Ultra if self.is_finished() => "TIME'S UP!".to_string(),
+            GameMode::Puzzle if self.puzzle_solved => "PUZZLE SOLVED!".to_string(),
+            GameMode::Puzzle => "PUZZLE FAILED".to_string(),
+            _ => "GAME OVER".to_string(),
         }
+    }
 
+    fn show_high_scores(&mut self, stdout: &mut io::Stdout) -> Result<()> {
+        let (heading, mut players_str) = match &self.daily {
+            Some(daily) => (
+                "TODAY'S RANKING",
+                daily_ranking(self.highscore_repo.as_ref(), daily)?,
+            ),
+            None => (
+                "HIGH SCORES",
+                leaderboard(self.highscore_repo.as_ref(), self.config.mode)?,
+            ),
+        };
+        let title = self.result_title();
+
+        let prompt = self.config.keys.prompt(Context::GameOver.actions());
         if players_str.len() > 0 {
             self.print_centered_messages(
                 stdout,
                 Some((PLAY_WIDTH + 2) * CELL_WIDTH).into(),
-                vec!["GAME OVER"]
+                vec![title.as_str()]
                     .into_iter()
                     .chain(vec![""; players_str.len() + 3])
-                    .chain(vec!["(R)estart | (Q)uit"].into_iter())
+                    .chain(vec![prompt.as_str()])
                     .collect::<Vec<&str>>(),
             )?;
 
-            players_str.insert(0, "HIGH SCORES".to_string());
+            players_str.insert(0, heading.to_string());
 
             self.print_centered_messages(
                 stdout,
@@ -1485,11 +3511,7 @@ impl Game {
                 players_str.iter().map(|s| s.as_str()).collect(),
             )?;
         } else {
-            self.print_centered_messages(
-                stdout,
-                None,
-                vec!["GAME OVER", "", "(R)estart | (Q)uit"],
-            )?;
+            self.print_centered_messages(stdout, None, vec![&title, "", &prompt])?;
         }
 
         loop {
@@ -1503,11 +3525,11 @@ impl Game {
                         state: _,
                     }) => {
                         if kind == KeyEventKind::Press {
-                            match code {
-                                KeyCode::Char('q') => {
+                            match self.config.keys.action(Context::GameOver, code) {
+                                Some(Action::Quit) => {
                                     self.quit()?;
                                 }
-                                KeyCode::Char('r') => {
+                                Some(Action::Restart) => {
                                     reset_game(self, stdout)?;
                                 }
                                 _ => {}
@@ -1521,19 +3543,36 @@ impl Game {
     }
 
     fn new_high_score(&mut self, stdout: &mut std::io::Stdout) -> Result<()> {
+        let (heading, result) = if self.config.mode == GameMode::Marathon {
+            ("NEW HIGH SCORE!", self.score.to_string())
+        } else if self.config.mode == GameMode::Master {
+            (
+                "NEW RECORD!",
+                master::grade_name(self.record_score()).to_string(),
+            )
+        } else if self.config.mode.ranks_by_time() {
+            ("NEW RECORD!", mode::format_time(self.time_ms()))
+        } else {
+            ("NEW RECORD!", self.score.to_string())
+        };
+        let heading = if self.daily.is_some() {
+            "DAILY CHALLENGE"
+        } else {
+            heading
+        };
         self.print_centered_messages(
             stdout,
             None,
             vec![
-                "NEW HIGH SCORE!",
-                &self.score.to_string(),
+                heading,
+                &result,
                 "",
                 &format!("{}{}", ENTER_YOUR_NAME_MESSAGE, " ".repeat(MAX_NAME_LENGTH)),
             ],
         )?;
 
-        let mut name = String::new();
-        let mut cursor_position: usize = 0;
+        let mut name = self.config.default_name.clone();
+        let mut cursor_position: usize = name.len();
 
         let (term_width, term_height) = terminal::size()?;
         stdout.execute(MoveTo(
@@ -1574,7 +3613,25 @@ impl Game {
                                     }
                                 }
                                 KeyCode::Enter => {
-                                    self.highscore_repo.insert(&name, self.score)?;
+                                    if let Some(daily) = &self.daily {
+                                        self.highscore_repo.insert_daily_record(
+                                            &daily.day,
+                                            self.config.mode,
+                                            &name,
+                                            self.score,
+                                            self.time_ms(),
+                                        )?;
+                                    } else if self.config.mode == GameMode::Marathon {
+                                        self.highscore_repo.insert(&name, self.score)?;
+                                    } else {
+                                        self.highscore_repo.insert_record(
+                                            self.config.mode,
+                                            &name,
+                                            self.record_score(),
+                                            self.time_ms(),
+                                            &self.splits,
+                                        )?;
+                                    }
 
                                     execute!(stdout.lock(), cursor::Hide)?;
                                     self.show_high_scores(stdout)?;
@@ -1621,57 +3678,13 @@ impl Game {
         width: Option<usize>,
         messages: Vec<&str>,
     ) -> Result<()> {
-        let (term_width, term_height) = terminal::size()?;
-        let start_y = term_height / 2 - messages.len() as u16 / 2;
-
-        let longest_length = find_longest_message_length(&messages);
-
-        let frame_width: usize;
-        if let Some(value) = width {
-            frame_width = value;
-        } else {
-            frame_width = longest_length + MARGIN * 2;
-        }
-
-        let start_x = (term_width - frame_width as u16 - 2) / 2;
-
-        // Print the top border
-        self.terminal.write(
-            Color::White,
-            start_x,
-            start_y - 1,
-            format!("{}{}{}", "|", ("-").repeat(frame_width), "|").as_str(),
-        )?;
-
-        // Print the messages with borders
-        for (index, message) in messages.iter().enumerate() {
-            let left = (frame_width - message.len()) / 2;
-            self.terminal.write(
-                Color::White,
-                start_x,
-                start_y + index as u16,
-                format!(
-                    "|{}{}{}|",
-                    " ".repeat(left as usize),
-                    message,
-                    " ".repeat(frame_width - left - message.len())
-                )
-                .as_str(),
-            )?;
-        }
-
-        // Print the bottom border
-        let bottom_border_y = start_y + messages.len() as u16;
-        self.terminal.write(
-            Color::White,
-            start_x,
-            bottom_border_y,
-            format!("{}{}{}", "|", ("-").repeat(frame_width), "|").as_str(),
-        )?;
-
-        stdout.flush()?;
-
-        Ok(())
+        print_centered_messages(
+            self.terminal.as_ref(),
+            self.theme.foreground,
+            stdout,
+            width,
+            messages,
+        )
     }
 
     pub fn quit(&self) -> Result<()> {
@@ -1681,33 +3694,137 @@ impl Game {
     }
 }
 
+/// The top 5 of a mode, one formatted line per player.
+pub(crate) fn leaderboard(highscore_repo: &dyn HighScore, mode: GameMode) -> Result<Vec<String>> {
+    let lines = if mode == GameMode::Marathon {
+        highscore_repo
+            .get_top_players()?
+            .iter()
+            .map(|player| {
+                format!(
+                    "{:<width$}{:>9}",
+                    player.name,
+                    player.score,
+                    width = MAX_NAME_LENGTH + 3
+                )
+            })
+            .collect()
+    } else {
+        record_lines(&highscore_repo.get_top_records(mode)?, mode)
+    };
+
+    Ok(lines)
+}
+
+/// The best try of each player at a daily challenge, one formatted line per player.
+pub(crate) fn daily_ranking(highscore_repo: &dyn HighScore, daily: &Daily) -> Result<Vec<String>> {
+    let records = highscore_repo.get_daily_records(&daily.day, daily.mode)?;
+    Ok(record_lines(&records, daily.mode))
+}
+
+fn record_lines(records: &[Record], mode: GameMode) -> Vec<String> {
+    records
+        .iter()
+        .map(|record| {
+            let result = if mode.ranks_by_time() {
+                mode::format_time(record.time_ms)
+            } else if mode == GameMode::Master {
+                master::grade_name(record.score as usize).to_string()
+            } else {
+                record.score.to_string()
+            };
+            format!(
+                "{:<width$}{:>9}",
+                record.name,
+                result,
+                width = MAX_NAME_LENGTH + 3
+            )
+        })
+        .collect()
+}
+
 fn reset_game(game: &mut Game, stdout: &mut io::Stdout) -> Result<()> {
     game.reset();
     game.render(stdout)?;
+    if game.stream.is_some() && !game.disconnected {
+        // Both players start the round together
+        game.count_down(stdout)?;
+    }
 
     game.handle_event(stdout)?;
 
     Ok(())
 }
 
+pub(crate) fn print_centered_messages(
+    terminal: &dyn Terminal,
+    foreground_color: Color,
+    stdout: &mut io::Stdout,
+    width: Option<usize>,
+    messages: Vec<&str>,
+) -> Result<()> {
+    let (term_width, term_height) = terminal::size()?;
+    let start_y = term_height / 2 - messages.len() as u16 / 2;
+
+    let longest_length = find_longest_message_length(&messages);
+
+    let frame_width: usize;
+    if let Some(value) = width {
+        frame_width = value;
+    } else {
+        frame_width = longest_length + MARGIN * 2;
+    }
+
+    let start_x = (term_width - frame_width as u16 - 2) / 2;
+
+    // Print the top border
+    terminal.write(
+        foreground_color,
+        start_x,
+        start_y - 1,
+        format!("{}{}{}", "|", ("-").repeat(frame_width), "|").as_str(),
+    )?;
+
+    // Print the messages with borders
+    for (index, message) in messages.iter().enumerate() {
+        let left = (frame_width - message.len()) / 2;
+        terminal.write(
+            foreground_color,
+            start_x,
+            start_y + index as u16,
+            format!(
+                "|{}{}{}|",
+                " ".repeat(left),
+                message,
+                " ".repeat(frame_width - left - message.len())
+            )
+            .as_str(),
+        )?;
+    }
+
+    // Print the bottom border
+    let bottom_border_y = start_y + messages.len() as u16;
+    terminal.write(
+        foreground_color,
+        start_x,
+        bottom_border_y,
+        format!("{}{}{}", "|", ("-").repeat(frame_width), "|").as_str(),
+    )?;
+
+    stdout.flush()?;
+
+    Ok(())
+}
+
 fn create_grid(
     width: usize,
     height: usize,
     start_with_number_of_filled_lines: usize,
+    garbage: &mut GarbageGenerator,
 ) -> Vec<Vec<Cell>> {
     let mut grid = vec![vec![EMPTY_CELL; width]; height - start_with_number_of_filled_lines];
-
-    let cells = vec![I_CELL, O_CELL, T_CELL, S_CELL, Z_CELL, T_CELL, L_CELL];
     for _ in 0..start_with_number_of_filled_lines {
-        let mut rng = rand::thread_rng();
-        let random_cell_index = rng.gen_range(0..cells.len());
-        let random_cell = cells[random_cell_index].clone();
-
-        let mut new_row = vec![random_cell; PLAY_WIDTH];
-        let random_column = rng.gen_range(0..PLAY_WIDTH);
-        new_row[random_column] = EMPTY_CELL;
-
-        grid.push(new_row.clone());
+        grid.push(garbage.next_row());
     }
 
     grid
@@ -1808,10 +3925,84 @@ fn find_longest_key_value_length(messages: &Vec<&str>) -> (usize, usize) {
         if message.len() == 0 {
             continue;
         }
-        let parts: Vec<&str> = message.split(':').collect();
+        let parts: Vec<&str> = message.splitn(2, ':').collect();
         longest_key_length = longest_key_length.max(parts[0].len());
         longest_value_length = longest_value_length.max(parts[1].chars().count());
     }
 
     (longest_key_length, longest_value_length)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(score: u64, time_ms: u64) -> Record {
+        Record {
+            name: "Ann".to_string(),
+            score,
+            time_ms,
+        }
+    }
+
+    fn series(best_of: u8, my_score: u8, competitor_score: u8) -> MultiplayerScore {
+        MultiplayerScore {
+            my_score,
+            competitor_score,
+            my_name: "Ann".to_string(),
+            competitor_name: "Bob".to_string(),
+            best_of,
+        }
+    }
+
+    #[test]
+    fn needs_more_than_half_of_the_rounds() {
+        assert_eq!(series(1, 0, 0).wins_needed(), 1);
+        assert_eq!(series(3, 0, 0).wins_needed(), 2);
+        assert_eq!(series(5, 0, 0).wins_needed(), 3);
+        assert_eq!(series(9, 0, 0).wins_needed(), 5);
+    }
+
+    #[test]
+    fn ends_the_series_once_a_player_has_enough_wins() {
+        assert!(!series(3, 0, 0).is_series_over());
+        assert!(!series(3, 1, 1).is_series_over());
+        assert!(series(3, 2, 0).is_series_over());
+        assert!(series(3, 1, 2).is_series_over());
+        assert!(series(1, 0, 1).is_series_over());
+        assert!(!series(5, 2, 2).is_series_over());
+    }
+
+    #[test]
+    fn counts_rounds_from_one() {
+        assert_eq!(series(3, 0, 0).round(), 1);
+        assert_eq!(series(5, 2, 1).round(), 4);
+        assert_eq!(series(3, 1, 0).line(), "Ann 1 - 0 Bob");
+    }
+
+    #[test]
+    fn agrees_when_both_players_said_yes() {
+        let votes = |mine, theirs| Votes { mine, theirs }.agreed();
+        assert!(votes(true, Some(true)));
+        assert!(!votes(true, None));
+        assert!(!votes(true, Some(false)));
+        assert!(!votes(false, Some(true)));
+    }
+
+    #[test]
+    fn only_a_faster_time_beats_a_record_ranked_by_time() {
+        let last = record(5_000, 60_000);
+        assert!(last.is_beaten_by(GameMode::Sprint, 0, 59_999));
+        assert!(!last.is_beaten_by(GameMode::Sprint, 9_000, 60_000));
+        assert!(!last.is_beaten_by(GameMode::Dig, 9_000, 61_000));
+    }
+
+    #[test]
+    fn a_higher_score_or_the_same_score_faster_beats_a_record() {
+        let last = record(5_000, 60_000);
+        assert!(last.is_beaten_by(GameMode::Ultra, 5_001, 120_000));
+        assert!(last.is_beaten_by(GameMode::Ultra, 5_000, 59_000));
+        assert!(!last.is_beaten_by(GameMode::Ultra, 5_000, 60_000));
+        assert!(!last.is_beaten_by(GameMode::Master, 4_999, 1_000));
+    }
+}
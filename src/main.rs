@@ -4,24 +4,34 @@ use crossterm::terminal;
 
 use clap::Parser;
 
-use tetris_tui::{Args, PLAY_WIDTH, PLAY_HEIGHT, CELL_WIDTH, STATS_WIDTH, DISTANCE, MAX_LEVEL, Result};
+use tetris_tui::{config::Config, required_size, Args, Result};
 
 fn main() -> Result<()> {
     let args = Args::parse();
-    if args.number_of_lines_already_filled > 10 {
-        eprintln!("The number of lines already filled must be less than or equal 10.");
+    let saved = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(1);
+        }
+    };
+    let config = saved.clone().with_args(&args);
+
+    if let Err(err) = config.validate() {
+        eprintln!("{}", err);
         exit(1);
     }
 
-    if args.level > MAX_LEVEL {
-        eprintln!("Level must be between 0 and {}.", MAX_LEVEL);
-        exit(1);
+    if let Some(command) = &args.command {
+        if let Err(err) = tetris_tui::run(command, &args, &config) {
+            eprintln!("{}", err);
+            exit(1);
+        }
+        return Ok(());
     }
 
     let (term_width, term_height) = terminal::size()?;
-    let play_width = PLAY_WIDTH * CELL_WIDTH + 2;
-    let required_width = (STATS_WIDTH + 2 + DISTANCE) * 2 + play_width;
-    let required_height = PLAY_HEIGHT + 2;
+    let (required_width, required_height) = required_size(&config);
     if term_width < required_width as u16 || term_height < required_height as u16 {
         eprintln!(
             "The terminal is too small: {}x{}.\nRequired dimensions are  : {}x{}.",
@@ -30,7 +40,10 @@ fn main() -> Result<()> {
         exit(1);
     }
 
-    tetris_tui::start(&args, term_width, term_height)?;
+    if let Err(err) = tetris_tui::start(&args, saved, term_width, term_height) {
+        eprintln!("{}", err);
+        exit(1);
+    }
 
     Ok(())
 }
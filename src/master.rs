@@ -0,0 +1,343 @@
+use std::time::Duration;
+
+/// Reaching this level finishes the game.
+pub const FINAL_LEVEL: usize = 999;
+/// The number of levels in a section. The time of each one is shown at the end.
+pub const SECTION_LEVELS: usize = 100;
+pub const SECTIONS: usize = 10;
+
+// Gravity and lock delay are counted in frames of the arcade game
+const FRAMES_PER_SECOND: f64 = 60.0;
+// Gravity is in 1/256 rows per frame, each entry applies from its level on.
+// 5120 is 20 rows per frame (20G): a new piece lands on the stack right away.
+const GRAVITY: [(usize, u32); 30] = [
+    (0, 4),
+    (30, 6),
+    (35, 8),
+    (40, 10),
+    (50, 12),
+    (60, 16),
+    (70, 32),
+    (80, 48),
+    (90, 64),
+    (100, 80),
+    (120, 96),
+    (140, 112),
+    (160, 128),
+    (170, 144),
+    (200, 4),
+    (220, 32),
+    (230, 64),
+    (233, 96),
+    (236, 128),
+    (239, 160),
+    (243, 192),
+    (247, 224),
+    (251, 256),
+    (300, 512),
+    (330, 768),
+    (360, 1024),
+    (400, 1280),
+    (420, 1024),
+    (450, 768),
+    (500, 5120),
+];
+// How long (in frames) a piece can rest on the stack before it locks
+const LOCK_DELAY: [(usize, u64); 6] = [
+    (0, 30),
+    (500, 27),
+    (600, 24),
+    (700, 21),
+    (800, 18),
+    (900, 15),
+];
+
+// The score needed for each grade, from 9 (the lowest) up to S9
+const GRADES: [(usize, &str); 18] = [
+    (0, "9"),
+    (400, "8"),
+    (800, "7"),
+    (1400, "6"),
+    (2000, "5"),
+    (3500, "4"),
+    (5500, "3"),
+    (8000, "2"),
+    (12000, "1"),
+    (16000, "S1"),
+    (22000, "S2"),
+    (30000, "S3"),
+    (40000, "S4"),
+    (52000, "S5"),
+    (66000, "S6"),
+    (82000, "S7"),
+    (100000, "S8"),
+    (120000, "S9"),
+];
+const GRAND_MASTER: &str = "GM";
+// The checkpoints of the GM grade: (level, at most this time in ms, at least this score)
+const GRAND_MASTER_CHECKS: [(usize, u64, usize); 3] = [
+    (300, 255_000, 12000),
+    (500, 450_000, 40000),
+    (FINAL_LEVEL, 810_000, 126000),
+];
+
+pub fn rows_per_second(level: usize) -> f64 {
+    let gravity = GRAVITY
+        .iter()
+        .rev()
+        .find(|(from, _)| level >= *from)
+        .map_or(GRAVITY[0].1, |(_, gravity)| *gravity);
+
+    gravity as f64 / 256.0 * FRAMES_PER_SECOND
+}
+
+pub fn lock_delay(level: usize) -> Duration {
+    let frames = LOCK_DELAY
+        .iter()
+        .rev()
+        .find(|(from, _)| level >= *from)
+        .map_or(LOCK_DELAY[0].1, |(_, frames)| *frames);
+
+    Duration::from_secs_f64(frames as f64 / FRAMES_PER_SECOND)
+}
+
+/// The level after a new piece comes in. Only line clears go past the last
+/// level of a section (x99) or the level before the final one.
+pub fn level_after_piece(level: usize) -> usize {
+    if level % SECTION_LEVELS == SECTION_LEVELS - 1 || level >= FINAL_LEVEL - 1 {
+        level
+    } else {
+        level + 1
+    }
+}
+
+pub fn level_after_lines(level: usize, lines: usize) -> usize {
+    (level + lines).min(FINAL_LEVEL)
+}
+
+/// The level at the end of the current section, shown next to the level.
+pub fn section_end(level: usize) -> usize {
+    ((level / SECTION_LEVELS + 1) * SECTION_LEVELS).min(FINAL_LEVEL)
+}
+
+pub fn grade_name(grade: usize) -> &'static str {
+    GRADES.get(grade).map_or(GRAND_MASTER, |(_, name)| name)
+}
+
+// The playing time and the score when a section ended
+struct Split {
+    time_ms: u64,
+    score: usize,
+}
+
+/// The state of a Master game besides the level and score kept by the game.
+pub struct Master {
+    splits: Vec<Split>,
+    combo: usize,
+    // Rows the piece has yet to fall, carried over between ticks
+    fall: f64,
+    fell_at: Duration,
+    // When (in playing time) the piece came to rest on the stack
+    grounded_at: Option<Duration>,
+}
+
+impl Master {
+    pub fn new() -> Self {
+        Master {
+            splits: Vec::new(),
+            combo: 1,
+            fall: 0.0,
+            fell_at: Duration::ZERO,
+            grounded_at: None,
+        }
+    }
+
+    /// The number of whole rows the piece falls between the last call and `now`.
+    /// This is more than one row per tick once the gravity is high enough.
+    pub fn rows_to_fall(&mut self, level: usize, now: Duration) -> usize {
+        self.fall += now.saturating_sub(self.fell_at).as_secs_f64() * rows_per_second(level);
+        self.fell_at = now;
+
+        let rows = self.fall.floor();
+        self.fall -= rows;
+        rows as usize
+    }
+
+    /// Whether a piece resting on the stack must lock at `now`.
+    pub fn must_lock(&mut self, level: usize, now: Duration) -> bool {
+        // A resting piece doesn't build up rows to fall once it's moved away
+        self.fall = 0.0;
+        let grounded_at = *self.grounded_at.get_or_insert(now);
+        now.saturating_sub(grounded_at) >= lock_delay(level)
+    }
+
+    /// Called when the piece falls, which gives it a new lock delay.
+    pub fn lift(&mut self) {
+        self.grounded_at = None;
+    }
+
+    pub fn next_piece(&mut self) {
+        self.fall = 0.0;
+        self.grounded_at = None;
+    }
+
+    /// The points of a locked piece that cleared `lines` at `level`.
+    /// Consecutive clears build a combo and clearing the whole board counts 4 times.
+    pub fn score(&mut self, level: usize, lines: usize, perfect_clear: bool) -> usize {
+        if lines == 0 {
+            self.combo = 1;
+            return 0;
+        }
+
+        self.combo += 2 * lines - 2;
+        let bravo = if perfect_clear { 4 } else { 1 };
+        (level + lines).div_ceil(4) * lines * self.combo * bravo
+    }
+
+    /// Ends the sections passed when reaching `level`.
+    pub fn reach(&mut self, level: usize, time_ms: u64, score: usize) {
+        let sections = if level >= FINAL_LEVEL {
+            SECTIONS
+        } else {
+            level / SECTION_LEVELS
+        };
+        while self.splits.len() < sections {
+            self.splits.push(Split { time_ms, score });
+        }
+    }
+
+    /// How long each section took, including the current one when the game isn't over.
+    pub fn section_times(&self, time_ms: u64) -> Vec<u64> {
+        let mut started_at = 0;
+        let mut times = Vec::new();
+        for split in self.splits.iter() {
+            times.push(split.time_ms - started_at);
+            started_at = split.time_ms;
+        }
+        if times.len() < SECTIONS {
+            times.push(time_ms - started_at);
+        }
+
+        times
+    }
+
+    /// The index of the grade for `score`, see `grade_name`. The GM grade also
+    /// needs the score and time of every checkpoint.
+    pub fn grade(&self, score: usize) -> usize {
+        let is_grand_master = GRAND_MASTER_CHECKS
+            .iter()
+            .all(|(level, time_ms, min_score)| {
+                let section = (level + 1) / SECTION_LEVELS;
+                self.splits
+                    .get(section - 1)
+                    .is_some_and(|split| split.time_ms <= *time_ms && split.score >= *min_score)
+            });
+        if is_grand_master {
+            return GRADES.len();
+        }
+
+        GRADES
+            .iter()
+            .rposition(|(min_score, _)| score >= *min_score)
+            .unwrap_or(0)
+    }
+}
+
+impl Default for Master {
+    fn default() -> Self {
+        Master::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn grades_by_score() {
+        let master = Master::new();
+        let grade = |score| grade_name(master.grade(score));
+        assert_eq!(grade(0), "9");
+        assert_eq!(grade(399), "9");
+        assert_eq!(grade(400), "8");
+        assert_eq!(grade(11_999), "2");
+        assert_eq!(grade(12_000), "1");
+        assert_eq!(grade(16_000), "S1");
+        assert_eq!(grade(119_999), "S8");
+        assert_eq!(grade(1_000_000), "S9");
+    }
+
+    #[test]
+    fn grand_master_needs_every_checkpoint() {
+        let mut master = Master::new();
+        master.reach(300, 250_000, 12_000);
+        master.reach(500, 440_000, 40_000);
+        master.reach(FINAL_LEVEL, 800_000, 126_000);
+        assert_eq!(grade_name(master.grade(126_000)), "GM");
+
+        // Too slow at level 500
+        let mut master = Master::new();
+        master.reach(300, 250_000, 12_000);
+        master.reach(500, 460_000, 40_000);
+        master.reach(FINAL_LEVEL, 800_000, 126_000);
+        assert_eq!(grade_name(master.grade(126_000)), "S9");
+    }
+
+    #[test]
+    fn falls_slowly_at_first() {
+        // 4/256 rows per frame: 0.9375 rows per second
+        let mut master = Master::new();
+        assert_eq!(master.rows_to_fall(0, ms(1000)), 0);
+        assert_eq!(master.rows_to_fall(0, ms(2000)), 1);
+        assert_eq!(master.rows_to_fall(0, ms(2100)), 0);
+    }
+
+    #[test]
+    fn falls_faster_with_the_level() {
+        // 80/256 rows per frame: 18.75 rows per second
+        let mut master = Master::new();
+        assert_eq!(master.rows_to_fall(100, ms(1000)), 18);
+        assert_eq!(master.rows_to_fall(100, ms(2000)), 19);
+
+        // Back to the first gravity at level 200
+        let mut master = Master::new();
+        assert_eq!(master.rows_to_fall(200, ms(2000)), 1);
+
+        // 20G: a whole board in a few frames
+        let mut master = Master::new();
+        assert_eq!(master.rows_to_fall(500, ms(100)), 120);
+    }
+
+    #[test]
+    fn locks_after_the_lock_delay() {
+        // 30 frames, half a second
+        let mut master = Master::new();
+        assert!(!master.must_lock(0, ms(1000)));
+        assert!(!master.must_lock(0, ms(1499)));
+        assert!(master.must_lock(0, ms(1500)));
+    }
+
+    #[test]
+    fn locks_sooner_at_higher_levels() {
+        // 15 frames, a quarter of a second
+        let mut master = Master::new();
+        assert!(!master.must_lock(900, ms(1000)));
+        assert!(!master.must_lock(900, ms(1249)));
+        assert!(master.must_lock(900, ms(1250)));
+        assert_eq!(lock_delay(600), ms(400));
+    }
+
+    #[test]
+    fn falling_gives_a_new_lock_delay() {
+        let mut master = Master::new();
+        assert!(!master.must_lock(0, ms(1000)));
+        master.lift();
+        assert!(!master.must_lock(0, ms(1400)));
+        assert!(!master.must_lock(0, ms(1800)));
+        assert!(master.must_lock(0, ms(1900)));
+    }
+}
@@ -0,0 +1,848 @@
+use std::io;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyEventKind};
+use crossterm::terminal;
+
+use crate::config::{
+    Config, MAX_BEST_OF, MAX_DIG_LINES, MAX_DIG_RISE_SECONDS, MAX_GARBAGE_MESSINESS,
+    MAX_INVISIBLE_FADE_SECONDS, MAX_PREVIEW_COUNT, MAX_SPRINT_LINES, MAX_ULTRA_SECONDS,
+};
+use crate::daily::Daily;
+use crate::garbage::GarbageStyle;
+use crate::glyphs::GlyphStyle;
+use crate::keys::{self, KeyBindings, ACTIONS};
+use crate::mode::{GameMode, TopOut};
+use crate::puzzle;
+use crate::randomizer::Randomizer;
+use crate::theme::{self, ColorSupport, Theme};
+use crate::{
+    daily_ranking, leaderboard, print_centered_messages, required_size, Args, HighScore,
+    RealTerminal, Result, Terminal, MAX_LEVEL, MAX_NAME_LENGTH, MAX_NUMBER_OF_LINES_ALREADY_FILLED,
+};
+
+const MENU_WIDTH: usize = 36;
+const MAX_ADDRESS_LENGTH: usize = 28;
+const DAS_STEP: u64 = 10;
+const ARR_STEP: u64 = 5;
+const MAX_DELAY: u64 = 1000;
+const SPRINT_LINES_STEP: usize = 10;
+const ULTRA_SECONDS_STEP: u64 = 30;
+const DIG_LINES_STEP: usize = 5;
+const DIG_RISE_STEP: u64 = 5;
+const INVISIBLE_FADE_STEP: u64 = 1;
+const GARBAGE_MESSINESS_STEP: u8 = 10;
+const PUZZLES_PER_PAGE: usize = 10;
+
+pub enum MenuChoice {
+    Play,
+    Host,
+    Join,
+    Quit,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum MainItem {
+    Play,
+    Mode,
+    Daily,
+    Host,
+    Join,
+    HighScores,
+    Settings,
+    Quit,
+}
+
+const MAIN_ITEMS: [MainItem; 8] = [
+    MainItem::Play,
+    MainItem::Mode,
+    MainItem::Daily,
+    MainItem::Host,
+    MainItem::Join,
+    MainItem::HighScores,
+    MainItem::Settings,
+    MainItem::Quit,
+];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SettingsItem {
+    Level,
+    LinesFilled,
+    Theme,
+    Glyphs,
+    Das,
+    Arr,
+    Ghost,
+    Preview,
+    Randomizer,
+    GarbageMessiness,
+    BestOf,
+    Name,
+    Modes,
+    Controls,
+    Back,
+}
+
+const SETTINGS_ITEMS: [SettingsItem; 15] = [
+    SettingsItem::Level,
+    SettingsItem::LinesFilled,
+    SettingsItem::Theme,
+    SettingsItem::Glyphs,
+    SettingsItem::Das,
+    SettingsItem::Arr,
+    SettingsItem::Ghost,
+    SettingsItem::Preview,
+    SettingsItem::Randomizer,
+    SettingsItem::GarbageMessiness,
+    SettingsItem::BestOf,
+    SettingsItem::Name,
+    SettingsItem::Modes,
+    SettingsItem::Controls,
+    SettingsItem::Back,
+];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ModeItem {
+    SprintLines,
+    UltraSeconds,
+    DigLines,
+    DigGarbage,
+    DigRise,
+    ZenGravity,
+    ZenTopOut,
+    InvisibleFade,
+    Back,
+}
+
+const MODE_ITEMS: [ModeItem; 9] = [
+    ModeItem::SprintLines,
+    ModeItem::UltraSeconds,
+    ModeItem::DigLines,
+    ModeItem::DigGarbage,
+    ModeItem::DigRise,
+    ModeItem::ZenGravity,
+    ModeItem::ZenTopOut,
+    ModeItem::InvisibleFade,
+    ModeItem::Back,
+];
+
+pub struct Menu<'a> {
+    terminal: RealTerminal,
+    highscore_repo: &'a dyn HighScore,
+    theme: Theme,
+    args: &'a mut Args,
+    config: &'a mut Config,
+}
+
+impl<'a> Menu<'a> {
+    pub fn new(
+        highscore_repo: &'a dyn HighScore,
+        args: &'a mut Args,
+        config: &'a mut Config,
+    ) -> Result<Self> {
+        let theme = Theme::load(&config.theme)?.with_color_support(ColorSupport::detect());
+
+        Ok(Menu {
+            terminal: RealTerminal {
+                background: theme.background,
+            },
+            highscore_repo,
+            theme,
+            args,
+            config,
+        })
+    }
+
+    /// Shows the start menu until the player picks something to do.
+    /// The chosen options are written back to `args`, and the settings to `config`,
+    /// which holds the saved settings without the command line flags.
+    pub fn show(&mut self, stdout: &mut io::Stdout) -> Result<MenuChoice> {
+        self.terminal.enable_raw_mode()?;
+        self.terminal.enter_alternate_screen()?;
+
+        let mut selected = 0;
+        loop {
+            self.render_main(stdout, selected)?;
+
+            match self.read_key()? {
+                KeyCode::Up | KeyCode::Char('k') => {
+                    selected = (selected + MAIN_ITEMS.len() - 1) % MAIN_ITEMS.len();
+                }
+                KeyCode::Down | KeyCode::Char('j') => {
+                    selected = (selected + 1) % MAIN_ITEMS.len();
+                }
+                KeyCode::Left | KeyCode::Char('h') if MAIN_ITEMS[selected] == MainItem::Mode => {
+                    self.args.mode = cycle(GameMode::value_variants(), &self.args.mode, false);
+                }
+                KeyCode::Right | KeyCode::Char('l') if MAIN_ITEMS[selected] == MainItem::Mode => {
+                    self.args.mode = cycle(GameMode::value_variants(), &self.args.mode, true);
+                }
+                KeyCode::Enter => match MAIN_ITEMS[selected] {
+                    MainItem::Play if self.args.mode == GameMode::Puzzle => {
+                        if let Some(id) = self.show_puzzles(stdout)? {
+                            self.args.puzzle = Some(id);
+                            return Ok(MenuChoice::Play);
+                        }
+                    }
+                    MainItem::Play => return Ok(MenuChoice::Play),
+                    MainItem::Mode => {
+                        self.args.mode = cycle(GameMode::value_variants(), &self.args.mode, true);
+                    }
+                    MainItem::Daily => {
+                        if self.show_daily(stdout)? {
+                            self.args.daily = true;
+                            return Ok(MenuChoice::Play);
+                        }
+                    }
+                    MainItem::Host => {
+                        self.args.multiplayer = true;
+                        self.args.server_address = None;
+                        return Ok(MenuChoice::Host);
+                    }
+                    MainItem::Join => {
+                        let address = self.read_text(
+                            stdout,
+                            "JOIN 2-PLAYER GAME",
+                            "Server address (host:port):",
+                            self.args.server_address.clone().unwrap_or_default(),
+                            MAX_ADDRESS_LENGTH,
+                        )?;
+                        if let Some(address) = address {
+                            self.args.multiplayer = true;
+                            self.args.server_address = Some(address);
+                            return Ok(MenuChoice::Join);
+                        }
+                    }
+                    MainItem::HighScores => self.show_high_scores(stdout)?,
+                    MainItem::Settings => self.show_settings(stdout)?,
+                    MainItem::Quit => return Ok(MenuChoice::Quit),
+                },
+                KeyCode::Esc | KeyCode::Char('q') => return Ok(MenuChoice::Quit),
+                _ => {}
+            }
+        }
+    }
+
+    /// Restores the terminal before leaving the menu without starting a game,
+    /// or before printing the multiplayer connection messages.
+    pub fn close(&self) -> Result<()> {
+        self.terminal.leave_alternate_screen()?;
+        self.terminal.disable_raw_mode()?;
+        Ok(())
+    }
+
+    fn render_main(&self, stdout: &mut io::Stdout, selected: usize) -> Result<()> {
+        let labels: Vec<String> = MAIN_ITEMS
+            .iter()
+            .map(|item| match item {
+                MainItem::Play => "Play".to_string(),
+                MainItem::Mode => format!("Mode: < {} >", self.args.mode),
+                MainItem::Daily => "Daily challenge".to_string(),
+                MainItem::Host => "Host 2-player game".to_string(),
+                MainItem::Join => "Join 2-player game".to_string(),
+                MainItem::HighScores => "High scores".to_string(),
+                MainItem::Settings => "Settings".to_string(),
+                MainItem::Quit => "Quit".to_string(),
+            })
+            .collect();
+
+        self.render_list(stdout, "TETRIS", &labels, selected)
+    }
+
+    fn render_list(
+        &self,
+        stdout: &mut io::Stdout,
+        title: &str,
+        labels: &[String],
+        selected: usize,
+    ) -> Result<()> {
+        let items: Vec<String> = labels
+            .iter()
+            .enumerate()
+            .map(|(index, label)| {
+                if index == selected {
+                    format!("> {} <", label)
+                } else {
+                    label.to_string()
+                }
+            })
+            .collect();
+
+        let mut messages = vec![title, ""];
+        messages.extend(items.iter().map(|item| item.as_str()));
+        messages.extend(["", "Up/Down: select | Enter: OK"]);
+
+        self.terminal.clear()?;
+        print_centered_messages(
+            &self.terminal,
+            self.theme.foreground,
+            stdout,
+            Some(MENU_WIDTH),
+            messages,
+        )
+    }
+
+    fn show_settings(&mut self, stdout: &mut io::Stdout) -> Result<()> {
+        let themes = theme::available_themes();
+
+        let mut selected = 0;
+        loop {
+            let labels: Vec<String> = SETTINGS_ITEMS
+                .iter()
+                .map(|item| match item {
+                    SettingsItem::Level => format!("Level: < {} >", self.config.level),
+                    SettingsItem::LinesFilled => format!(
+                        "Lines filled: < {} >",
+                        self.config.number_of_lines_already_filled
+                    ),
+                    SettingsItem::Theme => format!("Theme: < {} >", self.config.theme),
+                    SettingsItem::Glyphs => format!(
+                        "Glyphs: < {} >",
+                        self.config.glyphs.to_possible_value().unwrap().get_name()
+                    ),
+                    SettingsItem::Das => format!("DAS: < {}ms >", self.config.das),
+                    SettingsItem::Arr => format!("ARR: < {}ms >", self.config.arr),
+                    SettingsItem::Ghost => format!(
+                        "Ghost piece: < {} >",
+                        if self.config.ghost { "on" } else { "off" }
+                    ),
+                    SettingsItem::Preview => {
+                        format!("Next pieces: < {} >", self.config.preview_count)
+                    }
+                    SettingsItem::Randomizer => format!(
+                        "Randomizer: < {} >",
+                        self.config
+                            .randomizer
+                            .to_possible_value()
+                            .unwrap()
+                            .get_name()
+                    ),
+                    SettingsItem::GarbageMessiness => {
+                        format!("Garbage messiness: < {}% >", self.config.garbage_messiness)
+                    }
+                    SettingsItem::BestOf => {
+                        format!("2-player series: < best of {} >", self.config.best_of)
+                    }
+                    SettingsItem::Name => format!("Name: {}", self.config.default_name),
+                    SettingsItem::Modes => "Game modes...".to_string(),
+                    SettingsItem::Controls => "Controls...".to_string(),
+                    SettingsItem::Back => "Save and go back".to_string(),
+                })
+                .collect();
+            self.render_list(stdout, "SETTINGS", &labels, selected)?;
+
+            let code = self.read_key()?;
+            let forward = match code {
+                KeyCode::Right | KeyCode::Char('l') | KeyCode::Enter => true,
+                KeyCode::Left | KeyCode::Char('h') => false,
+                KeyCode::Up | KeyCode::Char('k') => {
+                    selected = (selected + SETTINGS_ITEMS.len() - 1) % SETTINGS_ITEMS.len();
+                    continue;
+                }
+                KeyCode::Down | KeyCode::Char('j') => {
+                    selected = (selected + 1) % SETTINGS_ITEMS.len();
+                    continue;
+                }
+                KeyCode::Esc | KeyCode::Char('q') => return self.config.save(),
+                _ => continue,
+            };
+
+            match SETTINGS_ITEMS[selected] {
+                SettingsItem::Level => {
+                    self.config.level = step(self.config.level, 1, MAX_LEVEL, forward);
+                }
+                SettingsItem::LinesFilled => {
+                    self.config.number_of_lines_already_filled = step(
+                        self.config.number_of_lines_already_filled,
+                        1,
+                        MAX_NUMBER_OF_LINES_ALREADY_FILLED,
+                        forward,
+                    );
+                }
+                SettingsItem::Theme => {
+                    self.config.theme = cycle(&themes, &self.config.theme, forward);
+                    // A broken user theme keeps the current colors for the menu,
+                    // the error is reported when the game starts
+                    if let Ok(theme) = Theme::load(&self.config.theme) {
+                        self.theme = theme.with_color_support(ColorSupport::detect());
+                        self.terminal.background = self.theme.background;
+                    }
+                }
+                SettingsItem::Glyphs => {
+                    self.config.glyphs =
+                        cycle(GlyphStyle::value_variants(), &self.config.glyphs, forward);
+                }
+                SettingsItem::Das => {
+                    self.config.das = step(self.config.das, DAS_STEP, MAX_DELAY, forward);
+                }
+                SettingsItem::Arr => {
+                    self.config.arr = step(self.config.arr, ARR_STEP, MAX_DELAY, forward);
+                }
+                SettingsItem::Ghost => self.config.ghost = !self.config.ghost,
+                SettingsItem::Preview => {
+                    let preview_count = step(
+                        self.config.preview_count,
+                        1,
+                        max_preview_count(self.config),
+                        forward,
+                    );
+                    self.config.preview_count = preview_count.max(1);
+                }
+                SettingsItem::Randomizer => {
+                    self.config.randomizer = cycle(
+                        Randomizer::value_variants(),
+                        &self.config.randomizer,
+                        forward,
+                    );
+                }
+                SettingsItem::GarbageMessiness => {
+                    self.config.garbage_messiness = step(
+                        self.config.garbage_messiness,
+                        GARBAGE_MESSINESS_STEP,
+                        MAX_GARBAGE_MESSINESS,
+                        forward,
+                    );
+                }
+                SettingsItem::BestOf => {
+                    // Series are always of an odd number of rounds
+                    self.config.best_of = step(self.config.best_of, 2, MAX_BEST_OF, forward).max(1);
+                }
+                SettingsItem::Name => {
+                    if code == KeyCode::Enter {
+                        let name = self.read_text(
+                            stdout,
+                            "SETTINGS",
+                            "Default name:",
+                            self.config.default_name.clone(),
+                            MAX_NAME_LENGTH + 1,
+                        )?;
+                        if let Some(name) = name {
+                            self.config.default_name = name;
+                        }
+                    }
+                }
+                SettingsItem::Modes => {
+                    if code == KeyCode::Enter {
+                        self.show_mode_settings(stdout)?;
+                    }
+                }
+                SettingsItem::Controls => {
+                    if code == KeyCode::Enter {
+                        self.show_controls(stdout)?;
+                    }
+                }
+                SettingsItem::Back => {
+                    if code == KeyCode::Enter {
+                        return self.config.save();
+                    }
+                }
+            }
+        }
+    }
+
+    /// The options of the modes other than Marathon, saved with the other settings.
+    fn show_mode_settings(&mut self, stdout: &mut io::Stdout) -> Result<()> {
+        let mut selected = 0;
+        loop {
+            let labels: Vec<String> = MODE_ITEMS
+                .iter()
+                .map(|item| match item {
+                    ModeItem::SprintLines => {
+                        format!("Sprint lines: < {} >", self.config.sprint_lines)
+                    }
+                    ModeItem::UltraSeconds => {
+                        format!("Ultra time: < {}s >", self.config.ultra_seconds)
+                    }
+                    ModeItem::DigLines => format!("Dig lines: < {} >", self.config.dig_lines),
+                    ModeItem::DigGarbage => format!(
+                        "Dig garbage: < {} >",
+                        self.config
+                            .dig_garbage
+                            .to_possible_value()
+                            .unwrap()
+                            .get_name()
+                    ),
+                    ModeItem::DigRise => match self.config.dig_rise_seconds {
+                        0 => "Dig rising: < off >".to_string(),
+                        seconds => format!("Dig rising: < {}s >", seconds),
+                    },
+                    ModeItem::ZenGravity => format!(
+                        "Zen gravity: < {} >",
+                        if self.config.zen_gravity { "on" } else { "off" }
+                    ),
+                    ModeItem::ZenTopOut => format!(
+                        "Zen top out: < {} >",
+                        self.config
+                            .zen_top_out
+                            .to_possible_value()
+                            .unwrap()
+                            .get_name()
+                    ),
+                    ModeItem::InvisibleFade => match self.config.invisible_fade_seconds {
+                        0 => "Invisible fade: < at once >".to_string(),
+                        seconds => format!("Invisible fade: < {}s >", seconds),
+                    },
+                    ModeItem::Back => "Back".to_string(),
+                })
+                .collect();
+            self.render_list(stdout, "GAME MODES", &labels, selected)?;
+
+            let code = self.read_key()?;
+            let forward = match code {
+                KeyCode::Right | KeyCode::Char('l') | KeyCode::Enter => true,
+                KeyCode::Left | KeyCode::Char('h') => false,
+                KeyCode::Up | KeyCode::Char('k') => {
+                    selected = (selected + MODE_ITEMS.len() - 1) % MODE_ITEMS.len();
+                    continue;
+                }
+                KeyCode::Down | KeyCode::Char('j') => {
+                    selected = (selected + 1) % MODE_ITEMS.len();
+                    continue;
+                }
+                KeyCode::Esc | KeyCode::Char('q') => return Ok(()),
+                _ => continue,
+            };
+
+            match MODE_ITEMS[selected] {
+                ModeItem::SprintLines => {
+                    let sprint_lines = step(
+                        self.config.sprint_lines,
+                        SPRINT_LINES_STEP,
+                        MAX_SPRINT_LINES,
+                        forward,
+                    );
+                    self.config.sprint_lines = sprint_lines.max(SPRINT_LINES_STEP);
+                }
+                ModeItem::UltraSeconds => {
+                    let ultra_seconds = step(
+                        self.config.ultra_seconds,
+                        ULTRA_SECONDS_STEP,
+                        MAX_ULTRA_SECONDS,
+                        forward,
+                    );
+                    self.config.ultra_seconds = ultra_seconds.max(ULTRA_SECONDS_STEP);
+                }
+                ModeItem::DigLines => {
+                    let dig_lines = step(
+                        self.config.dig_lines,
+                        DIG_LINES_STEP,
+                        MAX_DIG_LINES,
+                        forward,
+                    );
+                    self.config.dig_lines = dig_lines.max(DIG_LINES_STEP);
+                }
+                ModeItem::DigGarbage => {
+                    self.config.dig_garbage = cycle(
+                        GarbageStyle::value_variants(),
+                        &self.config.dig_garbage,
+                        forward,
+                    );
+                }
+                ModeItem::DigRise => {
+                    self.config.dig_rise_seconds = step(
+                        self.config.dig_rise_seconds,
+                        DIG_RISE_STEP,
+                        MAX_DIG_RISE_SECONDS,
+                        forward,
+                    );
+                }
+                ModeItem::ZenGravity => self.config.zen_gravity = !self.config.zen_gravity,
+                ModeItem::ZenTopOut => {
+                    self.config.zen_top_out =
+                        cycle(TopOut::value_variants(), &self.config.zen_top_out, forward);
+                }
+                ModeItem::InvisibleFade => {
+                    self.config.invisible_fade_seconds = step(
+                        self.config.invisible_fade_seconds,
+                        INVISIBLE_FADE_STEP,
+                        MAX_INVISIBLE_FADE_SECONDS,
+                        forward,
+                    );
+                }
+                ModeItem::Back => {
+                    if code == KeyCode::Enter {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Rebinds actions one at a time. A new key replaces all previous keys
+    /// of the action and is refused if it conflicts with another binding.
+    fn show_controls(&mut self, stdout: &mut io::Stdout) -> Result<()> {
+        // The actions, then "Reset to defaults" and "Back"
+        let count = ACTIONS.len() + 2;
+
+        let mut selected = 0;
+        let mut error: Option<String> = None;
+        loop {
+            let mut labels: Vec<String> = ACTIONS
+                .iter()
+                .map(|action| {
+                    let keys: Vec<String> = self
+                        .config
+                        .keys
+                        .keys(*action)
+                        .iter()
+                        .map(keys::key_name)
+                        .collect();
+                    format!("{}: {}", action.label(), keys.join(", "))
+                })
+                .collect();
+            labels.extend(["Reset to defaults".to_string(), "Back".to_string()]);
+            if let Some(error) = error.take() {
+                labels.extend(["".to_string(), error]);
+            }
+            self.render_list(stdout, "CONTROLS", &labels, selected)?;
+
+            match self.read_key()? {
+                KeyCode::Up | KeyCode::Char('k') => selected = (selected + count - 1) % count,
+                KeyCode::Down | KeyCode::Char('j') => selected = (selected + 1) % count,
+                KeyCode::Esc | KeyCode::Char('q') => return Ok(()),
+                KeyCode::Enter if selected == ACTIONS.len() => {
+                    self.config.keys = KeyBindings::default();
+                }
+                KeyCode::Enter if selected == ACTIONS.len() + 1 => return Ok(()),
+                KeyCode::Enter => {
+                    let action = ACTIONS[selected];
+                    self.terminal.clear()?;
+                    print_centered_messages(
+                        &self.terminal,
+                        self.theme.foreground,
+                        stdout,
+                        Some(MENU_WIDTH),
+                        vec![
+                            "CONTROLS",
+                            "",
+                            &format!("Press a key for {}", action.label()),
+                            "",
+                            "Esc: cancel",
+                        ],
+                    )?;
+
+                    let code = self.read_key()?;
+                    if code == KeyCode::Esc {
+                        continue;
+                    }
+                    if !keys::can_be_saved(&code) {
+                        error = Some("This key can't be saved".to_string());
+                        continue;
+                    }
+
+                    let mut bindings = self.config.keys.clone();
+                    bindings.set(action, vec![code]);
+                    match bindings.validate() {
+                        Ok(()) => self.config.keys = bindings,
+                        Err(_) => {
+                            error = Some(format!("Key {} already in use", keys::key_name(&code)))
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn show_high_scores(&self, stdout: &mut io::Stdout) -> Result<()> {
+        let players = leaderboard(self.highscore_repo, self.args.mode)?;
+
+        let mut lines: Vec<String> = vec![format!("HIGH SCORES - {}", self.args.mode), "".into()];
+        if players.is_empty() {
+            lines.push("No high scores yet".to_string());
+        }
+        lines.extend(players);
+        lines.extend(["".to_string(), "Enter: back".to_string()]);
+
+        self.terminal.clear()?;
+        print_centered_messages(
+            &self.terminal,
+            self.theme.foreground,
+            stdout,
+            Some(MENU_WIDTH),
+            lines.iter().map(|line| line.as_str()).collect(),
+        )?;
+
+        loop {
+            if let KeyCode::Enter | KeyCode::Esc | KeyCode::Char('q') = self.read_key()? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Shows today's challenge and its ranking. Returns whether to play it.
+    fn show_daily(&self, stdout: &mut io::Stdout) -> Result<bool> {
+        let daily = Daily::today();
+        let players = daily_ranking(self.highscore_repo, &daily)?;
+
+        let mut lines: Vec<String> = vec![
+            "DAILY CHALLENGE".into(),
+            format!("{} - {}", daily.day, daily.mode),
+            "".into(),
+        ];
+        if players.is_empty() {
+            lines.push("Nobody has played today yet".to_string());
+        }
+        lines.extend(players);
+        lines.extend(["".to_string(), "Enter: play | Esc: back".to_string()]);
+
+        self.terminal.clear()?;
+        print_centered_messages(
+            &self.terminal,
+            self.theme.foreground,
+            stdout,
+            Some(MENU_WIDTH),
+            lines.iter().map(|line| line.as_str()).collect(),
+        )?;
+
+        loop {
+            match self.read_key()? {
+                KeyCode::Enter => return Ok(true),
+                KeyCode::Esc | KeyCode::Char('q') => return Ok(false),
+                _ => {}
+            }
+        }
+    }
+
+    /// Lists the puzzles of all packs, solved ones are checked.
+    /// Returns the id of the chosen puzzle, or `None` to go back.
+    fn show_puzzles(&self, stdout: &mut io::Stdout) -> Result<Option<String>> {
+        let puzzles = puzzle::load_puzzles()?;
+        let solved = self.highscore_repo.get_solved_puzzles()?;
+        let title = format!(
+            "PUZZLES - {}/{} solved",
+            puzzles
+                .iter()
+                .filter(|puzzle| solved.contains(&puzzle.id()))
+                .count(),
+            puzzles.len()
+        );
+
+        // Long lists scroll a page at a time
+        let mut selected = 0;
+        loop {
+            let page = selected / PUZZLES_PER_PAGE * PUZZLES_PER_PAGE;
+            let labels: Vec<String> = puzzles
+                .iter()
+                .skip(page)
+                .take(PUZZLES_PER_PAGE)
+                .map(|puzzle| {
+                    let check = if solved.contains(&puzzle.id()) {
+                        "[x]"
+                    } else {
+                        "[ ]"
+                    };
+                    let label = format!("{} {}", check, puzzle.id());
+                    label.chars().take(MENU_WIDTH - 6).collect()
+                })
+                .collect();
+            self.render_list(stdout, &title, &labels, selected - page)?;
+
+            match self.read_key()? {
+                KeyCode::Up | KeyCode::Char('k') => {
+                    selected = (selected + puzzles.len() - 1) % puzzles.len();
+                }
+                KeyCode::Down | KeyCode::Char('j') => {
+                    selected = (selected + 1) % puzzles.len();
+                }
+                KeyCode::Enter => return Ok(Some(puzzles[selected].id())),
+                KeyCode::Esc | KeyCode::Char('q') => return Ok(None),
+                _ => {}
+            }
+        }
+    }
+
+    /// Lets the player type a line of text. `max_length` includes the cursor.
+    fn read_text(
+        &self,
+        stdout: &mut io::Stdout,
+        title: &str,
+        prompt: &str,
+        initial: String,
+        max_length: usize,
+    ) -> Result<Option<String>> {
+        let mut text = initial;
+
+        loop {
+            let field = format!("{:<width$}", text.clone() + "_", width = max_length);
+            self.terminal.clear()?;
+            print_centered_messages(
+                &self.terminal,
+                self.theme.foreground,
+                stdout,
+                Some(MENU_WIDTH),
+                vec![title, "", prompt, &field, "", "Enter: OK | Esc: back"],
+            )?;
+
+            match self.read_key()? {
+                KeyCode::Enter if !text.is_empty() => return Ok(Some(text)),
+                KeyCode::Esc => return Ok(None),
+                KeyCode::Backspace => {
+                    text.pop();
+                }
+                KeyCode::Char(c) if text.len() < max_length - 1 => {
+                    text.push(c);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn read_key(&self) -> Result<KeyCode> {
+        loop {
+            if self.terminal.poll_event(Duration::from_millis(10))? {
+                if let Event::Key(KeyEvent {
+                    code,
+                    kind: KeyEventKind::Press,
+                    ..
+                }) = self.terminal.read_event()?
+                {
+                    return Ok(code);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the value after (or before) `current`, wrapping around.
+fn cycle<T: Clone + PartialEq>(values: &[T], current: &T, forward: bool) -> T {
+    let index = values
+        .iter()
+        .position(|value| value == current)
+        .unwrap_or(0);
+    let next = if forward {
+        (index + 1) % values.len()
+    } else {
+        (index + values.len() - 1) % values.len()
+    };
+
+    values[next].clone()
+}
+
+fn step<T>(value: T, by: T, max: T, forward: bool) -> T
+where
+    T: Copy + Default + Ord + std::ops::Add<Output = T> + std::ops::Sub<Output = T>,
+{
+    if forward {
+        (value + by).min(max)
+    } else if value > by {
+        value - by
+    } else {
+        T::default()
+    }
+}
+
+/// The largest number of next pieces that still fits in the terminal.
+fn max_preview_count(config: &Config) -> usize {
+    let (_, term_height) = terminal::size().unwrap_or((0, u16::MAX));
+    let mut config = config.clone();
+
+    (1..=MAX_PREVIEW_COUNT)
+        .rev()
+        .find(|&preview_count| {
+            config.preview_count = preview_count;
+            required_size(&config).1 <= term_height as usize
+        })
+        .unwrap_or(1)
+}
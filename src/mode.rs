@@ -0,0 +1,202 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum GameMode {
+    /// Endless game, the level goes up every 20 lines
+    #[default]
+    Marathon,
+    /// Clear 40 lines (see --sprint-lines) as fast as possible
+    Sprint,
+    /// Score as much as possible in 2 minutes (see --ultra-seconds)
+    Ultra,
+    /// Clear 10 garbage lines (see --dig-lines) as fast as possible
+    Dig,
+    /// No game over: topping out clears the board and the stats keep adding up
+    Zen,
+    /// Reach level 999 while the pieces speed up to falling instantly, graded at the end
+    Master,
+    /// Marathon where the stack is hidden once locked (see --invisible-fade-seconds)
+    Invisible,
+    /// Reach the goal of a puzzle with the pieces it gives (see --puzzle)
+    Puzzle,
+    /// Garbage rises faster and faster, last as long as possible
+    Survival,
+    /// Practice without gravity: undo moves, choose the next piece and edit the board
+    Sandbox,
+}
+
+impl GameMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            GameMode::Marathon => "Marathon",
+            GameMode::Sprint => "Sprint",
+            GameMode::Ultra => "Ultra",
+            GameMode::Dig => "Dig",
+            GameMode::Zen => "Zen",
+            GameMode::Master => "Master",
+            GameMode::Invisible => "Invisible",
+            GameMode::Puzzle => "Puzzle",
+            GameMode::Survival => "Survival",
+            GameMode::Sandbox => "Sandbox",
+        }
+    }
+
+    /// The key used for this mode in the records table.
+    pub fn id(&self) -> &'static str {
+        match self {
+            GameMode::Marathon => "marathon",
+            GameMode::Sprint => "sprint",
+            GameMode::Ultra => "ultra",
+            GameMode::Dig => "dig",
+            GameMode::Zen => "zen",
+            GameMode::Master => "master",
+            GameMode::Invisible => "invisible",
+            GameMode::Puzzle => "puzzle",
+            GameMode::Survival => "survival",
+            GameMode::Sandbox => "sandbox",
+        }
+    }
+
+    /// Whether the leaderboard keeps the fastest times instead of the highest scores.
+    pub fn ranks_by_time(&self) -> bool {
+        matches!(self, GameMode::Sprint | GameMode::Dig)
+    }
+
+    /// Whether the Stats panel shows a running clock.
+    pub fn has_clock(&self) -> bool {
+        matches!(
+            self,
+            GameMode::Sprint
+                | GameMode::Ultra
+                | GameMode::Dig
+                | GameMode::Master
+                | GameMode::Survival
+        )
+    }
+}
+
+/// What Zen mode does when the stack reaches the top.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum TopOut {
+    /// Empty the whole board
+    #[default]
+    Clear,
+    /// Only remove the top half of the board
+    Trim,
+}
+
+impl fmt::Display for GameMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Measures the playing time, leaving out the time spent paused.
+pub struct Stopwatch {
+    started_at: Option<Instant>,
+    elapsed: Duration,
+}
+
+impl Stopwatch {
+    pub fn start() -> Self {
+        Stopwatch {
+            started_at: Some(Instant::now()),
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    pub fn pause(&mut self) {
+        if let Some(started_at) = self.started_at.take() {
+            self.elapsed += started_at.elapsed();
+        }
+    }
+
+    pub fn resume(&mut self) {
+        if self.started_at.is_none() {
+            self.started_at = Some(Instant::now());
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        match self.started_at {
+            Some(started_at) => self.elapsed + started_at.elapsed(),
+            None => self.elapsed,
+        }
+    }
+}
+
+/// Formats a duration as `m:ss.mmm`.
+pub fn format_time(time_ms: u64) -> String {
+    format!(
+        "{}:{:02}.{:03}",
+        time_ms / 60_000,
+        time_ms / 1000 % 60,
+        time_ms % 1000
+    )
+}
+
+/// Sprint keeps the time every this many lines.
+pub const SPLIT_LINES: usize = 10;
+
+/// Adds the time of every multiple of `SPLIT_LINES` reached with `lines`.
+/// A clear across several of them gives them all the same time.
+pub fn add_splits(splits: &mut Vec<u64>, lines: usize, time_ms: u64) {
+    while (splits.len() + 1) * SPLIT_LINES <= lines {
+        splits.push(time_ms);
+    }
+}
+
+/// Formats how much slower (`+`) or faster (`-`) a split was, as `+s.mmm`.
+pub fn format_delta(delta_ms: i64) -> String {
+    let sign = if delta_ms < 0 { '-' } else { '+' };
+    let delta_ms = delta_ms.unsigned_abs();
+    format!("{}{}.{:03}", sign, delta_ms / 1000, delta_ms % 1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_times() {
+        assert_eq!(format_time(0), "0:00.000");
+        assert_eq!(format_time(7), "0:00.007");
+        assert_eq!(format_time(59_999), "0:59.999");
+        assert_eq!(format_time(61_234), "1:01.234");
+        assert_eq!(format_time(600_000), "10:00.000");
+    }
+
+    #[test]
+    fn formats_deltas() {
+        assert_eq!(format_delta(0), "+0.000");
+        assert_eq!(format_delta(1_234), "+1.234");
+        assert_eq!(format_delta(-512), "-0.512");
+        assert_eq!(format_delta(-75_000), "-75.000");
+    }
+
+    #[test]
+    fn adds_a_split_every_ten_lines() {
+        let mut splits = Vec::new();
+        add_splits(&mut splits, 9, 1_000);
+        assert!(splits.is_empty());
+        add_splits(&mut splits, 10, 2_000);
+        add_splits(&mut splits, 12, 3_000);
+        assert_eq!(splits, [2_000]);
+
+        // A tetris from 18 to 22 lines, then a double from 29 to 31
+        add_splits(&mut splits, 22, 4_000);
+        add_splits(&mut splits, 29, 5_000);
+        add_splits(&mut splits, 31, 6_000);
+        assert_eq!(splits, [2_000, 4_000, 6_000]);
+    }
+
+    #[test]
+    fn adds_every_split_skipped_at_once() {
+        let mut splits = Vec::new();
+        add_splits(&mut splits, 30, 1_000);
+        assert_eq!(splits, [1_000, 1_000, 1_000]);
+    }
+}
@@ -1,42 +1,813 @@
-use std::io::{Read, Write};
-use std::net::TcpStream;
-use std::sync::mpsc::Sender;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
 
+use local_ip_address::{local_ip, local_ipv6};
+
+use crate::config::{Config, MAX_BEST_OF};
+use crate::randomizer::Randomizer;
+use crate::{CellKind, GameError, MAX_LEVEL, MAX_NUMBER_OF_LINES_ALREADY_FILLED};
+
+/// Changes whenever the encoding of a message changes. Frames of another
+/// version are refused rather than misread, except for `Hello` and `Reject`
+/// whose encoding never changes, so that the handshake can tell players apart.
+/// `Watch` is read the same way for spectators.
+pub const PROTOCOL_VERSION: u8 = 9;
+
+/// The port a game is hosted on unless told otherwise.
+pub const DEFAULT_PORT: u16 = 8080;
+
+// How long each side waits for the other one during the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Each side tells the other it is still there this often
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// The other player is gone when nothing came from them for this long.
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
+// A player who lost the host tries to connect again this often
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+// A frame is the length of the rest of the frame (4 bytes, big-endian),
+// the protocol version, the type of the message and its body
+const LENGTH_SIZE: usize = 4;
+const HEADER_SIZE: usize = 2;
+// No message comes close to this, a longer frame means the stream is corrupt
+const MAX_FRAME_LENGTH: usize = 64 * 1024;
+
+const TAG_GARBAGE: u8 = 1;
+const TAG_NOTIFICATION: u8 = 2;
+const TAG_HELLO: u8 = 3;
+const TAG_WELCOME: u8 = 4;
+const TAG_REJECT: u8 = 5;
+const TAG_BOARD: u8 = 6;
+const TAG_LOBBY: u8 = 7;
+const TAG_TARGET: u8 = 8;
+const TAG_WATCH: u8 = 9;
+const TAG_VIEW: u8 = 10;
+const TAG_HEARTBEAT: u8 = 11;
+const TAG_READY: u8 = 12;
+const TAG_REMATCH: u8 = 13;
+
+// Cells of a board are sent as their index in this array
+const CELL_KINDS: [CellKind; 9] = [
+    CellKind::Empty,
+    CellKind::I,
+    CellKind::O,
+    CellKind::T,
+    CellKind::S,
+    CellKind::Z,
+    CellKind::J,
+    CellKind::L,
+    CellKind::Garbage,
+];
+
+/// The rules of a 2-player game. The host chooses them, so that both players
+/// start from the same level and garbage and get the same pieces.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rules {
+    pub level: usize,
+    pub lines_filled: usize,
+    /// Decides the pieces and the garbage lines already filled
+    pub seed: u64,
+    pub randomizer: Randomizer,
+    pub garbage_messiness: u8,
+    /// The number of rounds of a series, the first player to win most of them wins it
+    pub best_of: u8,
+}
+
+impl Rules {
+    /// The rules of the host's settings, with a new seed.
+    pub fn from_config(config: &Config) -> Self {
+        Rules {
+            level: config.level,
+            lines_filled: config.number_of_lines_already_filled,
+            seed: rand::random(),
+            randomizer: config.randomizer,
+            garbage_messiness: config.garbage_messiness,
+            best_of: config.best_of,
+        }
+    }
+
+    pub fn apply(&self, config: &mut Config) {
+        config.level = self.level;
+        config.number_of_lines_already_filled = self.lines_filled;
+        config.randomizer = self.randomizer;
+        config.garbage_messiness = self.garbage_messiness;
+        config.best_of = self.best_of;
+    }
+
+    /// Whether the rules are within the limits of the settings, which a host
+    /// of another build or a broken one may not respect.
+    fn is_valid(&self) -> bool {
+        self.level <= MAX_LEVEL
+            && self.lines_filled <= MAX_NUMBER_OF_LINES_ALREADY_FILLED
+            && !self.best_of.is_multiple_of(2)
+            && self.best_of <= MAX_BEST_OF
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum MessageType {
-    ClearedRows(usize),
+    /// Garbage lines sent by clearing rows, after offsetting the garbage received
+    Garbage(usize),
     Notification(String),
+    /// The first message of a player joining a game
+    Hello {
+        version: u8,
+        name: String,
+    },
+    /// The host's answer to a `Hello` when the game can start
+    Welcome {
+        name: String,
+        rules: Rules,
+    },
+    /// The host's answer to a `Hello` when the game can't start, and why
+    Reject(String),
+    /// The board of the other player, with their current piece, row by row
+    Board {
+        score: usize,
+        rows: Vec<Vec<CellKind>>,
+    },
+    /// Sent by a battle server to the players waiting for the battle to start
+    Lobby {
+        joined: usize,
+        players: usize,
+    },
+    /// Sent by a battle server to tell a player who their attacks go to
+    Target(String),
+    /// The first message of a spectator, who is welcomed like a player
+    Watch {
+        version: u8,
+    },
+    /// What spectators see of a player. The player has left when there are no rows.
+    View {
+        player: usize,
+        name: String,
+        score: usize,
+        wins: usize,
+        rows: Vec<Vec<CellKind>>,
+    },
+    /// Sent every second, so that a lost connection is noticed even when nothing happens
+    Heartbeat,
+    /// The player is ready for the next round, which starts once both are
+    Ready,
+    /// The vote of the player for playing another series once one is over
+    Rematch(bool),
+}
+
+impl MessageType {
+    /// The whole frame of the message, ready to be written to the stream.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut version = PROTOCOL_VERSION;
+        let (tag, body) = match self {
+            MessageType::Garbage(lines) => (TAG_GARBAGE, (*lines as u32).to_be_bytes().to_vec()),
+            MessageType::Notification(msg) => (TAG_NOTIFICATION, msg.as_bytes().to_vec()),
+            MessageType::Hello {
+                version: hello_version,
+                name,
+            } => {
+                version = *hello_version;
+                (TAG_HELLO, name.as_bytes().to_vec())
+            }
+            MessageType::Welcome { name, rules } => {
+                let mut body = Vec::new();
+                body.extend((rules.level as u32).to_be_bytes());
+                body.extend((rules.lines_filled as u32).to_be_bytes());
+                body.extend(rules.seed.to_be_bytes());
+                body.push(match rules.randomizer {
+                    Randomizer::Random => 0,
+                    Randomizer::Bag => 1,
+                });
+                body.push(rules.garbage_messiness);
+                body.push(rules.best_of);
+                body.extend(name.as_bytes());
+                (TAG_WELCOME, body)
+            }
+            MessageType::Reject(reason) => (TAG_REJECT, reason.as_bytes().to_vec()),
+            MessageType::Board { score, rows } => {
+                let mut body = (*score as u32).to_be_bytes().to_vec();
+                encode_board(rows, &mut body);
+                (TAG_BOARD, body)
+            }
+            MessageType::Lobby { joined, players } => {
+                let mut body = (*joined as u32).to_be_bytes().to_vec();
+                body.extend((*players as u32).to_be_bytes());
+                (TAG_LOBBY, body)
+            }
+            MessageType::Target(name) => (TAG_TARGET, name.as_bytes().to_vec()),
+            MessageType::Watch {
+                version: watch_version,
+            } => {
+                version = *watch_version;
+                (TAG_WATCH, Vec::new())
+            }
+            MessageType::View {
+                player,
+                name,
+                score,
+                wins,
+                rows,
+            } => {
+                let mut body = Vec::new();
+                for number in [player, score, wins] {
+                    body.extend((*number as u32).to_be_bytes());
+                }
+                let name = short_name(name);
+                body.push(name.len() as u8);
+                body.extend(name.as_bytes());
+                encode_board(rows, &mut body);
+                (TAG_VIEW, body)
+            }
+            MessageType::Heartbeat => (TAG_HEARTBEAT, Vec::new()),
+            MessageType::Ready => (TAG_READY, Vec::new()),
+            MessageType::Rematch(vote) => (TAG_REMATCH, vec![*vote as u8]),
+        };
+
+        let length = (HEADER_SIZE + body.len()) as u32;
+        let mut frame = Vec::with_capacity(LENGTH_SIZE + length as usize);
+        frame.extend(length.to_be_bytes());
+        frame.extend([version, tag]);
+        frame.extend(body);
+        frame
+    }
+
+    /// Reads a frame without its length.
+    fn decode(frame: &[u8]) -> Result<MessageType, ProtocolError> {
+        let (version, tag, body) = match frame {
+            [version, tag, body @ ..] => (*version, *tag, body),
+            _ => return Err(ProtocolError::Truncated),
+        };
+
+        // Whatever their version, players must understand why they can't play together
+        match tag {
+            TAG_HELLO => {
+                return Ok(MessageType::Hello {
+                    version,
+                    name: text(body)?,
+                })
+            }
+            TAG_REJECT => return Ok(MessageType::Reject(text(body)?)),
+            TAG_WATCH => return Ok(MessageType::Watch { version }),
+            _ => {}
+        }
+        if version != PROTOCOL_VERSION {
+            return Err(ProtocolError::UnsupportedVersion(version));
+        }
+
+        match tag {
+            TAG_GARBAGE => {
+                let lines: [u8; 4] = body.try_into().map_err(|_| ProtocolError::Truncated)?;
+                Ok(MessageType::Garbage(u32::from_be_bytes(lines) as usize))
+            }
+            TAG_NOTIFICATION => Ok(MessageType::Notification(text(body)?)),
+            TAG_WELCOME => {
+                let (numbers, name) = body.split_at_checked(19).ok_or(ProtocolError::Truncated)?;
+                let number = |range: std::ops::Range<usize>| {
+                    numbers[range]
+                        .iter()
+                        .fold(0u64, |number, byte| number << 8 | *byte as u64)
+                };
+                let randomizer = match numbers[16] {
+                    0 => Randomizer::Random,
+                    1 => Randomizer::Bag,
+                    _ => return Err(ProtocolError::InvalidRules),
+                };
+                let rules = Rules {
+                    level: number(0..4) as usize,
+                    lines_filled: number(4..8) as usize,
+                    seed: number(8..16),
+                    randomizer,
+                    garbage_messiness: numbers[17].min(100),
+                    best_of: numbers[18],
+                };
+                if !rules.is_valid() {
+                    return Err(ProtocolError::InvalidRules);
+                }
+                Ok(MessageType::Welcome {
+                    name: text(name)?,
+                    rules,
+                })
+            }
+            TAG_BOARD => {
+                let (score, board) = body.split_at_checked(4).ok_or(ProtocolError::Truncated)?;
+                Ok(MessageType::Board {
+                    score: number(score) as usize,
+                    rows: decode_board(board)?,
+                })
+            }
+            TAG_LOBBY => {
+                let numbers: [u8; 8] = body.try_into().map_err(|_| ProtocolError::Truncated)?;
+                Ok(MessageType::Lobby {
+                    joined: number(&numbers[0..4]) as usize,
+                    players: number(&numbers[4..8]) as usize,
+                })
+            }
+            TAG_TARGET => Ok(MessageType::Target(text(body)?)),
+            TAG_HEARTBEAT => Ok(MessageType::Heartbeat),
+            TAG_READY => Ok(MessageType::Ready),
+            TAG_REMATCH => match body {
+                [vote] => Ok(MessageType::Rematch(*vote != 0)),
+                _ => Err(ProtocolError::Truncated),
+            },
+            TAG_VIEW => {
+                let (numbers, rest) = body.split_at_checked(13).ok_or(ProtocolError::Truncated)?;
+                let (name, board) = rest
+                    .split_at_checked(numbers[12] as usize)
+                    .ok_or(ProtocolError::Truncated)?;
+                Ok(MessageType::View {
+                    player: number(&numbers[0..4]) as usize,
+                    name: text(name)?,
+                    score: number(&numbers[4..8]) as usize,
+                    wins: number(&numbers[8..12]) as usize,
+                    rows: decode_board(board)?,
+                })
+            }
+            other => Err(ProtocolError::UnknownMessage(other)),
+        }
+    }
+}
+
+/// The longest start of `name` whose length fits in a byte, cut between characters.
+fn short_name(name: &str) -> &str {
+    let mut end = name.len().min(u8::MAX as usize);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
+
+fn text(bytes: &[u8]) -> Result<String, ProtocolError> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| ProtocolError::InvalidText)
+}
+
+/// A big-endian u32 of 4 bytes.
+fn number(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Appends the width and height of a board, then the kind of each cell.
+fn encode_board(rows: &[Vec<CellKind>], body: &mut Vec<u8>) {
+    let width = rows.first().map_or(0, |row| row.len());
+    body.extend([width as u8, rows.len() as u8]);
+    body.extend(rows.iter().flatten().map(|kind| {
+        CELL_KINDS
+            .iter()
+            .position(|other| other == kind)
+            .unwrap_or(0) as u8
+    }));
+}
+
+fn decode_board(bytes: &[u8]) -> Result<Vec<Vec<CellKind>>, ProtocolError> {
+    let (width, height, cells) = match bytes {
+        [width, height, cells @ ..] => (*width as usize, *height as usize, cells),
+        _ => return Err(ProtocolError::Truncated),
+    };
+    if cells.len() != width * height {
+        return Err(ProtocolError::InvalidBoard);
+    }
+    let kinds = cells
+        .iter()
+        .map(|cell| CELL_KINDS.get(*cell as usize).copied())
+        .collect::<Option<Vec<CellKind>>>()
+        .ok_or(ProtocolError::InvalidBoard)?;
+
+    Ok(kinds.chunks(width.max(1)).map(|row| row.to_vec()).collect())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// The other player runs a version of the game that encodes messages differently
+    UnsupportedVersion(u8),
+    UnknownMessage(u8),
+    /// The frame is too short for its message
+    Truncated,
+    InvalidText,
+    InvalidRules,
+    InvalidBoard,
+    /// The length of a frame is out of bounds, nothing after it can be trusted
+    FrameTooLong(usize),
+}
+
+impl ProtocolError {
+    /// Whether the stream can't be read any further. Other errors only lose one message.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, ProtocolError::FrameTooLong(_))
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::UnsupportedVersion(version) => write!(
+                f,
+                "protocol version {} is not supported (expected {})",
+                version, PROTOCOL_VERSION
+            ),
+            ProtocolError::UnknownMessage(tag) => write!(f, "unknown message type {}", tag),
+            ProtocolError::Truncated => write!(f, "truncated message"),
+            ProtocolError::InvalidText => write!(f, "message text is not valid UTF-8"),
+            ProtocolError::InvalidRules => write!(f, "invalid game rules"),
+            ProtocolError::InvalidBoard => write!(f, "invalid board"),
+            ProtocolError::FrameTooLong(length) => write!(
+                f,
+                "frame of {} bytes is longer than {} bytes",
+                length, MAX_FRAME_LENGTH
+            ),
+        }
+    }
+}
+
+impl Error for ProtocolError {}
+
+/// Splits a byte stream into messages, however its bytes were cut into reads:
+/// a read can hold part of a message, or several of them.
+pub struct Decoder {
+    buffer: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder { buffer: Vec::new() }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// The next complete message, or `None` until more bytes are pushed.
+    /// A frame that can't be decoded is skipped after its error is returned.
+    pub fn next_message(&mut self) -> Result<Option<MessageType>, ProtocolError> {
+        let length = match self.buffer.get(..LENGTH_SIZE) {
+            Some(length) => u32::from_be_bytes(length.try_into().unwrap()) as usize,
+            None => return Ok(None),
+        };
+        if length > MAX_FRAME_LENGTH {
+            return Err(ProtocolError::FrameTooLong(length));
+        }
+        if self.buffer.len() < LENGTH_SIZE + length {
+            return Ok(None);
+        }
+
+        let frame: Vec<u8> = self.buffer.drain(..LENGTH_SIZE + length).collect();
+        MessageType::decode(&frame[LENGTH_SIZE..]).map(Some)
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder::new()
+    }
+}
+
+/// Starts hosting a game on `address`. A busy port or an address of another
+/// machine fails with a message saying how to host anyway.
+pub fn listen(address: SocketAddr) -> crate::Result<TcpListener> {
+    TcpListener::bind(address).map_err(|err| {
+        let message = match err.kind() {
+            io::ErrorKind::AddrInUse => format!(
+                "Port {} is already in use, host on another one with --port \
+                 (--port 0 picks a free one).",
+                address.port()
+            ),
+            io::ErrorKind::AddrNotAvailable => format!(
+                "{} is not an address of this machine, host on another one with --bind.",
+                address.ip()
+            ),
+            io::ErrorKind::PermissionDenied => format!(
+                "Not allowed to host on port {}, use a port above 1023 with --port.",
+                address.port()
+            ),
+            _ => format!("Can't host on {}: {}", address, err),
+        };
+        Box::new(GameError { message }).into()
+    })
+}
+
+/// The address the other player connects to. The port is the one actually
+/// chosen, and a game hosted on every interface is found at the local IP.
+pub fn invite_address(listener: &TcpListener) -> crate::Result<SocketAddr> {
+    let address = listener.local_addr()?;
+    let ip = match address.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => local_ip()?,
+        // Dual-stack sockets also accept IPv4 players
+        IpAddr::V6(ip) if ip.is_unspecified() => local_ipv6().or_else(|_| local_ip())?,
+        ip => ip,
+    };
+
+    Ok(SocketAddr::new(ip, address.port()))
+}
+
+/// Reads a single message, without reading any byte of the next one.
+fn read_message(stream: &mut impl Read) -> crate::Result<MessageType> {
+    let mut length = [0u8; LENGTH_SIZE];
+    stream.read_exact(&mut length)?;
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_FRAME_LENGTH {
+        return Err(Box::new(ProtocolError::FrameTooLong(length)));
+    }
+
+    let mut frame = vec![0u8; length];
+    stream.read_exact(&mut frame)?;
+    Ok(MessageType::decode(&frame)?)
+}
+
+/// Reads the message answering the handshake, which must come in time.
+fn read_handshake(stream: &mut TcpStream) -> crate::Result<MessageType> {
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let message = read_message(stream).map_err(|err| {
+        match err.downcast_ref::<io::Error>().map(|err| err.kind()) {
+            Some(io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => Box::new(GameError {
+                message: "The other player didn't answer in time, are you both running \
+                          the same version of tetris-tui?"
+                    .to_string(),
+            }),
+            _ => err,
+        }
+    })?;
+    stream.set_read_timeout(None)?;
+
+    Ok(message)
+}
+
+/// Who just connected to a host.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Guest {
+    /// A player, by their name
+    Player(String),
+    /// Someone who only watches the game
+    Spectator,
+}
+
+/// The host's side of the handshake: waits for the `Hello` of the player who just
+/// connected, or the `Watch` of a spectator, and welcomes them with the rules of
+/// the game. A guest of another version is rejected, which fails with the reason.
+pub fn welcome(stream: &mut TcpStream, name: &str, rules: &Rules) -> crate::Result<Guest> {
+    let guest = greet(stream)?;
+    let welcome = MessageType::Welcome {
+        name: name.to_string(),
+        rules: rules.clone(),
+    };
+    stream.write_all(&welcome.encode())?;
+
+    Ok(guest)
+}
+
+/// Waits for the `Hello` or `Watch` of the guest who just connected, and rejects
+/// them if they run another version. They are left waiting for their `Welcome`.
+pub fn greet(stream: &mut TcpStream) -> crate::Result<Guest> {
+    let (version, guest) = match read_handshake(stream)? {
+        MessageType::Hello { version, name } => (version, Guest::Player(name)),
+        MessageType::Watch { version } => (version, Guest::Spectator),
+        _ => {
+            return Err(Box::new(GameError {
+                message: "The other player didn't say hello.".to_string(),
+            }))
+        }
+    };
+    if version == PROTOCOL_VERSION {
+        return Ok(guest);
+    }
+
+    let reason = format!(
+        "The host plays with protocol version {} and you with version {}, \
+         please use the same version of tetris-tui.",
+        PROTOCOL_VERSION, version
+    );
+    stream.write_all(&MessageType::Reject(reason).encode())?;
+    let guest = match guest {
+        Guest::Player(name) => name,
+        Guest::Spectator => "a spectator".to_string(),
+    };
+    Err(Box::new(GameError {
+        message: format!(
+            "Rejected {}: protocol version {} instead of {}.",
+            guest, version, PROTOCOL_VERSION
+        ),
+    }))
+}
+
+/// Keeps welcoming spectators on `listener` once the game has started, and
+/// `competitor` when they connect again after losing the game. Other players
+/// are rejected.
+/// Returns the connections of `competitor`.
+pub fn accept_guests(
+    listener: TcpListener,
+    name: String,
+    rules: Rules,
+    competitor: String,
+    spectators: Spectators,
+) -> Receiver<TcpStream> {
+    let (sender, reconnections) = channel();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let (name, rules, competitor) = (name.clone(), rules.clone(), competitor.clone());
+            let (spectators, sender) = (spectators.clone(), sender.clone());
+            // A slow guest doesn't hold the others
+            thread::spawn(move || {
+                let Ok(guest) = greet(&mut stream) else {
+                    return;
+                };
+                let welcome = match &guest {
+                    Guest::Player(player) => *player == competitor,
+                    Guest::Spectator => true,
+                };
+                let answer = if welcome {
+                    MessageType::Welcome { name, rules }
+                } else {
+                    MessageType::Reject(
+                        "The game has already started, you can watch it with --spectate."
+                            .to_string(),
+                    )
+                };
+                if stream.write_all(&answer.encode()).is_err() || !welcome {
+                    return;
+                }
+
+                match guest {
+                    Guest::Spectator => spectators.add(stream),
+                    Guest::Player(_) => {
+                        let _ = sender.send(stream);
+                    }
+                }
+            });
+        }
+    });
+
+    reconnections
+}
+
+/// Connects again and again to the host at `address`, as `name`, until the
+/// host takes the player back.
+/// Returns the new connection, once there is one.
+pub fn reconnect(address: String, name: String) -> Receiver<TcpStream> {
+    let (sender, reconnection) = channel();
+    thread::spawn(move || loop {
+        thread::sleep(RECONNECT_INTERVAL);
+        let Ok(mut stream) = TcpStream::connect(&address) else {
+            continue;
+        };
+        let hello = MessageType::Hello {
+            version: PROTOCOL_VERSION,
+            name: name.clone(),
+        };
+        if stream.write_all(&hello.encode()).is_err() {
+            continue;
+        }
+        // The game goes on with the rules it started with
+        if let Ok(MessageType::Welcome { .. }) = read_handshake(&mut stream) {
+            let _ = sender.send(stream);
+            return;
+        }
+    });
+
+    reconnection
+}
+
+/// Sends a `Heartbeat` every second until the connection is closed.
+pub fn keep_alive(writer: Writer) {
+    thread::spawn(move || {
+        while writer.send(&MessageType::Heartbeat).is_ok() {
+            thread::sleep(HEARTBEAT_INTERVAL);
+        }
+    });
 }
 
-pub const PREFIX_CLEARED_ROWS: &str = "ClearedRows: ";
-pub const PREFIX_NOTIFICATION: &str = "Notification: ";
+/// Writes to a connection from several threads, e.g. the game and its heartbeats.
+/// Each message is written whole, so that the frames of two threads never mix.
+#[derive(Clone)]
+pub struct Writer {
+    stream: Arc<Mutex<TcpStream>>,
+}
+
+impl Writer {
+    pub fn new(stream: TcpStream) -> Self {
+        Writer {
+            stream: Arc::new(Mutex::new(stream)),
+        }
+    }
+
+    /// Fails when the connection is lost, which the heartbeats tell the game about.
+    pub fn send(&self, message: &MessageType) -> io::Result<()> {
+        self.lock().write_all(&message.encode())
+    }
+
+    /// Closes the connection, which also stops the threads reading it.
+    pub fn shutdown(&self) {
+        let _ = self.lock().shutdown(Shutdown::Both);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, TcpStream> {
+        self.stream.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The spectator's side of the handshake.
+/// Returns the name of the host.
+pub fn watch(stream: &mut TcpStream) -> crate::Result<String> {
+    let watch = MessageType::Watch {
+        version: PROTOCOL_VERSION,
+    };
+    stream.write_all(&watch.encode())?;
+
+    match read_handshake(stream)? {
+        MessageType::Welcome { name, .. } => Ok(name),
+        MessageType::Reject(reason) => Err(Box::new(GameError {
+            message: format!("The host refused to let you watch. {}", reason),
+        })),
+        _ => Err(Box::new(GameError {
+            message: "The host didn't answer the handshake.".to_string(),
+        })),
+    }
+}
 
-pub fn send_to_other_player(stream: &mut TcpStream, message: MessageType) {
-    let message_string = match message {
-        MessageType::ClearedRows(rows) => format!("{}{}", PREFIX_CLEARED_ROWS, rows),
-        MessageType::Notification(msg) => format!("{}{}", PREFIX_NOTIFICATION, msg),
+/// The connections watching a game, shared by the threads that welcome them
+/// and the one that plays.
+#[derive(Clone, Default)]
+pub struct Spectators {
+    streams: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl Spectators {
+    pub fn add(&self, stream: TcpStream) {
+        self.lock().push(stream);
+    }
+
+    /// Sends `message` to every spectator, and forgets those who have left.
+    pub fn send(&self, message: &MessageType) {
+        let frame = message.encode();
+        self.lock()
+            .retain_mut(|stream| stream.write_all(&frame).is_ok());
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<TcpStream>> {
+        self.streams.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The joining player's side of the handshake.
+/// Returns the name of the host and the rules of the game.
+pub fn join(stream: &mut TcpStream, name: &str) -> crate::Result<(String, Rules)> {
+    let hello = MessageType::Hello {
+        version: PROTOCOL_VERSION,
+        name: name.to_string(),
     };
+    stream.write_all(&hello.encode())?;
 
-    if let Err(err) = stream.write_all(message_string.as_bytes()) {
-        eprintln!("Error writing message: {}", err);
+    loop {
+        match read_handshake(stream)? {
+            MessageType::Welcome { name, rules } => return Ok((name, rules)),
+            MessageType::Reject(reason) => {
+                return Err(Box::new(GameError {
+                    message: format!("The host refused to start the game. {}", reason),
+                }))
+            }
+            // A battle server starts once enough players have joined
+            MessageType::Lobby { joined, players } => {
+                println!("Waiting for players: {}/{}.", joined, players);
+            }
+            MessageType::Heartbeat => {}
+            _ => {
+                return Err(Box::new(GameError {
+                    message: "The host didn't answer the handshake.".to_string(),
+                }))
+            }
+        }
     }
 }
 
-pub fn forward_to_main_thread(stream: &mut TcpStream, sender: Sender<MessageType>) {
-    let mut buffer = [0u8; 256];
+/// Reads the messages of the other player until the connection is closed.
+pub fn forward_to_main_thread(stream: &mut impl Read, sender: Sender<MessageType>) {
+    // Sending only fails once the game has stopped listening
+    read_messages(stream, |message| sender.send(message).is_ok());
+}
+
+/// Hands the messages read from `stream` to `handle` until the connection is
+/// closed, or until `handle` returns false. Messages that can't be read are
+/// skipped, and reading stops when the rest of the stream makes no sense.
+pub fn read_messages(stream: &mut impl Read, mut handle: impl FnMut(MessageType) -> bool) {
+    let mut decoder = Decoder::new();
+    let mut buffer = [0u8; 4096];
     loop {
         match stream.read(&mut buffer) {
             Ok(n) if n > 0 => {
-                let msg = String::from_utf8_lossy(&buffer[0..n]);
-                if msg.starts_with(PREFIX_CLEARED_ROWS) {
-                    if let Ok(rows) = msg.trim_start_matches(PREFIX_CLEARED_ROWS).parse() {
-                        if let Err(err) = sender.send(MessageType::ClearedRows(rows)) {
-                            eprintln!("Error sending number of cleared rows: {}", err)
+                decoder.push(&buffer[0..n]);
+                loop {
+                    match decoder.next_message() {
+                        Ok(Some(message)) => {
+                            if !handle(message) {
+                                return;
+                            }
                         }
-                    }
-                } else if msg.starts_with(PREFIX_NOTIFICATION) {
-                    let msg = msg.trim_start_matches(PREFIX_NOTIFICATION).to_string();
-                    if let Err(err) = sender.send(MessageType::Notification(msg)) {
-                        eprintln!("Error sending notification message: {}", err)
+                        Ok(None) => break,
+                        // Printing would garble the screen of the game, a lost
+                        // connection shows up there instead
+                        Err(err) if err.is_fatal() => return,
+                        Err(_) => {}
                     }
                 }
             }
@@ -46,3 +817,467 @@ pub fn forward_to_main_thread(stream: &mut TcpStream, sender: Sender<MessageType
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    fn decode_all(decoder: &mut Decoder) -> Vec<MessageType> {
+        let mut messages = Vec::new();
+        while let Some(message) = decoder.next_message().unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    // Hands out its bytes in reads of the given sizes
+    struct ChunkedReader {
+        bytes: Vec<u8>,
+        chunks: Vec<usize>,
+    }
+
+    impl Read for ChunkedReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.bytes.is_empty() {
+                return Ok(0);
+            }
+            let size = if self.chunks.is_empty() {
+                self.bytes.len()
+            } else {
+                self.chunks.remove(0)
+            };
+            let size = size.min(buf.len()).min(self.bytes.len());
+            buf[..size].copy_from_slice(&self.bytes[..size]);
+            self.bytes.drain(..size);
+            Ok(size)
+        }
+    }
+
+    #[test]
+    fn round_trips_every_message() {
+        let messages = vec![
+            MessageType::Garbage(4),
+            MessageType::Notification("YOU WIN!".to_string()),
+            MessageType::Notification(String::new()),
+        ];
+
+        let mut decoder = Decoder::new();
+        for message in messages.iter() {
+            decoder.push(&message.encode());
+        }
+
+        assert_eq!(decode_all(&mut decoder), messages);
+    }
+
+    #[test]
+    fn decodes_coalesced_messages() {
+        let mut bytes = MessageType::Garbage(2).encode();
+        bytes.extend(MessageType::Notification("YOU WIN!".to_string()).encode());
+        bytes.extend(MessageType::Garbage(1).encode());
+
+        let mut decoder = Decoder::new();
+        decoder.push(&bytes);
+
+        assert_eq!(
+            decode_all(&mut decoder),
+            vec![
+                MessageType::Garbage(2),
+                MessageType::Notification("YOU WIN!".to_string()),
+                MessageType::Garbage(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn waits_for_fragmented_messages() {
+        let bytes = MessageType::Notification("YOU WIN!".to_string()).encode();
+
+        let mut decoder = Decoder::new();
+        for byte in bytes[..bytes.len() - 1].iter() {
+            decoder.push(&[*byte]);
+            assert_eq!(decoder.next_message(), Ok(None));
+        }
+        decoder.push(&bytes[bytes.len() - 1..]);
+
+        assert_eq!(
+            decoder.next_message(),
+            Ok(Some(MessageType::Notification("YOU WIN!".to_string())))
+        );
+        assert_eq!(decoder.next_message(), Ok(None));
+    }
+
+    #[test]
+    fn decodes_messages_split_across_reads() {
+        // The second message starts in the middle of the first read
+        let mut bytes = MessageType::Garbage(3).encode();
+        bytes.extend(MessageType::Notification("YOU WIN!".to_string()).encode());
+
+        let mut decoder = Decoder::new();
+        decoder.push(&bytes[..12]);
+        assert_eq!(decoder.next_message(), Ok(Some(MessageType::Garbage(3))));
+        assert_eq!(decoder.next_message(), Ok(None));
+
+        decoder.push(&bytes[12..]);
+        assert_eq!(
+            decoder.next_message(),
+            Ok(Some(MessageType::Notification("YOU WIN!".to_string())))
+        );
+    }
+
+    #[test]
+    fn skips_frames_that_cannot_be_decoded() {
+        let mut unknown = MessageType::Garbage(1).encode();
+        unknown[LENGTH_SIZE + 1] = 99;
+        let mut newer = MessageType::Garbage(1).encode();
+        newer[LENGTH_SIZE] = PROTOCOL_VERSION + 1;
+        let mut short = MessageType::Garbage(1).encode();
+        short[LENGTH_SIZE - 1] -= 1;
+        short.pop();
+        let invalid_text = [0, 0, 0, 3, PROTOCOL_VERSION, TAG_NOTIFICATION, 0xff];
+
+        let mut decoder = Decoder::new();
+        decoder.push(&unknown);
+        decoder.push(&newer);
+        decoder.push(&short);
+        decoder.push(&invalid_text);
+        decoder.push(&MessageType::Garbage(2).encode());
+
+        assert_eq!(
+            decoder.next_message(),
+            Err(ProtocolError::UnknownMessage(99))
+        );
+        assert_eq!(
+            decoder.next_message(),
+            Err(ProtocolError::UnsupportedVersion(PROTOCOL_VERSION + 1))
+        );
+        assert_eq!(decoder.next_message(), Err(ProtocolError::Truncated));
+        assert_eq!(decoder.next_message(), Err(ProtocolError::InvalidText));
+        assert_eq!(decoder.next_message(), Ok(Some(MessageType::Garbage(2))));
+    }
+
+    #[test]
+    fn refuses_frames_that_are_too_long() {
+        let mut decoder = Decoder::new();
+        decoder.push(&u32::MAX.to_be_bytes());
+
+        let err = decoder.next_message().unwrap_err();
+        assert_eq!(err, ProtocolError::FrameTooLong(u32::MAX as usize));
+        assert!(err.is_fatal());
+    }
+
+    #[test]
+    fn round_trips_the_handshake() {
+        let messages = vec![
+            MessageType::Hello {
+                version: PROTOCOL_VERSION,
+                name: "ann".to_string(),
+            },
+            MessageType::Welcome {
+                name: "bob".to_string(),
+                rules: Rules {
+                    level: 3,
+                    lines_filled: 5,
+                    seed: u64::MAX - 1,
+                    randomizer: Randomizer::Bag,
+                    garbage_messiness: 40,
+                    best_of: 5,
+                },
+            },
+            MessageType::Reject("Wrong version".to_string()),
+            MessageType::Lobby {
+                joined: 2,
+                players: 4,
+            },
+            MessageType::Target("carl".to_string()),
+            MessageType::Watch {
+                version: PROTOCOL_VERSION,
+            },
+            MessageType::Heartbeat,
+            MessageType::Ready,
+            MessageType::Rematch(true),
+            MessageType::Rematch(false),
+        ];
+
+        let mut decoder = Decoder::new();
+        for message in messages.iter() {
+            decoder.push(&message.encode());
+        }
+
+        assert_eq!(decode_all(&mut decoder), messages);
+    }
+
+    #[test]
+    fn refuses_rules_out_of_range() {
+        let welcome = |change: fn(&mut Rules)| {
+            let mut rules = Rules::from_config(&Config::default());
+            change(&mut rules);
+            let mut decoder = Decoder::new();
+            decoder.push(
+                &MessageType::Welcome {
+                    name: "bob".to_string(),
+                    rules,
+                }
+                .encode(),
+            );
+            decoder.next_message()
+        };
+
+        assert!(matches!(welcome(|_| {}), Ok(Some(_))));
+        assert!(matches!(
+            welcome(|rules| rules.level = MAX_LEVEL),
+            Ok(Some(_))
+        ));
+        assert_eq!(
+            welcome(|rules| rules.level = MAX_LEVEL + 1),
+            Err(ProtocolError::InvalidRules)
+        );
+        assert_eq!(
+            welcome(|rules| rules.lines_filled = MAX_NUMBER_OF_LINES_ALREADY_FILLED + 1),
+            Err(ProtocolError::InvalidRules)
+        );
+        assert_eq!(
+            welcome(|rules| rules.best_of = 0),
+            Err(ProtocolError::InvalidRules)
+        );
+        assert_eq!(
+            welcome(|rules| rules.best_of = 4),
+            Err(ProtocolError::InvalidRules)
+        );
+        assert_eq!(
+            welcome(|rules| rules.best_of = MAX_BEST_OF + 2),
+            Err(ProtocolError::InvalidRules)
+        );
+    }
+
+    #[test]
+    fn cuts_names_too_long_for_a_view() {
+        let view = |name: &str| MessageType::View {
+            player: 1,
+            name: name.to_string(),
+            score: 10,
+            wins: 2,
+            rows: vec![vec![CellKind::Empty; 10]; 20],
+        };
+
+        let mut decoder = Decoder::new();
+        decoder.push(&view(&"a".repeat(300)).encode());
+        // 'é' takes 2 bytes, the last one would only fit halfway
+        decoder.push(&view(&"é".repeat(200)).encode());
+
+        assert_eq!(
+            decoder.next_message(),
+            Ok(Some(view(&"a".repeat(u8::MAX as usize))))
+        );
+        assert_eq!(decoder.next_message(), Ok(Some(view(&"é".repeat(127)))));
+    }
+
+    #[test]
+    fn round_trips_boards() {
+        let mut rows = vec![vec![CellKind::Empty; 10]; 20];
+        rows[19] = vec![CellKind::Garbage; 10];
+        rows[19][3] = CellKind::Empty;
+        rows[0][4] = CellKind::T;
+        let board = MessageType::Board {
+            score: 1200,
+            rows: rows.clone(),
+        };
+        let view = MessageType::View {
+            player: 3,
+            name: "ann".to_string(),
+            score: 1200,
+            wins: 2,
+            rows,
+        };
+        let left = MessageType::View {
+            player: 3,
+            name: "ann".to_string(),
+            score: 0,
+            wins: 0,
+            rows: Vec::new(),
+        };
+
+        let mut decoder = Decoder::new();
+        decoder.push(&board.encode());
+        decoder.push(&view.encode());
+        decoder.push(&left.encode());
+
+        assert_eq!(decode_all(&mut decoder), vec![board, view, left]);
+    }
+
+    #[test]
+    fn reads_hello_of_any_version() {
+        let hello = MessageType::Hello {
+            version: PROTOCOL_VERSION + 1,
+            name: "ann".to_string(),
+        };
+
+        let mut decoder = Decoder::new();
+        decoder.push(&hello.encode());
+
+        assert_eq!(decoder.next_message(), Ok(Some(hello)));
+    }
+
+    #[test]
+    fn rejects_players_of_another_version() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let host = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            welcome(&mut stream, "bob", &Rules::from_config(&Config::default())).is_err()
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        let hello = MessageType::Hello {
+            version: PROTOCOL_VERSION + 1,
+            name: "ann".to_string(),
+        };
+        stream.write_all(&hello.encode()).unwrap();
+
+        match read_message(&mut stream).unwrap() {
+            MessageType::Reject(reason) => assert!(reason.contains("protocol version")),
+            other => panic!("expected a rejection, got {:?}", other),
+        }
+        assert!(host.join().unwrap());
+    }
+
+    #[test]
+    fn welcomes_players_with_the_rules() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let rules = Rules::from_config(&Config::default());
+        let host_rules = rules.clone();
+        let host = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            welcome(&mut stream, "bob", &host_rules).unwrap()
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        let (host_name, joined_rules) = join(&mut stream, "ann").unwrap();
+
+        assert_eq!(host.join().unwrap(), Guest::Player("ann".to_string()));
+        assert_eq!(host_name, "bob");
+        assert_eq!(joined_rules, rules);
+    }
+
+    #[test]
+    fn welcomes_spectators() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let host = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            welcome(&mut stream, "bob", &Rules::from_config(&Config::default())).unwrap()
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+
+        assert_eq!(watch(&mut stream).unwrap(), "bob");
+        assert_eq!(host.join().unwrap(), Guest::Spectator);
+    }
+
+    #[test]
+    fn takes_back_the_competitor_only() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let reconnections = accept_guests(
+            listener,
+            "bob".to_string(),
+            Rules::from_config(&Config::default()),
+            "ann".to_string(),
+            Spectators::default(),
+        );
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        let err = join(&mut stream, "eve").unwrap_err();
+        assert!(err.to_string().contains("already started"));
+
+        let reconnection = reconnect(address.to_string(), "ann".to_string());
+        assert!(reconnection.recv_timeout(HANDSHAKE_TIMEOUT).is_ok());
+        assert!(reconnections.recv_timeout(HANDSHAKE_TIMEOUT).is_ok());
+    }
+
+    #[test]
+    fn reports_busy_ports() {
+        let listener = listen("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let err = listen(address).unwrap_err();
+
+        assert!(err.to_string().contains("already in use"));
+    }
+
+    #[test]
+    fn invites_to_the_chosen_port() {
+        let listener = listen("[::1]:0".parse().unwrap())
+            .or_else(|_| listen("127.0.0.1:0".parse().unwrap()))
+            .unwrap();
+
+        let address = invite_address(&listener).unwrap();
+
+        assert_ne!(address.port(), 0);
+        assert_eq!(address, listener.local_addr().unwrap());
+    }
+
+    #[test]
+    fn forwards_messages_whatever_the_reads() {
+        let messages = vec![
+            MessageType::Garbage(1),
+            MessageType::Garbage(4),
+            MessageType::Notification("YOU WIN!".to_string()),
+        ];
+        let bytes: Vec<u8> = messages
+            .iter()
+            .flat_map(|message| message.encode())
+            .collect();
+
+        for chunks in [vec![1; bytes.len()], vec![3, 7, 1, 20], vec![bytes.len()]] {
+            let mut reader = ChunkedReader {
+                bytes: bytes.clone(),
+                chunks,
+            };
+            let (sender, receiver) = channel();
+            forward_to_main_thread(&mut reader, sender);
+
+            assert_eq!(receiver.try_iter().collect::<Vec<_>>(), messages);
+        }
+    }
+
+    #[test]
+    fn never_mixes_the_frames_of_two_threads() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut other_end, _) = listener.accept().unwrap();
+        let writer = Writer::new(stream);
+
+        let board = MessageType::Board {
+            score: 100,
+            rows: vec![vec![CellKind::Garbage; 10]; 20],
+        };
+        let threads: Vec<_> = [board.clone(), MessageType::Heartbeat]
+            .into_iter()
+            .map(|message| {
+                let writer = writer.clone();
+                thread::spawn(move || {
+                    for _ in 0..500 {
+                        writer.send(&message).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        writer.shutdown();
+
+        let mut messages = Vec::new();
+        read_messages(&mut other_end, |message| {
+            messages.push(message);
+            true
+        });
+        assert_eq!(messages.len(), 1000);
+        assert_eq!(
+            messages.iter().filter(|message| **message == board).count(),
+            500
+        );
+    }
+}
@@ -0,0 +1,477 @@
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    data_dir, Cell, CellKind, GameError, Result, Tetromino, TetrominoSpawner, EMPTY_CELL,
+    GARBAGE_CELL, I_CELL, J_CELL, L_CELL, O_CELL, PLAY_HEIGHT, PLAY_WIDTH, S_CELL, T_CELL, Z_CELL,
+};
+
+const PUZZLES_DIR: &str = "puzzles";
+const PUZZLE_EXTENSION: &str = "puzzles";
+
+const BUILTIN_PACK: &str = "basics";
+const BUILTIN_PUZZLES: &str = "
+[Four at once]
+goal = lines 4
+pieces = I
+xxxxxxxxx.
+xxxxxxxxx.
+xxxxxxxxx.
+xxxxxxxxx.
+
+[Fill the gap]
+goal = lines 2
+pieces = O O
+SSZZTT....
+SZZTTJ....
+
+[Clean sweep]
+goal = perfect-clear
+pieces = J O
+IIIIJ.....
+IIIIJJJ...
+
+[T-spin double]
+goal = tsd
+pieces = T
+xxxx......
+xxx...xxxx
+xxxx.xxxxx
+";
+
+/// What has to be done with the pieces of a puzzle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Goal {
+    /// Clear this many lines
+    Lines(usize),
+    /// Leave the board empty
+    PerfectClear,
+    /// Clear two lines at once with a T piece rotated into its slot
+    TSpinDouble,
+}
+
+impl Goal {
+    fn parse(value: &str) -> Option<Goal> {
+        match value.split_whitespace().collect::<Vec<&str>>().as_slice() {
+            ["lines", lines] => lines
+                .parse()
+                .ok()
+                .filter(|lines| *lines > 0)
+                .map(Goal::Lines),
+            ["perfect-clear"] => Some(Goal::PerfectClear),
+            ["tsd"] | ["t-spin-double"] => Some(Goal::TSpinDouble),
+            _ => None,
+        }
+    }
+
+    /// A short description that fits in the Stats panel.
+    pub fn short_name(&self) -> String {
+        match self {
+            Goal::Lines(1) => "1 line".to_string(),
+            Goal::Lines(lines) => format!("{} lines", lines),
+            Goal::PerfectClear => "PC".to_string(),
+            Goal::TSpinDouble => "TSD".to_string(),
+        }
+    }
+
+    /// Whether the goal is met after a piece locked and cleared `cleared` rows.
+    pub fn is_met(&self, lines: usize, cleared: usize, t_spin: bool, board_empty: bool) -> bool {
+        match self {
+            Goal::Lines(goal) => lines >= *goal,
+            Goal::PerfectClear => cleared > 0 && board_empty,
+            Goal::TSpinDouble => t_spin && cleared == 2,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Puzzle {
+    pub pack: String,
+    pub name: String,
+    pub goal: Goal,
+    pub pieces: Vec<CellKind>,
+    // The bottom rows of the board, the rows above them are empty
+    rows: Vec<Vec<Cell>>,
+}
+
+impl Puzzle {
+    /// Identifies the puzzle on the command line and in the database, e.g. `basics/Clean sweep`
+    pub fn id(&self) -> String {
+        format!("{}/{}", self.pack, self.name)
+    }
+
+    pub fn board(&self) -> Vec<Vec<Cell>> {
+        let mut board = vec![vec![EMPTY_CELL; PLAY_WIDTH]; PLAY_HEIGHT - self.rows.len()];
+        board.extend(self.rows.iter().cloned());
+        board
+    }
+}
+
+/// Hands out the pieces of a puzzle in order, then pieces with no blocks.
+pub struct ScriptedTetromino {
+    pieces: Vec<CellKind>,
+    next: AtomicUsize,
+}
+
+impl ScriptedTetromino {
+    pub fn new(pieces: Vec<CellKind>) -> Self {
+        ScriptedTetromino {
+            pieces,
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl TetrominoSpawner for ScriptedTetromino {
+    fn spawn(&self, is_next: bool) -> Tetromino {
+        let index = self.next.fetch_add(1, Ordering::Relaxed);
+        let kind = self.pieces.get(index).copied().unwrap_or(CellKind::Empty);
+
+        Tetromino::new(kind, is_next)
+    }
+}
+
+/// Parses a pack of puzzles. Each puzzle starts with its name in brackets, followed by
+/// its goal, its pieces and the bottom rows of its board:
+///
+/// ```text
+/// # ~/.tetris/puzzles/mine.puzzles
+/// [Four at once]
+/// goal = lines 4
+/// pieces = I
+/// xxxxxxxxx.
+/// xxxxxxxxx.
+/// ```
+///
+/// Goals are `lines N`, `perfect-clear` and `tsd` (T-spin double). In rows, `.` is
+/// an empty cell, `x` a garbage block and `I`, `O`, `T`, `S`, `Z`, `J`, `L` blocks
+/// of the color of that piece.
+pub fn parse_pack(pack: &str, content: &str) -> Result<Vec<Puzzle>> {
+    let mut puzzles: Vec<Puzzle> = Vec::new();
+
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let error = |message: &str| -> Box<dyn std::error::Error> {
+            Box::new(GameError {
+                message: format!("line {}: {}", index + 1, message),
+            })
+        };
+
+        if let Some(name) = line
+            .strip_prefix('[')
+            .and_then(|rest| rest.strip_suffix(']'))
+        {
+            let name = name.trim();
+            if puzzles.iter().any(|puzzle| puzzle.name == name) {
+                return Err(error(&format!(
+                    "there is already a puzzle named '{}'",
+                    name
+                )));
+            }
+            puzzles.push(Puzzle {
+                pack: pack.to_string(),
+                name: name.to_string(),
+                goal: Goal::Lines(1),
+                pieces: Vec::new(),
+                rows: Vec::new(),
+            });
+            continue;
+        }
+
+        let puzzle = puzzles
+            .last_mut()
+            .ok_or_else(|| error("expected a puzzle name in brackets"))?;
+
+        if let Some((key, value)) = line.split_once('=') {
+            let value = value.trim();
+            match key.trim() {
+                "goal" => {
+                    puzzle.goal = Goal::parse(value).ok_or_else(|| error("unknown goal"))?;
+                }
+                "pieces" => {
+                    puzzle.pieces = value
+                        .chars()
+                        .filter(|c| !c.is_whitespace() && *c != ',')
+                        .map(|c| match cell(c) {
+                            Some(cell)
+                                if !matches!(cell.kind, CellKind::Empty | CellKind::Garbage) =>
+                            {
+                                Ok(cell.kind)
+                            }
+                            _ => Err(error(&format!("unknown piece '{}'", c))),
+                        })
+                        .collect::<Result<Vec<CellKind>>>()?;
+                }
+                other => return Err(error(&format!("unknown key '{}'", other))),
+            }
+        } else {
+            let row = line
+                .chars()
+                .map(cell)
+                .collect::<Option<Vec<Cell>>>()
+                .filter(|row| row.len() == PLAY_WIDTH)
+                .ok_or_else(|| {
+                    error(&format!(
+                        "a row must have {} cells among . x I O T S Z J L",
+                        PLAY_WIDTH
+                    ))
+                })?;
+            if puzzle.rows.len() == PLAY_HEIGHT {
+                return Err(error("the board is too high"));
+            }
+            puzzle.rows.push(row);
+        }
+    }
+
+    if let Some(puzzle) = puzzles.iter().find(|puzzle| puzzle.pieces.is_empty()) {
+        return Err(Box::new(GameError {
+            message: format!("the puzzle '{}' has no pieces", puzzle.name),
+        }));
+    }
+
+    Ok(puzzles)
+}
+
+fn cell(c: char) -> Option<Cell> {
+    let cell = match c {
+        '.' => EMPTY_CELL,
+        'x' => GARBAGE_CELL,
+        'I' => I_CELL,
+        'O' => O_CELL,
+        'T' => T_CELL,
+        'S' => S_CELL,
+        'Z' => Z_CELL,
+        'J' => J_CELL,
+        'L' => L_CELL,
+        _ => return None,
+    };
+
+    Some(cell)
+}
+
+/// The built-in puzzles followed by the packs in `~/.tetris/puzzles`, sorted by name.
+pub fn load_puzzles() -> Result<Vec<Puzzle>> {
+    let mut puzzles = parse_pack(BUILTIN_PACK, BUILTIN_PUZZLES)?;
+
+    let dir = data_dir()?.join(PUZZLES_DIR);
+    if let Ok(entries) = fs::read_dir(&dir) {
+        let mut paths: Vec<_> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == PUZZLE_EXTENSION))
+            .collect();
+        paths.sort();
+
+        for path in paths {
+            let pack = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default();
+            if pack == BUILTIN_PACK {
+                continue;
+            }
+
+            let content = fs::read_to_string(&path)?;
+            let pack_puzzles = parse_pack(&pack, &content).map_err(|err| {
+                Box::new(GameError {
+                    message: format!("{}: {}", path.display(), err),
+                }) as Box<dyn std::error::Error>
+            })?;
+            puzzles.extend(pack_puzzles);
+        }
+    }
+
+    Ok(puzzles)
+}
+
+/// Finds a puzzle by `pack/name` or `pack/number` (from 1). A pack alone, or no name
+/// at all, picks the first puzzle that hasn't been solved yet.
+pub fn find(puzzles: &[Puzzle], id: Option<&str>, solved: &[String]) -> Result<Puzzle> {
+    let (pack, name) = match id {
+        Some(id) => match id.split_once('/') {
+            Some((pack, name)) => (Some(pack), Some(name)),
+            None => (Some(id), None),
+        },
+        None => (None, None),
+    };
+
+    let candidates: Vec<&Puzzle> = puzzles
+        .iter()
+        .filter(|puzzle| pack.is_none_or(|pack| puzzle.pack == pack))
+        .collect();
+
+    let puzzle = match name {
+        Some(name) => match name.parse::<usize>() {
+            Ok(number) if number > 0 => candidates.get(number - 1).copied(),
+            _ => candidates
+                .iter()
+                .find(|puzzle| puzzle.name == name)
+                .copied(),
+        },
+        None => candidates
+            .iter()
+            .find(|puzzle| !solved.contains(&puzzle.id()))
+            .or(candidates.first())
+            .copied(),
+    };
+
+    puzzle.cloned().ok_or_else(|| {
+        Box::new(GameError {
+            message: format!("Unknown puzzle '{}'.", id.unwrap_or_default()),
+        }) as Box<dyn std::error::Error>
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACK: &str = "
+# Two puzzles
+[Single]
+goal = lines 1
+pieces = I, O
+xxxxxxxxx.
+
+[Sweep]
+goal = perfect-clear
+pieces = J L
+IIII......
+";
+
+    fn error(content: &str) -> String {
+        parse_pack("mine", content).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_a_pack() {
+        let puzzles = parse_pack("mine", PACK).unwrap();
+        assert_eq!(puzzles.len(), 2);
+
+        let single = &puzzles[0];
+        assert_eq!(single.id(), "mine/Single");
+        assert_eq!(single.goal, Goal::Lines(1));
+        assert_eq!(single.pieces, [CellKind::I, CellKind::O]);
+        let board = single.board();
+        assert_eq!(board.len(), PLAY_HEIGHT);
+        assert!(board[..PLAY_HEIGHT - 1]
+            .iter()
+            .flatten()
+            .all(|cell| *cell == EMPTY_CELL));
+        assert_eq!(board[PLAY_HEIGHT - 1][0].kind, CellKind::Garbage);
+        assert_eq!(board[PLAY_HEIGHT - 1][PLAY_WIDTH - 1], EMPTY_CELL);
+
+        assert_eq!(puzzles[1].goal, Goal::PerfectClear);
+        assert_eq!(puzzles[1].pieces, [CellKind::J, CellKind::L]);
+    }
+
+    #[test]
+    fn parses_the_builtin_pack() {
+        let puzzles = parse_pack(BUILTIN_PACK, BUILTIN_PUZZLES).unwrap();
+        assert_eq!(puzzles.len(), 4);
+        assert_eq!(puzzles[3].goal, Goal::TSpinDouble);
+    }
+
+    #[test]
+    fn parses_goals() {
+        assert_eq!(Goal::parse("lines 4"), Some(Goal::Lines(4)));
+        assert_eq!(Goal::parse("perfect-clear"), Some(Goal::PerfectClear));
+        assert_eq!(Goal::parse("tsd"), Some(Goal::TSpinDouble));
+        assert_eq!(Goal::parse("t-spin-double"), Some(Goal::TSpinDouble));
+        assert_eq!(Goal::parse("lines 0"), None);
+        assert_eq!(Goal::parse("lines"), None);
+        assert_eq!(Goal::parse("tetris"), None);
+    }
+
+    #[test]
+    fn reports_the_line_of_a_mistake() {
+        assert_eq!(
+            error("[A]\npieces = I\nxxxxxxxxx"),
+            "line 3: a row must have 10 cells among . x I O T S Z J L"
+        );
+        assert_eq!(
+            error("[A]\npieces = I\nxxxxxxxxxQ"),
+            "line 3: a row must have 10 cells among . x I O T S Z J L"
+        );
+        assert_eq!(error("[A]\ngoal = lines many"), "line 2: unknown goal");
+        assert_eq!(error("[A]\npieces = I x"), "line 2: unknown piece 'x'");
+        assert_eq!(error("[A]\nhold = I"), "line 2: unknown key 'hold'");
+        assert_eq!(
+            error("pieces = I"),
+            "line 1: expected a puzzle name in brackets"
+        );
+        assert_eq!(
+            error("[A]\npieces = I\n[A]\npieces = O"),
+            "line 3: there is already a puzzle named 'A'"
+        );
+        assert_eq!(error("[A]\ngoal = tsd"), "the puzzle 'A' has no pieces");
+    }
+
+    #[test]
+    fn refuses_a_board_higher_than_the_playfield() {
+        let content = format!(
+            "[A]\npieces = I\n{}",
+            "xxxxxxxxx.\n".repeat(PLAY_HEIGHT + 1)
+        );
+        assert_eq!(error(&content), "line 23: the board is too high");
+    }
+
+    #[test]
+    fn meets_a_lines_goal_once_enough_lines_are_cleared() {
+        let goal = Goal::Lines(2);
+        assert!(!goal.is_met(1, 1, false, false));
+        assert!(goal.is_met(2, 1, false, false));
+        assert!(goal.is_met(3, 3, false, true));
+    }
+
+    #[test]
+    fn meets_a_perfect_clear_goal_when_a_clear_empties_the_board() {
+        let goal = Goal::PerfectClear;
+        assert!(goal.is_met(2, 2, false, true));
+        assert!(!goal.is_met(2, 2, false, false));
+        // An empty board without a clear doesn't count
+        assert!(!goal.is_met(0, 0, false, true));
+    }
+
+    #[test]
+    fn meets_a_t_spin_double_goal_only_with_two_lines() {
+        let goal = Goal::TSpinDouble;
+        assert!(goal.is_met(2, 2, true, false));
+        assert!(!goal.is_met(2, 2, false, false));
+        assert!(!goal.is_met(1, 1, true, false));
+        assert!(!goal.is_met(3, 3, true, false));
+    }
+
+    #[test]
+    fn finds_puzzles_by_name_or_number() {
+        let mut puzzles = parse_pack(BUILTIN_PACK, BUILTIN_PUZZLES).unwrap();
+        puzzles.extend(parse_pack("mine", PACK).unwrap());
+
+        let find_id = |id| find(&puzzles, Some(id), &[]).map(|puzzle| puzzle.id());
+        assert_eq!(find_id("mine/Sweep").unwrap(), "mine/Sweep");
+        assert_eq!(find_id("basics/2").unwrap(), "basics/Fill the gap");
+        assert_eq!(find_id("mine").unwrap(), "mine/Single");
+        assert_eq!(
+            find_id("mine/3").unwrap_err().to_string(),
+            "Unknown puzzle 'mine/3'."
+        );
+        assert!(find_id("other/Single").is_err());
+    }
+
+    #[test]
+    fn finds_the_first_unsolved_puzzle() {
+        let puzzles = parse_pack(BUILTIN_PACK, BUILTIN_PUZZLES).unwrap();
+        let solved = ["basics/Four at once".to_string()];
+        let puzzle = find(&puzzles, None, &solved).unwrap();
+        assert_eq!(puzzle.name, "Fill the gap");
+
+        // Once everything is solved, the first puzzle again
+        let solved: Vec<String> = puzzles.iter().map(|puzzle| puzzle.id()).collect();
+        let puzzle = find(&puzzles, Some("basics"), &solved).unwrap();
+        assert_eq!(puzzle.name, "Four at once");
+    }
+}
@@ -0,0 +1,53 @@
+use std::sync::{Mutex, PoisonError};
+
+use clap::ValueEnum;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+use crate::{CellKind, Tetromino, TetrominoSpawner, TETROMINO_KINDS};
+
+/// How the next piece is picked.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Randomizer {
+    /// Any piece, every time
+    #[default]
+    Random,
+    /// The 7 pieces in a random order, then the 7 again in another order
+    Bag,
+}
+
+/// Hands out the same pieces for the same seed and randomizer, so that
+/// players of a daily challenge or a 2-player game get the same ones.
+pub struct SeededTetromino {
+    randomizer: Randomizer,
+    state: Mutex<(StdRng, Vec<CellKind>)>,
+}
+
+impl SeededTetromino {
+    pub fn new(seed: u64, randomizer: Randomizer) -> Self {
+        SeededTetromino {
+            randomizer,
+            state: Mutex::new((StdRng::seed_from_u64(seed), Vec::new())),
+        }
+    }
+}
+
+impl TetrominoSpawner for SeededTetromino {
+    fn spawn(&self, is_next: bool) -> Tetromino {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let (rng, bag) = &mut *state;
+        let kind = match self.randomizer {
+            Randomizer::Random => TETROMINO_KINDS[rng.gen_range(0..TETROMINO_KINDS.len())],
+            Randomizer::Bag => {
+                if bag.is_empty() {
+                    *bag = TETROMINO_KINDS.to_vec();
+                    bag.shuffle(rng);
+                }
+                bag.pop().unwrap_or(CellKind::I)
+            }
+        };
+
+        Tetromino::new(kind, is_next)
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::VecDeque;
+
+use crate::{Cell, CellKind, TETROMINO_KINDS};
+
+// Older moves are forgotten past this many undo steps
+const MAX_UNDO: usize = 100;
+
+/// Drawn over the cell under the cursor of the board editor.
+pub const CURSOR: &str = "(+)";
+
+/// What a move or an edit changes in Sandbox mode, taken before it to undo it.
+pub struct Snapshot {
+    pub play_grid: Vec<Vec<Cell>>,
+    pub current: CellKind,
+    pub next: Vec<CellKind>,
+    pub score: usize,
+    pub lines: usize,
+    pub pieces: usize,
+}
+
+pub struct History {
+    snapshots: VecDeque<Snapshot>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        History {
+            snapshots: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, snapshot: Snapshot) {
+        if self.snapshots.len() == MAX_UNDO {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(snapshot);
+    }
+
+    pub fn pop(&mut self) -> Option<Snapshot> {
+        self.snapshots.pop_back()
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new()
+    }
+}
+
+/// The piece after `kind` when choosing the next piece, in the order I O T S Z J L.
+pub fn next_kind(kind: CellKind) -> CellKind {
+    let index = TETROMINO_KINDS
+        .iter()
+        .position(|other| *other == kind)
+        .map_or(0, |index| (index + 1) % TETROMINO_KINDS.len());
+
+    TETROMINO_KINDS[index]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A snapshot told apart by its score
+    fn snapshot(score: usize) -> Snapshot {
+        Snapshot {
+            play_grid: Vec::new(),
+            current: CellKind::T,
+            next: Vec::new(),
+            score,
+            lines: 0,
+            pieces: 0,
+        }
+    }
+
+    fn scores(history: &mut History) -> Vec<usize> {
+        std::iter::from_fn(|| history.pop())
+            .map(|snapshot| snapshot.score)
+            .collect()
+    }
+
+    #[test]
+    fn undoes_the_last_move_first() {
+        let mut history = History::new();
+        assert!(history.pop().is_none());
+
+        history.push(snapshot(1));
+        history.push(snapshot(2));
+        history.push(snapshot(3));
+        assert_eq!(history.pop().unwrap().score, 3);
+
+        // A new move after an undo
+        history.push(snapshot(4));
+        assert_eq!(scores(&mut history), [4, 2, 1]);
+    }
+
+    #[test]
+    fn forgets_the_oldest_moves_past_the_limit() {
+        let mut history = History::new();
+        for score in 0..MAX_UNDO + 5 {
+            history.push(snapshot(score));
+        }
+
+        let scores = scores(&mut history);
+        assert_eq!(scores.len(), MAX_UNDO);
+        assert_eq!(scores[0], MAX_UNDO + 4);
+        assert_eq!(scores[MAX_UNDO - 1], 5);
+    }
+
+    #[test]
+    fn cycles_through_the_pieces() {
+        let mut kind = CellKind::I;
+        let mut kinds = Vec::new();
+        for _ in 0..TETROMINO_KINDS.len() {
+            kinds.push(kind);
+            kind = next_kind(kind);
+        }
+        assert_eq!(kinds, TETROMINO_KINDS);
+        assert_eq!(kind, CellKind::I);
+        assert_eq!(next_kind(CellKind::L), CellKind::I);
+    }
+
+    #[test]
+    fn starts_from_the_first_piece_without_one() {
+        assert_eq!(next_kind(CellKind::Empty), TETROMINO_KINDS[0]);
+        assert_eq!(next_kind(CellKind::Garbage), TETROMINO_KINDS[0]);
+    }
+}
@@ -0,0 +1,673 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use rand::seq::SliceRandom;
+
+use crate::multiplayer::{self, Guest, MessageType, Rules, Spectators, Writer};
+use crate::{CellKind, Result, MAX_NAME_LENGTH, PLAY_HEIGHT, PLAY_WIDTH};
+
+// Waiting players hear from the lobby this often, so that their handshake doesn't time out
+const LOBBY_INTERVAL: Duration = Duration::from_secs(3);
+// Every badge makes attacks this much (in percent) stronger, up to MAX_BADGES badges
+const BADGE_BONUS_PERCENT: usize = 25;
+const MAX_BADGES: usize = 4;
+// The rival players see until the battle gives them a target
+const SERVER_NAME: &str = "Battle";
+
+/// Who the attacks of a player go to in a battle.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Targeting {
+    /// Another player, picked at random for every attack
+    #[default]
+    Random,
+    /// Every player whose attacks go to you, or a random one if there is none
+    Attackers,
+    /// The player with the highest stack, who is the closest to being knocked out
+    Ko,
+    /// The player with the most badges, earned by knocking out other players
+    Badges,
+}
+
+enum Event {
+    Joined(TcpStream, String),
+    Watching(TcpStream),
+    Message(usize, MessageType),
+    Left(usize),
+}
+
+struct Player {
+    name: String,
+    writer: Writer,
+    alive: bool,
+    // The player their attacks went to last, and the last player who attacked them
+    target: Option<usize>,
+    attacker: Option<usize>,
+    // One badge for every player knocked out
+    badges: usize,
+    // The battles they won since they joined
+    wins: usize,
+    score: usize,
+    board: Option<Vec<Vec<CellKind>>>,
+}
+
+/// A battle between `size` players or more: it starts once `size` players have
+/// joined, and starts over whenever a single player is left standing.
+struct Battle {
+    size: usize,
+    targeting: Targeting,
+    rules: Rules,
+    // Players keep their id when others leave
+    players: BTreeMap<usize, Player>,
+    next_id: usize,
+    started: bool,
+    events: Sender<Event>,
+    spectators: Spectators,
+}
+
+/// Hosts a battle on `listener` until the process is stopped. Players join it
+/// like a 2-player game, and the server only relays their messages. Spectators
+/// can join at any time and see every player.
+pub fn serve(listener: TcpListener, size: usize, targeting: Targeting, rules: Rules) -> Result<()> {
+    let (sender, events) = channel();
+
+    let accepted = sender.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            // Players say hello on their own thread, so that a slow one doesn't hold the others
+            let accepted = accepted.clone();
+            thread::spawn(move || {
+                let mut stream = stream;
+                let event = match multiplayer::greet(&mut stream) {
+                    Ok(Guest::Player(name)) => Event::Joined(stream, name),
+                    Ok(Guest::Spectator) => Event::Watching(stream),
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        return;
+                    }
+                };
+                let _ = accepted.send(event);
+            });
+        }
+    });
+
+    let mut battle = Battle {
+        size,
+        targeting,
+        rules,
+        players: BTreeMap::new(),
+        next_id: 0,
+        started: false,
+        events: sender,
+        spectators: Spectators::default(),
+    };
+    loop {
+        match events.recv_timeout(LOBBY_INTERVAL) {
+            Ok(Event::Joined(stream, name)) => battle.join(stream, name)?,
+            Ok(Event::Watching(stream)) => battle.watch(stream),
+            Ok(Event::Message(id, message)) => battle.handle(id, message),
+            Ok(Event::Left(id)) => battle.leave(id),
+            Err(RecvTimeoutError::Timeout) => battle.update_lobby(),
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+impl Battle {
+    fn join(&mut self, mut stream: TcpStream, name: String) -> Result<()> {
+        if self.started {
+            let reason = "The battle has already started.".to_string();
+            let _ = stream.write_all(&MessageType::Reject(reason).encode());
+            return Ok(());
+        }
+        // Every spectator gets the names, which have to fit in their frames
+        if name.chars().count() > MAX_NAME_LENGTH {
+            let reason = format!("Names can't be longer than {} characters.", MAX_NAME_LENGTH);
+            let _ = stream.write_all(&MessageType::Reject(reason).encode());
+            return Ok(());
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let mut reader = stream.try_clone()?;
+        let writer = Writer::new(stream);
+        multiplayer::keep_alive(writer.clone());
+        let events = self.events.clone();
+        thread::spawn(move || {
+            multiplayer::read_messages(&mut reader, |message| {
+                events.send(Event::Message(id, message)).is_ok()
+            });
+            let _ = events.send(Event::Left(id));
+        });
+
+        println!(
+            "{} joined ({}/{}).",
+            name,
+            self.players.len() + 1,
+            self.size
+        );
+        self.players.insert(
+            id,
+            Player {
+                name,
+                writer,
+                alive: false,
+                target: None,
+                attacker: None,
+                badges: 0,
+                wins: 0,
+                score: 0,
+                board: None,
+            },
+        );
+
+        if self.players.len() >= self.size {
+            self.start();
+        } else {
+            self.update_lobby();
+        }
+
+        Ok(())
+    }
+
+    /// Welcomes a spectator, who sees every player from now on.
+    fn watch(&mut self, mut stream: TcpStream) {
+        let welcome = MessageType::Welcome {
+            name: SERVER_NAME.to_string(),
+            rules: self.rules.clone(),
+        };
+        let mut frames = welcome.encode();
+        for id in self.players.keys() {
+            frames.extend(self.view(*id).encode());
+        }
+        if stream.write_all(&frames).is_ok() {
+            self.spectators.add(stream);
+        }
+    }
+
+    /// Tells the waiting players how many have joined.
+    fn update_lobby(&mut self) {
+        if self.started {
+            return;
+        }
+
+        let lobby = MessageType::Lobby {
+            joined: self.players.len(),
+            players: self.size,
+        };
+        for player in self.players.values_mut() {
+            let _ = player.writer.send(&lobby);
+        }
+    }
+
+    fn start(&mut self) {
+        println!("The battle starts!");
+        self.started = true;
+
+        let welcome = MessageType::Welcome {
+            name: SERVER_NAME.to_string(),
+            rules: self.rules.clone(),
+        };
+        for player in self.players.values_mut() {
+            let _ = player.writer.send(&welcome);
+        }
+
+        self.start_round();
+    }
+
+    /// Brings every player back in, without badges, each with a new target.
+    /// The battle doesn't wait for anyone, so it is ready as soon as each player is.
+    fn start_round(&mut self) {
+        for player in self.players.values_mut() {
+            player.alive = true;
+            player.target = None;
+            player.attacker = None;
+            player.badges = 0;
+            let _ = player.writer.send(&MessageType::Ready);
+        }
+
+        let ids: Vec<usize> = self.players.keys().copied().collect();
+        for id in ids {
+            self.retarget(id);
+        }
+    }
+
+    fn handle(&mut self, id: usize, message: MessageType) {
+        match message {
+            MessageType::Board { score, rows } => {
+                for watcher in self.watchers(id) {
+                    let board = MessageType::Board {
+                        score,
+                        rows: rows.clone(),
+                    };
+                    self.send(watcher, board);
+                }
+                if let Some(player) = self.players.get_mut(&id) {
+                    player.score = score;
+                    player.board = Some(rows);
+                }
+                self.spectators.send(&self.view(id));
+            }
+            MessageType::Garbage(lines) if self.is_alive(id) => self.attack(id, lines),
+            // A player who tops out tells the other player of a 2-player game they won
+            MessageType::Notification(_) if self.is_alive(id) => self.knock_out(id),
+            // Battles go on for as long as players stay
+            MessageType::Rematch(true) => self.send(id, MessageType::Rematch(true)),
+            _ => {}
+        }
+    }
+
+    fn leave(&mut self, id: usize) {
+        if self.is_alive(id) {
+            self.knock_out(id);
+        }
+
+        if let Some(player) = self.players.remove(&id) {
+            println!("{} left.", player.name);
+            self.spectators.send(&MessageType::View {
+                player: id,
+                name: player.name,
+                score: 0,
+                wins: 0,
+                rows: Vec::new(),
+            });
+        }
+
+        // A battle needs two players, the next one starts from the lobby
+        if self.started && self.players.len() < 2 {
+            println!("Waiting for {} players.", self.size);
+            self.started = false;
+        } else {
+            // Their attackers need another target
+            for other in self.watchers(id) {
+                self.retarget(other);
+            }
+            self.update_lobby();
+        }
+    }
+
+    fn attack(&mut self, id: usize, lines: usize) {
+        let badges = self.players.get(&id).map_or(0, |player| player.badges);
+        let lines = lines + lines * BADGE_BONUS_PERCENT * badges.min(MAX_BADGES) / 100;
+
+        let targets = self.targets(id);
+        if targets.first()
+            != self
+                .players
+                .get(&id)
+                .and_then(|player| player.target.as_ref())
+        {
+            self.set_target(id, targets.first().copied());
+        }
+        for target in targets {
+            if let Some(player) = self.players.get_mut(&target) {
+                player.attacker = Some(id);
+            }
+            self.send(target, MessageType::Garbage(lines));
+        }
+    }
+
+    fn knock_out(&mut self, id: usize) {
+        let attacker = match self.players.get_mut(&id) {
+            Some(player) => {
+                player.alive = false;
+                player.attacker.filter(|attacker| *attacker != id)
+            }
+            None => return,
+        };
+
+        let left = self.alive().len();
+        let name = self.name(id);
+        match attacker.and_then(|attacker| self.players.get_mut(&attacker)) {
+            Some(attacker) => {
+                attacker.badges += 1;
+                println!(
+                    "{} was knocked out by {}, {} left.",
+                    name, attacker.name, left
+                );
+            }
+            None => println!("{} is out, {} left.", name, left),
+        }
+
+        for watcher in self.watchers(id) {
+            self.retarget(watcher);
+        }
+
+        if let [winner] = self.alive()[..] {
+            println!("{} wins!", self.name(winner));
+            self.send(winner, MessageType::Notification("YOU WIN!".to_string()));
+            if let Some(player) = self.players.get_mut(&winner) {
+                player.wins += 1;
+            }
+            self.spectators.send(&self.view(winner));
+            if self.players.len() > 1 {
+                self.start_round();
+            }
+        }
+    }
+
+    /// The players whose attacks go to `id`, who see their board.
+    fn watchers(&self, id: usize) -> Vec<usize> {
+        self.players
+            .iter()
+            .filter(|(_, player)| player.target == Some(id))
+            .map(|(other, _)| *other)
+            .collect()
+    }
+
+    /// Who the next attack of `id` goes to.
+    fn targets(&self, id: usize) -> Vec<usize> {
+        let others: Vec<usize> = self
+            .alive()
+            .into_iter()
+            .filter(|other| *other != id)
+            .collect();
+        let random = || others.choose(&mut rand::thread_rng()).copied();
+
+        let target = match self.targeting {
+            Targeting::Random => random(),
+            Targeting::Attackers => {
+                let attackers: Vec<usize> = others
+                    .iter()
+                    .copied()
+                    .filter(|other| self.players[other].target == Some(id))
+                    .collect();
+                if !attackers.is_empty() {
+                    return attackers;
+                }
+                random()
+            }
+            Targeting::Ko => others
+                .iter()
+                .copied()
+                .max_by_key(|other| stack_height(self.players[other].board.as_deref())),
+            Targeting::Badges => others
+                .iter()
+                .copied()
+                .max_by_key(|other| self.players[other].badges),
+        };
+
+        target.into_iter().collect()
+    }
+
+    fn retarget(&mut self, id: usize) {
+        if self.is_alive(id) {
+            let target = self.targets(id).first().copied();
+            self.set_target(id, target);
+        }
+    }
+
+    /// Tells `id` who their attacks go to, and shows them their board.
+    fn set_target(&mut self, id: usize, target: Option<usize>) {
+        let Some(player) = self.players.get_mut(&id) else {
+            return;
+        };
+        player.target = target;
+
+        if let Some(target) = target.and_then(|target| self.players.get(&target)) {
+            let name = target.name.clone();
+            let board = target.board.clone().map(|rows| MessageType::Board {
+                score: target.score,
+                rows,
+            });
+            self.send(id, MessageType::Target(name));
+            if let Some(board) = board {
+                self.send(id, board);
+            }
+        }
+    }
+
+    // A player who can't be written to has left, their reader tells the battle
+    fn send(&mut self, id: usize, message: MessageType) {
+        if let Some(player) = self.players.get_mut(&id) {
+            let _ = player.writer.send(&message);
+        }
+    }
+
+    /// What spectators see of `id`.
+    fn view(&self, id: usize) -> MessageType {
+        let player = &self.players[&id];
+        MessageType::View {
+            player: id,
+            name: player.name.clone(),
+            score: player.score,
+            wins: player.wins,
+            rows: player
+                .board
+                .clone()
+                .unwrap_or_else(|| vec![vec![CellKind::Empty; PLAY_WIDTH]; PLAY_HEIGHT]),
+        }
+    }
+
+    fn is_alive(&self, id: usize) -> bool {
+        self.started && self.players.get(&id).is_some_and(|player| player.alive)
+    }
+
+    fn alive(&self) -> Vec<usize> {
+        self.players
+            .iter()
+            .filter(|(_, player)| player.alive)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    fn name(&self, id: usize) -> String {
+        self.players
+            .get(&id)
+            .map_or_else(String::new, |player| player.name.clone())
+    }
+}
+
+/// How many rows of a board are taken, from its highest block down.
+fn stack_height(board: Option<&[Vec<CellKind>]>) -> usize {
+    let board = board.unwrap_or_default();
+    board
+        .iter()
+        .position(|row| row.iter().any(|kind| *kind != CellKind::Empty))
+        .map_or(0, |top| board.len() - top)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::sync::mpsc::Receiver;
+
+    // A battle for 3 players that nobody has joined yet
+    fn lobby(targeting: Targeting) -> (Battle, Receiver<Event>) {
+        let (events, receiver) = channel();
+        let battle = Battle {
+            size: 3,
+            targeting,
+            rules: Rules::from_config(&Config::default()),
+            players: BTreeMap::new(),
+            next_id: 0,
+            started: false,
+            events,
+            spectators: Spectators::default(),
+        };
+
+        (battle, receiver)
+    }
+
+    // Joins `battle` as `name`, and returns the player's end of the connection
+    fn join(battle: &mut Battle, name: &str) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        battle.join(stream, name.to_string()).unwrap();
+        client
+    }
+
+    // A battle between Ann (0), Bob (1) and Cat (2), with the other end of their connections
+    fn battle(targeting: Targeting) -> (Battle, Vec<TcpStream>, Receiver<Event>) {
+        let (mut battle, receiver) = lobby(targeting);
+        let clients = ["Ann", "Bob", "Cat"]
+            .iter()
+            .map(|name| join(&mut battle, name))
+            .collect();
+
+        (battle, clients, receiver)
+    }
+
+    // The messages a player got so far, besides heartbeats
+    fn received(client: &mut TcpStream) -> Vec<MessageType> {
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let mut messages = Vec::new();
+        multiplayer::read_messages(client, |message| {
+            if message != MessageType::Heartbeat {
+                messages.push(message);
+            }
+            true
+        });
+        messages
+    }
+
+    // A board whose stack is `height` rows high
+    fn board(height: usize) -> Vec<Vec<CellKind>> {
+        let mut rows = vec![vec![CellKind::Empty; PLAY_WIDTH]; PLAY_HEIGHT - height];
+        rows.extend(vec![vec![CellKind::Garbage; PLAY_WIDTH]; height]);
+        rows
+    }
+
+    #[test]
+    fn starts_once_everyone_has_joined() {
+        let (battle, _clients, _events) = battle(Targeting::Random);
+        assert!(battle.started);
+        assert_eq!(battle.alive(), [0, 1, 2]);
+        for (id, player) in &battle.players {
+            let target = player.target.unwrap();
+            assert_ne!(target, *id);
+        }
+    }
+
+    #[test]
+    fn refuses_names_that_are_too_long() {
+        let (mut battle, _events) = lobby(Targeting::Random);
+        let mut client = join(&mut battle, &"a".repeat(MAX_NAME_LENGTH + 1));
+
+        assert!(battle.players.is_empty());
+        let reason = format!("Names can't be longer than {} characters.", MAX_NAME_LENGTH);
+        assert_eq!(received(&mut client), [MessageType::Reject(reason)]);
+    }
+
+    #[test]
+    fn attacks_a_random_player_still_standing() {
+        let (mut battle, _clients, _events) = battle(Targeting::Random);
+        for _ in 0..20 {
+            let targets = battle.targets(0);
+            assert!(targets == [1] || targets == [2]);
+        }
+
+        battle.knock_out(1);
+        assert_eq!(battle.targets(0), [2]);
+    }
+
+    #[test]
+    fn attacks_back_every_attacker() {
+        let (mut battle, _clients, _events) = battle(Targeting::Attackers);
+        battle.players.get_mut(&1).unwrap().target = Some(0);
+        battle.players.get_mut(&2).unwrap().target = Some(0);
+        assert_eq!(battle.targets(0), [1, 2]);
+
+        // Nobody attacks Bob: a random player
+        battle.players.get_mut(&2).unwrap().target = Some(1);
+        assert_eq!(battle.targets(0), [1]);
+        battle.players.get_mut(&0).unwrap().target = Some(2);
+        battle.players.get_mut(&2).unwrap().target = Some(0);
+        let targets = battle.targets(1);
+        assert!(targets == [0] || targets == [2]);
+    }
+
+    #[test]
+    fn attacks_the_highest_stack_for_a_ko() {
+        let (mut battle, _clients, _events) = battle(Targeting::Ko);
+        battle.handle(
+            1,
+            MessageType::Board {
+                score: 0,
+                rows: board(12),
+            },
+        );
+        battle.handle(
+            2,
+            MessageType::Board {
+                score: 0,
+                rows: board(4),
+            },
+        );
+        assert_eq!(battle.targets(0), [1]);
+        assert_eq!(battle.targets(2), [1]);
+        assert_eq!(battle.targets(1), [2]);
+    }
+
+    #[test]
+    fn attacks_the_player_with_the_most_badges() {
+        let (mut battle, _clients, _events) = battle(Targeting::Badges);
+        battle.players.get_mut(&0).unwrap().badges = 2;
+        battle.players.get_mut(&1).unwrap().badges = 1;
+        assert_eq!(battle.targets(2), [0]);
+        assert_eq!(battle.targets(0), [1]);
+    }
+
+    #[test]
+    fn makes_attacks_stronger_with_badges() {
+        let (mut battle, mut clients, _events) = battle(Targeting::Badges);
+        // Cat attacks Bob, who has more badges than Ann
+        battle.players.get_mut(&1).unwrap().badges = 3;
+        battle.players.get_mut(&2).unwrap().badges = 2;
+        received(&mut clients[1]);
+
+        battle.handle(2, MessageType::Garbage(4));
+        assert!(received(&mut clients[1]).contains(&MessageType::Garbage(6)));
+    }
+
+    #[test]
+    fn knocks_players_out_until_one_wins() {
+        let (mut battle, mut clients, _events) = battle(Targeting::Badges);
+        // Bob attacks Cat, who tops out: Bob gets a badge
+        battle.handle(1, MessageType::Garbage(4));
+        assert_eq!(battle.players[&2].attacker, Some(1));
+        battle.handle(2, MessageType::Notification("YOU WIN!".to_string()));
+        assert_eq!(battle.alive(), [0, 1]);
+        assert_eq!(battle.players[&1].badges, 1);
+
+        // Ann can only attack Bob now
+        assert_eq!(battle.targets(0), [1]);
+        // A knocked out player can't attack anymore
+        received(&mut clients[0]);
+        battle.handle(2, MessageType::Garbage(4));
+        assert!(!received(&mut clients[0]).contains(&MessageType::Garbage(4)));
+
+        // Ann tops out: Bob wins and the next battle starts
+        received(&mut clients[1]);
+        battle.handle(0, MessageType::Notification("YOU WIN!".to_string()));
+        let win = MessageType::Notification("YOU WIN!".to_string());
+        assert!(received(&mut clients[1]).contains(&win));
+        assert!(!received(&mut clients[0]).contains(&win));
+        assert_eq!(battle.players[&1].wins, 1);
+        assert_eq!(battle.players[&0].wins, 0);
+        assert_eq!(battle.alive(), [0, 1, 2]);
+        assert_eq!(battle.players[&1].badges, 0);
+    }
+
+    #[test]
+    fn stops_when_a_single_player_is_left() {
+        let (mut battle, _clients, _events) = battle(Targeting::Random);
+        battle.leave(2);
+        assert!(battle.started);
+        assert_eq!(battle.alive(), [0, 1]);
+        assert_eq!(battle.targets(0), [1]);
+
+        battle.leave(1);
+        assert!(!battle.started);
+        assert!(!battle.is_alive(0));
+    }
+}
@@ -0,0 +1,464 @@
+use std::collections::BTreeMap;
+use std::net::TcpStream;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use crossterm::event::{Event, KeyEvent, KeyEventKind};
+use crossterm::terminal;
+
+use crate::config::Config;
+use crate::keys::{Action, Context};
+use crate::multiplayer::{self, MessageType};
+use crate::theme::Theme;
+use crate::{
+    CellKind, Result, Terminal, DISTANCE, OPPONENT_CELL_WIDTH, OPPONENT_WIDTH, PLAY_HEIGHT,
+    PLAY_WIDTH,
+};
+
+// The rows above the boards, for the host and the match score
+const HEADER_HEIGHT: usize = 3;
+// The rows below the boards, for the score and wins of each player
+const FOOTER_HEIGHT: usize = 3;
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+struct View {
+    name: String,
+    score: usize,
+    wins: usize,
+    rows: Vec<Vec<CellKind>>,
+}
+
+/// Shows the boards of a game as the host sends them, without playing,
+/// until the quit key is pressed.
+pub fn watch(
+    mut stream: TcpStream,
+    host: &str,
+    terminal: Box<dyn Terminal>,
+    theme: Theme,
+    config: &Config,
+) -> Result<()> {
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        multiplayer::forward_to_main_thread(&mut stream, sender);
+    });
+
+    terminal.enable_raw_mode()?;
+    terminal.enter_alternate_screen()?;
+
+    let mut spectator = Spectator {
+        terminal,
+        theme,
+        host: host.to_string(),
+        views: BTreeMap::new(),
+        over: false,
+        size: terminal::size()?,
+    };
+    let result = spectator.run(&receiver, config);
+
+    spectator.terminal.leave_alternate_screen()?;
+    spectator.terminal.disable_raw_mode()?;
+    result
+}
+
+struct Spectator {
+    terminal: Box<dyn Terminal>,
+    theme: Theme,
+    host: String,
+    // The players by the id the host gave them, shown from left to right
+    views: BTreeMap<usize, View>,
+    // Whether the host has closed the game
+    over: bool,
+    // The width and height of the terminal
+    size: (u16, u16),
+}
+
+impl Spectator {
+    fn run(&mut self, receiver: &Receiver<MessageType>, config: &Config) -> Result<()> {
+        self.render()?;
+        loop {
+            let size = terminal::size()?;
+            if size != self.size {
+                self.size = size;
+                self.render()?;
+            }
+
+            if self.terminal.poll_event(POLL_INTERVAL)? {
+                if let Event::Key(KeyEvent {
+                    code,
+                    kind: KeyEventKind::Press,
+                    ..
+                }) = self.terminal.read_event()?
+                {
+                    if config.keys.action(Context::Game, code) == Some(Action::Quit) {
+                        return Ok(());
+                    }
+                }
+            }
+
+            loop {
+                match receiver.try_recv() {
+                    Ok(MessageType::View {
+                        player,
+                        name,
+                        score,
+                        wins,
+                        rows,
+                    }) => self.update(
+                        player,
+                        View {
+                            name,
+                            score,
+                            wins,
+                            rows,
+                        },
+                    )?,
+                    Ok(_) => {}
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        if !self.over {
+                            self.over = true;
+                            self.render_header()?;
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Redraws what changed for `player`, or everything when players came or left.
+    fn update(&mut self, player: usize, view: View) -> Result<()> {
+        if view.rows.is_empty() {
+            self.views.remove(&player);
+            return self.render();
+        }
+
+        let previous = match self.views.insert(player, view) {
+            Some(previous) => previous,
+            None => return self.render(),
+        };
+        let slot = self.views.keys().position(|id| *id == player).unwrap_or(0);
+        let view = &self.views[&player];
+        if slot < self.slots() {
+            self.render_cells(slot, &view.rows, Some(&previous.rows))?;
+            self.render_footer(slot, view)?;
+        }
+        if view.wins != previous.wins {
+            self.render_header()?;
+        }
+
+        Ok(())
+    }
+
+    /// How many boards fit side by side in the terminal.
+    fn slots(&self) -> usize {
+        let (term_width, _) = self.size;
+        (term_width as usize + DISTANCE) / (OPPONENT_WIDTH + DISTANCE)
+    }
+
+    /// Where the frame of the board in `slot` starts.
+    fn position(&self, slot: usize) -> (usize, usize) {
+        let (term_width, term_height) = self.size;
+        let boards = self.views.len().clamp(1, self.slots().max(1));
+        let width = boards * (OPPONENT_WIDTH + DISTANCE) - DISTANCE;
+        let height = HEADER_HEIGHT + PLAY_HEIGHT + 2 + FOOTER_HEIGHT;
+
+        let start_x = (term_width as usize).saturating_sub(width) / 2;
+        let start_y = (term_height as usize).saturating_sub(height) / 2;
+        (
+            start_x + slot * (OPPONENT_WIDTH + DISTANCE),
+            start_y + HEADER_HEIGHT,
+        )
+    }
+
+    fn render(&self) -> Result<()> {
+        self.terminal.clear()?;
+        self.render_header()?;
+
+        for (slot, view) in self.views.values().take(self.slots()).enumerate() {
+            self.render_frame(slot, &view.name)?;
+            self.render_cells(slot, &view.rows, None)?;
+            self.render_footer(slot, view)?;
+        }
+
+        Ok(())
+    }
+
+    /// The host, and the match score when two players play each other.
+    fn render_header(&self) -> Result<()> {
+        let (term_width, _) = self.size;
+        let (_, start_y) = self.position(0);
+
+        let title = format!("Watching the game of {}", self.host);
+        let status = if self.over {
+            "The game is over.".to_string()
+        } else {
+            match self.views.values().collect::<Vec<_>>()[..] {
+                [first, second] => format!(
+                    "{} {} - {} {}",
+                    first.name, first.wins, second.wins, second.name
+                ),
+                [] => "Waiting for the players.".to_string(),
+                _ => String::new(),
+            }
+        };
+
+        for (index, line) in [title, status].iter().enumerate() {
+            let y = (start_y - HEADER_HEIGHT + index) as u16;
+            self.terminal.write(
+                self.theme.foreground,
+                0,
+                y,
+                &" ".repeat(term_width as usize),
+            )?;
+            self.terminal.write(
+                self.theme.foreground,
+                (term_width as usize).saturating_sub(line.len()) as u16 / 2,
+                y,
+                line,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn render_frame(&self, slot: usize, name: &str) -> Result<()> {
+        let (start_x, start_y) = self.position(slot);
+        let width = PLAY_WIDTH * OPPONENT_CELL_WIDTH;
+        let title: String = name.chars().take(width - 4).collect();
+        let left = (width - title.len() - 2) / 2;
+
+        self.terminal.write(
+            self.theme.foreground,
+            start_x as u16,
+            start_y as u16,
+            format!(
+                "|{} {} {}|",
+                "-".repeat(left),
+                title,
+                "-".repeat(width - left - title.len() - 2)
+            )
+            .as_str(),
+        )?;
+        for y in 1..=PLAY_HEIGHT {
+            for x in [start_x, start_x + width + 1] {
+                self.terminal
+                    .write(self.theme.foreground, x as u16, (start_y + y) as u16, "|")?;
+            }
+        }
+        self.terminal.write(
+            self.theme.foreground,
+            start_x as u16,
+            (start_y + PLAY_HEIGHT + 1) as u16,
+            format!("|{}|", "-".repeat(width)).as_str(),
+        )?;
+
+        Ok(())
+    }
+
+    /// Draws the cells of a board, only those that differ from `previous` when it is given.
+    fn render_cells(
+        &self,
+        slot: usize,
+        rows: &[Vec<CellKind>],
+        previous: Option<&[Vec<CellKind>]>,
+    ) -> Result<()> {
+        let (start_x, start_y) = self.position(slot);
+        for (y, row) in rows.iter().take(PLAY_HEIGHT).enumerate() {
+            for (x, &kind) in row.iter().take(PLAY_WIDTH).enumerate() {
+                if previous.and_then(|previous| previous.get(y)?.get(x)) == Some(&kind) {
+                    continue;
+                }
+
+                self.terminal.write(
+                    self.theme.cell_color(kind),
+                    (start_x + 1 + x * OPPONENT_CELL_WIDTH) as u16,
+                    (start_y + 1 + y) as u16,
+                    if kind == CellKind::Empty { "  " } else { "[]" },
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn render_footer(&self, slot: usize, view: &View) -> Result<()> {
+        let (start_x, start_y) = self.position(slot);
+        let lines = [
+            format!("Score: {}", view.score),
+            format!("Wins : {}", view.wins),
+        ];
+        for (index, line) in lines.iter().enumerate() {
+            self.terminal.write(
+                self.theme.foreground,
+                (start_x + 1) as u16,
+                (start_y + PLAY_HEIGHT + 3 + index) as u16,
+                format!("{:<width$}", line, width = OPPONENT_WIDTH - 2).as_str(),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossterm::style::Color;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // What was written, and where
+    type Writes = Rc<RefCell<Vec<(u16, u16, String)>>>;
+
+    #[derive(Default)]
+    struct Screen {
+        writes: Writes,
+    }
+
+    impl Terminal for Screen {
+        fn enable_raw_mode(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn enter_alternate_screen(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn clear(&self) -> Result<()> {
+            self.writes.borrow_mut().clear();
+            Ok(())
+        }
+
+        fn write(&self, _foreground_color: Color, col: u16, row: u16, msg: &str) -> Result<()> {
+            self.writes.borrow_mut().push((col, row, msg.to_string()));
+            Ok(())
+        }
+
+        fn poll_event(&self, _duration: Duration) -> Result<bool> {
+            Ok(false)
+        }
+
+        fn read_event(&self) -> Result<Event> {
+            Ok(Event::FocusGained)
+        }
+
+        fn leave_alternate_screen(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn disable_raw_mode(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    // A spectator of a terminal of `width` x 40, and what it wrote there
+    fn spectator(width: u16) -> (Spectator, Writes) {
+        let screen = Screen::default();
+        let writes = screen.writes.clone();
+        let spectator = Spectator {
+            terminal: Box::new(screen),
+            theme: Theme::classic(),
+            host: "Ann".to_string(),
+            views: BTreeMap::new(),
+            over: false,
+            size: (width, 40),
+        };
+        (spectator, writes)
+    }
+
+    fn view(name: &str, wins: usize, rows: Vec<Vec<CellKind>>) -> View {
+        View {
+            name: name.to_string(),
+            score: 0,
+            wins,
+            rows,
+        }
+    }
+
+    fn empty_board() -> Vec<Vec<CellKind>> {
+        vec![vec![CellKind::Empty; PLAY_WIDTH]; PLAY_HEIGHT]
+    }
+
+    #[test]
+    fn fits_as_many_boards_as_the_width_allows() {
+        // Boards are 22 columns wide, 6 apart
+        assert_eq!(spectator(21).0.slots(), 0);
+        assert_eq!(spectator(22).0.slots(), 1);
+        assert_eq!(spectator(49).0.slots(), 1);
+        assert_eq!(spectator(50).0.slots(), 2);
+        assert_eq!(spectator(80).0.slots(), 3);
+    }
+
+    #[test]
+    fn centers_the_boards() {
+        let (mut spectator, _) = spectator(80);
+        // One board, or none yet: 22 columns and 28 rows in 80 x 40
+        assert_eq!(spectator.position(0), (29, 9));
+
+        spectator.update(1, view("Ann", 0, empty_board())).unwrap();
+        spectator.update(2, view("Bob", 0, empty_board())).unwrap();
+        assert_eq!(spectator.position(0), (15, 9));
+        assert_eq!(spectator.position(1), (43, 9));
+
+        // No more boards than fit side by side
+        for player in 3..6 {
+            spectator
+                .update(player, view("Cat", 0, empty_board()))
+                .unwrap();
+        }
+        assert_eq!(spectator.position(0), (1, 9));
+    }
+
+    #[test]
+    fn updates_the_player_of_a_view() {
+        let (mut spectator, _) = spectator(80);
+        spectator.update(4, view("Ann", 0, empty_board())).unwrap();
+        spectator.update(7, view("Bob", 0, empty_board())).unwrap();
+
+        let mut board = empty_board();
+        board[PLAY_HEIGHT - 1][0] = CellKind::Garbage;
+        spectator.update(7, view("Bob", 1, board.clone())).unwrap();
+
+        assert_eq!(spectator.views[&4].rows, empty_board());
+        assert_eq!(spectator.views[&4].wins, 0);
+        assert_eq!(spectator.views[&7].rows, board);
+        assert_eq!(spectator.views[&7].wins, 1);
+    }
+
+    #[test]
+    fn only_redraws_the_cells_that_changed() {
+        let (mut spectator, writes) = spectator(80);
+        spectator.update(4, view("Ann", 0, empty_board())).unwrap();
+        spectator.update(7, view("Bob", 0, empty_board())).unwrap();
+        writes.borrow_mut().clear();
+
+        let mut board = empty_board();
+        board[PLAY_HEIGHT - 1][2] = CellKind::T;
+        spectator.update(7, view("Bob", 0, board)).unwrap();
+
+        // In the second board, below its frame
+        let cells: Vec<(u16, u16, String)> = writes
+            .borrow()
+            .iter()
+            .filter(|(_, _, msg)| msg == "[]" || msg == "  ")
+            .cloned()
+            .collect();
+        assert_eq!(
+            cells,
+            [(43 + 1 + 2 * 2, 9 + PLAY_HEIGHT as u16, "[]".to_string())]
+        );
+    }
+
+    #[test]
+    fn removes_a_player_who_left() {
+        let (mut spectator, _) = spectator(80);
+        spectator.update(4, view("Ann", 0, empty_board())).unwrap();
+        spectator.update(7, view("Bob", 0, empty_board())).unwrap();
+
+        spectator.update(4, view("Ann", 0, Vec::new())).unwrap();
+        assert_eq!(spectator.views.keys().collect::<Vec<_>>(), [&7]);
+        assert_eq!(spectator.position(0), (29, 9));
+    }
+}
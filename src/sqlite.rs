@@ -1,25 +1,10 @@
-use crate::{HighScore, Player, Result};
+use crate::mode::GameMode;
+use crate::{data_dir, HighScore, Player, Record, Result};
 use rusqlite::{params, Connection, Result as RusqliteResult};
 use std::error::Error;
-use std::fs;
 
 pub fn open() -> RusqliteResult<Connection, Box<dyn Error>> {
-    let home_dir = match dirs::home_dir() {
-        Some(path) => path,
-        None => {
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Failed to get the user's home directory.",
-            )));
-        }
-    };
-
-    let db_dir = home_dir.join(".tetris");
-    if let Err(err) = fs::create_dir_all(db_dir.clone()) {
-        return Err(Box::new(err));
-    }
-
-    let db_path = db_dir.join("high_scores.db");
+    let db_path = data_dir()?.join("high_scores.db");
     let conn = Connection::open(&db_path)?;
 
     Ok(conn)
@@ -40,6 +25,64 @@ impl HighScore for HighScoreRepo {
             )",
             params![],
         )?;
+        // Every mode but Marathon keeps its leaderboard here
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS records (
+                id INTEGER PRIMARY KEY,
+                mode TEXT,
+                player_name TEXT,
+                score INTEGER,
+                time_ms INTEGER,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            )",
+            params![],
+        )?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS puzzles (
+                id INTEGER PRIMARY KEY,
+                pack TEXT,
+                name TEXT,
+                pieces INTEGER,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            )",
+            params![],
+        )?;
+        // The time every 10 lines of a Sprint record, `split` counts from 1
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS splits (
+                id INTEGER PRIMARY KEY,
+                record_id INTEGER,
+                split INTEGER,
+                time_ms INTEGER
+            )",
+            params![],
+        )?;
+        // The daily challenges, `day` is the date of the challenge
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS daily (
+                id INTEGER PRIMARY KEY,
+                day TEXT,
+                mode TEXT,
+                player_name TEXT,
+                score INTEGER,
+                time_ms INTEGER,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            )",
+            params![],
+        )?;
+        // The 2-player series, from the point of view of `player_name`
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS series (
+                id INTEGER PRIMARY KEY,
+                player_name TEXT,
+                opponent_name TEXT,
+                best_of INTEGER,
+                wins INTEGER,
+                losses INTEGER,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            )",
+            params![],
+        )?;
         Ok(())
     }
 
@@ -92,4 +135,209 @@ impl HighScore for HighScoreRepo {
 
         Ok(())
     }
+
+    fn get_top_records(&self, mode: GameMode) -> Result<Vec<Record>> {
+        let order = if mode.ranks_by_time() {
+            "time_ms ASC"
+        } else {
+            "score DESC, time_ms ASC"
+        };
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT player_name, score, time_ms FROM records WHERE mode = ?1 ORDER BY {} LIMIT 5",
+            order
+        ))?;
+        let rows = stmt.query_map(params![mode.id()], |row| {
+            Ok(Record {
+                name: row.get(0)?,
+                score: row.get(1)?,
+                time_ms: row.get(2)?,
+            })
+        })?;
+        let records: Result<Vec<Record>> = rows
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|err| err.into());
+        records
+    }
+
+    fn insert_record(
+        &mut self,
+        mode: GameMode,
+        name: &str,
+        score: usize,
+        time_ms: u64,
+        splits: &[u64],
+    ) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO records (mode, player_name, score, time_ms) VALUES (?1, ?2, ?3, ?4)",
+            params![mode.id(), name, score, time_ms],
+        )?;
+        let record_id = tx.last_insert_rowid();
+        for (index, split_ms) in splits.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO splits (record_id, split, time_ms) VALUES (?1, ?2, ?3)",
+                params![record_id, index + 1, split_ms],
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn get_best_splits(&self, mode: GameMode) -> Result<Vec<u64>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT time_ms FROM splits WHERE record_id = (
+                SELECT id FROM records WHERE mode = ?1 ORDER BY time_ms ASC LIMIT 1
+            ) ORDER BY split",
+        )?;
+        let rows = stmt.query_map(params![mode.id()], |row| row.get(0))?;
+        let splits: Result<Vec<u64>> = rows
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|err| err.into());
+        splits
+    }
+
+    fn get_solved_puzzles(&self) -> Result<Vec<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT DISTINCT pack || '/' || name FROM puzzles")?;
+        let rows = stmt.query_map(params![], |row| row.get(0))?;
+        let puzzles: Result<Vec<String>> = rows
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|err| err.into());
+        puzzles
+    }
+
+    fn insert_solved_puzzle(&mut self, pack: &str, name: &str, pieces: usize) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO puzzles (pack, name, pieces) VALUES (?1, ?2, ?3)",
+            params![pack, name, pieces],
+        )?;
+
+        Ok(())
+    }
+
+    fn get_daily_records(&self, day: &str, mode: GameMode) -> Result<Vec<Record>> {
+        // The best try of each player. SQLite takes the other columns
+        // from the row that has the MIN or MAX.
+        let query = if mode.ranks_by_time() {
+            "SELECT player_name, score, MIN(time_ms) AS best FROM daily
+             WHERE day = ?1 AND mode = ?2 GROUP BY player_name ORDER BY best ASC LIMIT 10"
+        } else {
+            "SELECT player_name, MAX(score) AS best, time_ms FROM daily
+             WHERE day = ?1 AND mode = ?2 GROUP BY player_name ORDER BY best DESC, time_ms ASC
+             LIMIT 10"
+        };
+        let mut stmt = self.conn.prepare(query)?;
+        let rows = stmt.query_map(params![day, mode.id()], |row| {
+            Ok(Record {
+                name: row.get(0)?,
+                score: row.get(1)?,
+                time_ms: row.get(2)?,
+            })
+        })?;
+        let records: Result<Vec<Record>> = rows
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|err| err.into());
+        records
+    }
+
+    fn insert_daily_record(
+        &mut self,
+        day: &str,
+        mode: GameMode,
+        name: &str,
+        score: usize,
+        time_ms: u64,
+    ) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO daily (day, mode, player_name, score, time_ms)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![day, mode.id(), name, score, time_ms],
+        )?;
+
+        Ok(())
+    }
+
+    fn insert_series(
+        &mut self,
+        name: &str,
+        opponent: &str,
+        best_of: u8,
+        wins: u8,
+        losses: u8,
+    ) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO series (player_name, opponent_name, best_of, wins, losses)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![name, opponent, best_of, wins, losses],
+        )?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repo() -> HighScoreRepo {
+        let repo = HighScoreRepo {
+            conn: Connection::open_in_memory().unwrap(),
+        };
+        repo.create_table().unwrap();
+        repo
+    }
+
+    #[test]
+    fn keeps_the_splits_of_the_fastest_record() {
+        let mut repo = repo();
+        assert!(repo.get_best_splits(GameMode::Sprint).unwrap().is_empty());
+
+        repo.insert_record(GameMode::Sprint, "Ann", 0, 60_000, &[20_000, 45_000])
+            .unwrap();
+        repo.insert_record(GameMode::Sprint, "Bob", 0, 50_000, &[15_000, 40_000])
+            .unwrap();
+        repo.insert_record(GameMode::Sprint, "Cat", 0, 70_000, &[10_000, 50_000])
+            .unwrap();
+        assert_eq!(
+            repo.get_best_splits(GameMode::Sprint).unwrap(),
+            [15_000, 40_000]
+        );
+        assert!(repo.get_best_splits(GameMode::Dig).unwrap().is_empty());
+    }
+
+    #[test]
+    fn keeps_the_result_of_a_series() {
+        let mut repo = repo();
+        repo.insert_series("Ann", "Bob", 3, 2, 1).unwrap();
+        repo.insert_series("Ann", "Cat", 5, 0, 3).unwrap();
+
+        let mut stmt = repo
+            .conn
+            .prepare(
+                "SELECT player_name, opponent_name, best_of, wins, losses FROM series ORDER BY id",
+            )
+            .unwrap();
+        let series: Vec<(String, String, u8, u8, u8)> = stmt
+            .query_map(params![], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<RusqliteResult<_>>()
+            .unwrap();
+        assert_eq!(
+            series,
+            [
+                ("Ann".to_string(), "Bob".to_string(), 3, 2, 1),
+                ("Ann".to_string(), "Cat".to_string(), 5, 0, 3),
+            ]
+        );
+    }
 }
@@ -0,0 +1,643 @@
+use std::env;
+use std::fs;
+
+use crossterm::style::Color;
+
+use crate::config::parse_key_values;
+use crate::{data_dir, CellKind, GameError, Result};
+
+pub const DEFAULT_THEME: &str = "classic";
+pub const BUILTIN_THEMES: [&str; 5] = [
+    "classic",
+    "guideline",
+    "monochrome",
+    "high-contrast",
+    "solarized",
+];
+
+const THEMES_DIR: &str = "themes";
+const THEME_EXTENSION: &str = "theme";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Theme {
+    pub name: String,
+    // Used for frames, messages and the Stats/Help panels
+    pub foreground: Color,
+    pub background: Color,
+    pub i: Color,
+    pub o: Color,
+    pub t: Color,
+    pub s: Color,
+    pub z: Color,
+    pub j: Color,
+    pub l: Color,
+    // The outline showing where the current piece will land
+    pub ghost: Color,
+    // Rows added by Dig mode
+    pub garbage: Color,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorSupport {
+    TrueColor,
+    Ansi256,
+    Ansi16,
+}
+
+impl ColorSupport {
+    /// Guesses what the terminal can display from the environment,
+    /// the same way most terminal applications do.
+    pub fn detect() -> Self {
+        let colorterm = env::var("COLORTERM").unwrap_or_default().to_lowercase();
+        if colorterm == "truecolor" || colorterm == "24bit" || env::var("WT_SESSION").is_ok() {
+            return ColorSupport::TrueColor;
+        }
+
+        let term = env::var("TERM").unwrap_or_default().to_lowercase();
+        if term.contains("256color") {
+            return ColorSupport::Ansi256;
+        }
+
+        ColorSupport::Ansi16
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme::classic()
+    }
+}
+
+impl Theme {
+    pub fn classic() -> Self {
+        Theme {
+            name: "classic".to_string(),
+            foreground: Color::White,
+            background: Color::Black,
+            i: Color::Cyan,
+            o: Color::Yellow,
+            t: Color::Rgb {
+                r: 207,
+                g: 159,
+                b: 255,
+            },
+            s: Color::Green,
+            z: Color::Red,
+            j: Color::Blue,
+            l: Color::Rgb {
+                r: 255,
+                g: 165,
+                b: 0,
+            },
+            ghost: Color::DarkGrey,
+            garbage: Color::Grey,
+        }
+    }
+
+    pub fn guideline() -> Self {
+        Theme {
+            name: "guideline".to_string(),
+            foreground: Color::White,
+            background: Color::Black,
+            i: Color::Rgb {
+                r: 0,
+                g: 255,
+                b: 255,
+            },
+            o: Color::Rgb {
+                r: 255,
+                g: 255,
+                b: 0,
+            },
+            t: Color::Rgb {
+                r: 128,
+                g: 0,
+                b: 128,
+            },
+            s: Color::Rgb { r: 0, g: 255, b: 0 },
+            z: Color::Rgb { r: 255, g: 0, b: 0 },
+            j: Color::Rgb { r: 0, g: 0, b: 255 },
+            l: Color::Rgb {
+                r: 255,
+                g: 127,
+                b: 0,
+            },
+            ghost: Color::DarkGrey,
+            garbage: Color::Grey,
+        }
+    }
+
+    pub fn monochrome() -> Self {
+        Theme {
+            name: "monochrome".to_string(),
+            foreground: Color::White,
+            background: Color::Black,
+            i: Color::White,
+            o: Color::White,
+            t: Color::White,
+            s: Color::White,
+            z: Color::White,
+            j: Color::White,
+            l: Color::White,
+            ghost: Color::DarkGrey,
+            garbage: Color::Grey,
+        }
+    }
+
+    pub fn high_contrast() -> Self {
+        Theme {
+            name: "high-contrast".to_string(),
+            foreground: Color::White,
+            background: Color::Black,
+            i: Color::Rgb {
+                r: 0,
+                g: 255,
+                b: 255,
+            },
+            o: Color::Rgb {
+                r: 255,
+                g: 255,
+                b: 0,
+            },
+            t: Color::Rgb {
+                r: 255,
+                g: 0,
+                b: 255,
+            },
+            s: Color::Rgb { r: 0, g: 255, b: 0 },
+            z: Color::Rgb { r: 255, g: 0, b: 0 },
+            j: Color::Rgb {
+                r: 90,
+                g: 90,
+                b: 255,
+            },
+            l: Color::Rgb {
+                r: 255,
+                g: 140,
+                b: 0,
+            },
+            ghost: Color::DarkGrey,
+            garbage: Color::Grey,
+        }
+    }
+
+    pub fn solarized() -> Self {
+        Theme {
+            name: "solarized".to_string(),
+            foreground: Color::Rgb {
+                r: 147,
+                g: 161,
+                b: 161,
+            },
+            background: Color::Rgb { r: 0, g: 43, b: 54 },
+            i: Color::Rgb {
+                r: 42,
+                g: 161,
+                b: 152,
+            },
+            o: Color::Rgb {
+                r: 181,
+                g: 137,
+                b: 0,
+            },
+            t: Color::Rgb {
+                r: 211,
+                g: 54,
+                b: 130,
+            },
+            s: Color::Rgb {
+                r: 133,
+                g: 153,
+                b: 0,
+            },
+            z: Color::Rgb {
+                r: 220,
+                g: 50,
+                b: 47,
+            },
+            j: Color::Rgb {
+                r: 38,
+                g: 139,
+                b: 210,
+            },
+            l: Color::Rgb {
+                r: 203,
+                g: 75,
+                b: 22,
+            },
+            ghost: Color::Rgb {
+                r: 88,
+                g: 110,
+                b: 117,
+            },
+            garbage: Color::Rgb {
+                r: 101,
+                g: 123,
+                b: 131,
+            },
+        }
+    }
+
+    pub fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "classic" => Some(Theme::classic()),
+            "guideline" => Some(Theme::guideline()),
+            "monochrome" => Some(Theme::monochrome()),
+            "high-contrast" => Some(Theme::high_contrast()),
+            "solarized" => Some(Theme::solarized()),
+            _ => None,
+        }
+    }
+
+    /// Loads a built-in theme, or a user theme from `~/.tetris/themes/<name>.theme`.
+    pub fn load(name: &str) -> Result<Self> {
+        if let Some(theme) = Theme::builtin(name) {
+            return Ok(theme);
+        }
+        // The name is a file name, it must not lead out of the themes directory
+        if name.contains(['/', '\\']) {
+            return Err(Box::new(GameError {
+                message: format!("Invalid theme name '{}'.", name),
+            }));
+        }
+
+        let path = data_dir()?
+            .join(THEMES_DIR)
+            .join(format!("{}.{}", name, THEME_EXTENSION));
+        if !path.exists() {
+            return Err(Box::new(GameError {
+                message: format!(
+                    "Unknown theme '{}'. Available themes: {}, or a file at {}.",
+                    name,
+                    BUILTIN_THEMES.join(", "),
+                    path.display()
+                ),
+            }));
+        }
+
+        let content = fs::read_to_string(&path)?;
+        Theme::parse(name, &content).map_err(|err| {
+            Box::new(GameError {
+                message: format!("{}: {}", path.display(), err),
+            }) as Box<dyn std::error::Error>
+        })
+    }
+
+    /// Parses a theme file. Every line is a `key = value` pair, where `base` picks the
+    /// built-in theme to start from and the other keys override single colors:
+    ///
+    /// ```text
+    /// # ~/.tetris/themes/dracula.theme
+    /// base = classic
+    /// background = #282a36
+    /// foreground = #f8f8f2
+    /// t = #bd93f9
+    /// l = 208
+    /// ```
+    ///
+    /// The base applies first wherever it is written, so it never undoes an override.
+    pub fn parse(name: &str, content: &str) -> Result<Self> {
+        let settings = parse_key_values(content)?;
+
+        let base = match settings.iter().rev().find(|(_, key, _)| key == "base") {
+            Some((line_number, _, value)) => Theme::builtin(value).ok_or_else(|| GameError {
+                message: format!("line {}: unknown base theme '{}'", line_number, value),
+            })?,
+            None => Theme::classic(),
+        };
+        let mut theme = Theme {
+            name: name.to_string(),
+            ..base
+        };
+
+        for (line_number, key, value) in settings {
+            if key == "base" {
+                continue;
+            }
+
+            let color = parse_color(&value).ok_or_else(|| GameError {
+                message: format!("line {}: invalid color '{}'", line_number, value),
+            })?;
+            match key.as_str() {
+                "foreground" => theme.foreground = color,
+                "background" => theme.background = color,
+                "i" => theme.i = color,
+                "o" => theme.o = color,
+                "t" => theme.t = color,
+                "s" => theme.s = color,
+                "z" => theme.z = color,
+                "j" => theme.j = color,
+                "l" => theme.l = color,
+                "ghost" => theme.ghost = color,
+                "garbage" => theme.garbage = color,
+                _ => {
+                    return Err(Box::new(GameError {
+                        message: format!("line {}: unknown key '{}'", line_number, key),
+                    }))
+                }
+            }
+        }
+
+        Ok(theme)
+    }
+
+    pub fn cell_color(&self, kind: CellKind) -> Color {
+        match kind {
+            CellKind::Empty => self.foreground,
+            CellKind::I => self.i,
+            CellKind::O => self.o,
+            CellKind::T => self.t,
+            CellKind::S => self.s,
+            CellKind::Z => self.z,
+            CellKind::J => self.j,
+            CellKind::L => self.l,
+            CellKind::Garbage => self.garbage,
+        }
+    }
+
+    /// Downgrades every color to something the terminal can display.
+    pub fn with_color_support(self, support: ColorSupport) -> Self {
+        let convert = |color: Color| fallback_color(color, support);
+        Theme {
+            name: self.name,
+            foreground: convert(self.foreground),
+            background: convert(self.background),
+            i: convert(self.i),
+            o: convert(self.o),
+            t: convert(self.t),
+            s: convert(self.s),
+            z: convert(self.z),
+            j: convert(self.j),
+            l: convert(self.l),
+            ghost: convert(self.ghost),
+            garbage: convert(self.garbage),
+        }
+    }
+}
+
+/// Lists the built-in themes followed by the user themes in `~/.tetris/themes`.
+pub fn available_themes() -> Vec<String> {
+    let mut themes: Vec<String> = BUILTIN_THEMES.iter().map(|name| name.to_string()).collect();
+
+    if let Ok(dir) = data_dir() {
+        if let Ok(entries) = fs::read_dir(dir.join(THEMES_DIR)) {
+            let mut user_themes: Vec<String> = entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|ext| ext == THEME_EXTENSION))
+                .filter_map(|path| Some(path.file_stem()?.to_string_lossy().to_string()))
+                .filter(|name| !themes.contains(name))
+                .collect();
+            user_themes.sort();
+            themes.extend(user_themes);
+        }
+    }
+
+    themes
+}
+
+/// Accepts `#rrggbb`, an ANSI value between 0 and 255, or a color name such as `dark_grey`.
+pub fn parse_color(value: &str) -> Option<Color> {
+    if let Some(hex) = value.strip_prefix('#') {
+        if hex.len() != 6 {
+            return None;
+        }
+        let channel = |index: usize| u8::from_str_radix(&hex[index..index + 2], 16).ok();
+        return Some(Color::Rgb {
+            r: channel(0)?,
+            g: channel(2)?,
+            b: channel(4)?,
+        });
+    }
+
+    if let Ok(value) = value.parse::<u8>() {
+        return Some(Color::AnsiValue(value));
+    }
+
+    Color::try_from(value).ok()
+}
+
+// The 16 ANSI colors, using the xterm default palette
+const ANSI_16: [(Color, (u8, u8, u8)); 16] = [
+    (Color::Black, (0, 0, 0)),
+    (Color::DarkRed, (205, 0, 0)),
+    (Color::DarkGreen, (0, 205, 0)),
+    (Color::DarkYellow, (205, 205, 0)),
+    (Color::DarkBlue, (0, 0, 238)),
+    (Color::DarkMagenta, (205, 0, 205)),
+    (Color::DarkCyan, (0, 205, 205)),
+    (Color::Grey, (229, 229, 229)),
+    (Color::DarkGrey, (127, 127, 127)),
+    (Color::Red, (255, 0, 0)),
+    (Color::Green, (0, 255, 0)),
+    (Color::Yellow, (255, 255, 0)),
+    (Color::Blue, (92, 92, 255)),
+    (Color::Magenta, (255, 0, 255)),
+    (Color::Cyan, (0, 255, 255)),
+    (Color::White, (255, 255, 255)),
+];
+
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+pub fn fallback_color(color: Color, support: ColorSupport) -> Color {
+    match (color, support) {
+        (_, ColorSupport::TrueColor) => color,
+        (Color::Rgb { r, g, b }, ColorSupport::Ansi256) => {
+            Color::AnsiValue(rgb_to_ansi256(r, g, b))
+        }
+        (Color::Rgb { r, g, b }, ColorSupport::Ansi16) => nearest_ansi16(r, g, b),
+        (Color::AnsiValue(value), ColorSupport::Ansi16) => {
+            let (r, g, b) = ansi256_to_rgb(value);
+            nearest_ansi16(r, g, b)
+        }
+        _ => color,
+    }
+}
+
+fn rgb_to_ansi256(r: u8, g: u8, b: u8) -> u8 {
+    let cube_index = |channel: u8| {
+        CUBE_LEVELS
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, &level)| (level as i32 - channel as i32).abs())
+            .map(|(index, _)| index as u8)
+            .unwrap_or(0)
+    };
+    let (ri, gi, bi) = (cube_index(r), cube_index(g), cube_index(b));
+    let cube = 16 + 36 * ri + 6 * gi + bi;
+    let cube_distance = distance(
+        (r, g, b),
+        (
+            CUBE_LEVELS[ri as usize],
+            CUBE_LEVELS[gi as usize],
+            CUBE_LEVELS[bi as usize],
+        ),
+    );
+
+    // The grayscale ramp (232-255) is a better match for colors close to gray
+    let average = (r as u32 + g as u32 + b as u32) / 3;
+    let gray_index = ((average.saturating_sub(8)) / 10).min(23) as u8;
+    let gray_level = 8 + 10 * gray_index;
+    let gray_distance = distance((r, g, b), (gray_level, gray_level, gray_level));
+
+    if gray_distance < cube_distance {
+        232 + gray_index
+    } else {
+        cube
+    }
+}
+
+fn ansi256_to_rgb(value: u8) -> (u8, u8, u8) {
+    match value {
+        0..=15 => ANSI_16[value as usize].1,
+        16..=231 => {
+            let index = value - 16;
+            (
+                CUBE_LEVELS[(index / 36) as usize],
+                CUBE_LEVELS[(index / 6 % 6) as usize],
+                CUBE_LEVELS[(index % 6) as usize],
+            )
+        }
+        _ => {
+            let level = 8 + 10 * (value - 232);
+            (level, level, level)
+        }
+    }
+}
+
+fn nearest_ansi16(r: u8, g: u8, b: u8) -> Color {
+    ANSI_16
+        .iter()
+        .min_by_key(|(_, rgb)| distance((r, g, b), *rgb))
+        .map(|(color, _)| *color)
+        .unwrap_or(Color::White)
+}
+
+fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+    let dr = a.0 as i32 - b.0 as i32;
+    let dg = a.1 as i32 - b.1 as i32;
+    let db = a.2 as i32 - b.2 as i32;
+    (dr * dr + dg * dg + db * db) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_names_leading_out_of_the_themes_directory() {
+        for name in ["../x", "themes/../../x", "/etc/passwd", "..\\x"] {
+            let err = Theme::load(name).unwrap_err();
+            assert!(
+                err.to_string().starts_with("Invalid theme name"),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn parses_a_theme_on_top_of_its_base() {
+        let theme = Theme::parse(
+            "dracula",
+            "# comment\nbase = solarized\nbackground = #282a36\nt = 141\nl = dark_grey\n",
+        )
+        .unwrap();
+
+        assert_eq!(theme.name, "dracula");
+        assert_eq!(
+            theme.background,
+            Color::Rgb {
+                r: 0x28,
+                g: 0x2a,
+                b: 0x36
+            }
+        );
+        assert_eq!(theme.t, Color::AnsiValue(141));
+        assert_eq!(theme.l, Color::DarkGrey);
+        assert_eq!(theme.i, Theme::solarized().i);
+    }
+
+    #[test]
+    fn keeps_the_overrides_written_above_the_base() {
+        let theme = Theme::parse(
+            "mine",
+            "t = 141
+base = solarized
+l = red
+",
+        )
+        .unwrap();
+        assert_eq!(theme.t, Color::AnsiValue(141));
+        assert_eq!(theme.l, Color::Red);
+        assert_eq!(theme.i, Theme::solarized().i);
+    }
+
+    #[test]
+    fn reports_the_line_of_a_bad_theme() {
+        let err = Theme::parse("bad", "base = classic\nt = #12345\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: invalid color '#12345'");
+
+        let err = Theme::parse("bad", "base = nope\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 1: unknown base theme 'nope'");
+
+        let err = Theme::parse("bad", "stripes = red\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 1: unknown key 'stripes'");
+    }
+
+    #[test]
+    fn parses_colors() {
+        assert_eq!(
+            parse_color("#ff8000"),
+            Some(Color::Rgb {
+                r: 255,
+                g: 128,
+                b: 0
+            })
+        );
+        assert_eq!(parse_color("208"), Some(Color::AnsiValue(208)));
+        assert_eq!(parse_color("dark_red"), Some(Color::DarkRed));
+        assert_eq!(parse_color("#ff80"), None);
+        assert_eq!(parse_color("#gg0000"), None);
+        assert_eq!(parse_color("256"), None);
+        assert_eq!(parse_color("sky"), None);
+    }
+
+    #[test]
+    fn falls_back_to_the_colors_of_the_terminal() {
+        let orange = Color::Rgb {
+            r: 255,
+            g: 135,
+            b: 0,
+        };
+        assert_eq!(fallback_color(orange, ColorSupport::TrueColor), orange);
+        assert_eq!(
+            fallback_color(orange, ColorSupport::Ansi256),
+            Color::AnsiValue(208)
+        );
+        assert_eq!(
+            fallback_color(orange, ColorSupport::Ansi16),
+            Color::DarkYellow
+        );
+
+        // Grays use the grayscale ramp rather than the color cube
+        let gray = Color::Rgb {
+            r: 128,
+            g: 128,
+            b: 128,
+        };
+        assert_eq!(
+            fallback_color(gray, ColorSupport::Ansi256),
+            Color::AnsiValue(244)
+        );
+
+        assert_eq!(
+            fallback_color(Color::AnsiValue(196), ColorSupport::Ansi16),
+            Color::Red
+        );
+        assert_eq!(
+            fallback_color(Color::DarkBlue, ColorSupport::Ansi16),
+            Color::DarkBlue
+        );
+    }
+}
@@ -7,8 +7,8 @@ use std::sync::mpsc::{channel, Receiver, Sender};
 use std::thread;
 use std::time::Duration;
 use tetris_tui::{
-    sqlite::HighScoreRepo, tetromino_width, Cell, Game, Position, Terminal, Tetromino,
-    TetrominoSpawner, EMPTY_CELL, I_CELL, NEXT_WIDTH, PLAY_WIDTH,
+    config::Config, sqlite::HighScoreRepo, tetromino_width, theme::Theme, Cell, Game, Position,
+    Terminal, Tetromino, TetrominoSpawner, EMPTY_CELL, I_CELL, NEXT_WIDTH, PLAY_WIDTH,
 };
 
 type Result<T> = result::Result<T, Box<dyn Error>>;
@@ -138,8 +138,8 @@ fn clear_lines() -> Result<()> {
         sqlite_highscore_repository,
         40,
         20,
-        0,
-        0,
+        Config::default(),
+        Theme::default(),
         None,
         None,
         Some(play_grid_tx),